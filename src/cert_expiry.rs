@@ -0,0 +1,156 @@
+use actix_web::web;
+use chrono::{DateTime, Duration, Utc};
+use log::{error, info, warn};
+use serde::Serialize;
+use std::collections::HashMap;
+
+use crate::key_rotation::KeyRole;
+use crate::models::state::AppState;
+
+/// How often the signing certificates are checked for expiry
+const EXPIRY_CHECK_INTERVAL_SECS: u64 = 60 * 60;
+
+/// How urgently a certificate needs replacing
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ExpiryLevel {
+    Ok,
+    Warning,
+    Critical,
+    Expired,
+}
+
+/// Time before expiry at which a certificate is reported as due for replacement
+#[derive(Debug, Clone, Copy)]
+pub struct ExpiryThresholds {
+    pub warning: Duration,
+    pub critical: Duration,
+}
+
+impl ExpiryThresholds {
+    pub fn level(&self, expires_at: DateTime<Utc>, now: DateTime<Utc>) -> ExpiryLevel {
+        let remaining = expires_at - now;
+        if remaining <= Duration::zero() {
+            ExpiryLevel::Expired
+        } else if remaining <= self.critical {
+            ExpiryLevel::Critical
+        } else if remaining <= self.warning {
+            ExpiryLevel::Warning
+        } else {
+            ExpiryLevel::Ok
+        }
+    }
+}
+
+/// Whole days left until `expires_at`, negative once it has passed
+pub fn days_to_expiry(expires_at: DateTime<Utc>, now: DateTime<Utc>) -> i64 {
+    (expires_at - now).num_days()
+}
+
+/// Periodically logs warnings for signing certificates nearing expiry. A
+/// certificate in the warning period is reported daily, and hourly once it is
+/// critical or expired. The previous certificate is skipped, as it is no
+/// longer used for signing.
+pub fn spawn_certificate_expiry_monitor(state: web::Data<AppState>) {
+    tokio::spawn(async move {
+        let mut interval =
+            tokio::time::interval(std::time::Duration::from_secs(EXPIRY_CHECK_INTERVAL_SECS));
+        let mut last_reported: HashMap<String, (ExpiryLevel, DateTime<Utc>)> = HashMap::new();
+        loop {
+            interval.tick().await;
+            let certificates = match state.signing_keys.certificate_validity() {
+                Ok(certificates) => certificates,
+                Err(e) => {
+                    error!("Failed to check signing certificate expiry: {}", e);
+                    continue;
+                }
+            };
+
+            let now = Utc::now();
+            last_reported.retain(|fingerprint, _| {
+                certificates
+                    .iter()
+                    .any(|certificate| &certificate.fingerprint == fingerprint)
+            });
+            for certificate in certificates {
+                if certificate.role == KeyRole::Previous {
+                    continue;
+                }
+                let level = state.certificate_expiry.level(certificate.expires_at, now);
+                let due = match last_reported.get(&certificate.fingerprint) {
+                    Some((last_level, _)) if *last_level != level => true,
+                    Some((_, reported_at)) if level == ExpiryLevel::Warning => {
+                        now - *reported_at >= Duration::days(1)
+                    }
+                    Some(_) => level > ExpiryLevel::Warning,
+                    None => true,
+                };
+                if !due {
+                    continue;
+                }
+                last_reported.insert(certificate.fingerprint.clone(), (level, now));
+
+                let days = days_to_expiry(certificate.expires_at, now);
+                match level {
+                    ExpiryLevel::Ok => info!(
+                        "The {} signing certificate {} expires at {} ({} days)",
+                        certificate.role.as_str(),
+                        certificate.fingerprint,
+                        certificate.expires_at,
+                        days
+                    ),
+                    ExpiryLevel::Warning => warn!(
+                        "The {} signing certificate {} expires at {} ({} days)",
+                        certificate.role.as_str(),
+                        certificate.fingerprint,
+                        certificate.expires_at,
+                        days
+                    ),
+                    ExpiryLevel::Critical => error!(
+                        "The {} signing certificate {} expires at {} ({} days). Rotate the signing key now",
+                        certificate.role.as_str(),
+                        certificate.fingerprint,
+                        certificate.expires_at,
+                        days
+                    ),
+                    ExpiryLevel::Expired => error!(
+                        "The {} signing certificate {} expired at {}. SPs may reject its signatures",
+                        certificate.role.as_str(),
+                        certificate.fingerprint,
+                        certificate.expires_at
+                    ),
+                }
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn level_follows_the_thresholds() {
+        let thresholds = ExpiryThresholds {
+            warning: Duration::days(30),
+            critical: Duration::days(7),
+        };
+        let now = Utc::now();
+        let level = |remaining: Duration| thresholds.level(now + remaining, now);
+
+        assert_eq!(level(Duration::days(31)), ExpiryLevel::Ok);
+        assert_eq!(level(Duration::days(30)), ExpiryLevel::Warning);
+        assert_eq!(level(Duration::days(8)), ExpiryLevel::Warning);
+        assert_eq!(level(Duration::days(7)), ExpiryLevel::Critical);
+        assert_eq!(level(Duration::seconds(1)), ExpiryLevel::Critical);
+        assert_eq!(level(Duration::zero()), ExpiryLevel::Expired);
+        assert_eq!(level(Duration::days(-1)), ExpiryLevel::Expired);
+    }
+
+    #[test]
+    fn days_to_expiry_is_negative_after_expiry() {
+        let now = Utc::now();
+        assert_eq!(days_to_expiry(now + Duration::hours(49), now), 2);
+        assert_eq!(days_to_expiry(now - Duration::days(3), now), -3);
+    }
+}
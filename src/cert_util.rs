@@ -1,107 +1,1161 @@
+use chrono::{DateTime, Utc};
 use log::{debug, error, info, warn};
-use samael::idp::{CertificateParams, IdentityProvider, KeyType, Rsa};
-use std::{fs, io, path::Path};
+use openssl::asn1::Asn1Time;
+use openssl::bn::{BigNum, MsbOption};
+use openssl::ec::{EcGroup, EcKey};
+use openssl::error::ErrorStack;
+use openssl::hash::{MessageDigest, hash};
+use openssl::nid::Nid;
+use openssl::pkey::{Id, PKey, Private};
+use openssl::rsa::Rsa;
+use openssl::stack::Stack;
+use openssl::symm::Cipher;
+use openssl::x509::extension::KeyUsage;
+use openssl::x509::{X509, X509Builder, X509Extension, X509Name, X509NameBuilder, X509ReqBuilder};
+use samael::idp::IdentityProvider;
+use std::ffi::OsString;
+use std::fmt;
+use std::io::Write;
+#[cfg(unix)]
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::{fs, io};
 
-const KEY_FILE_PATH: &str = "idp_private_key.der";
-const CERT_FILE_PATH: &str = "idp_certificate.der";
+pub const DEFAULT_KEY_FILE_PATH: &str = "idp_private_key.der";
+pub const DEFAULT_CERT_FILE_PATH: &str = "idp_certificate.der";
 
-/// Loads or creates an IdentityProvider with certificate
-pub fn load_or_create_identity_provider() -> io::Result<(IdentityProvider, Vec<u8>)> {
-    // Check if certificate and key files already exist
-    if Path::new(KEY_FILE_PATH).exists() && Path::new(CERT_FILE_PATH).exists() {
-        info!("Loading existing IdP certificate and key from files");
+pub const PRIVATE_KEY_FILE_MODE: u32 = 0o600;
+const CERTIFICATE_FILE_MODE: u32 = 0o644;
 
-        // Read key and certificate from files
-        let key_der = fs::read(KEY_FILE_PATH)?;
-        let cert_der = fs::read(CERT_FILE_PATH)?;
+/// DER certificates, starting with the IdP certificate and followed by any
+/// intermediate CA certificates
+pub type CertificateChain = Vec<Vec<u8>>;
 
-        debug!("Found key file of size: {} bytes", key_der.len());
-        debug!("Found certificate file of size: {} bytes", cert_der.len());
+/// Where an IdP key and certificate are stored.
+///
+/// Files ending in `.pem` are PEM, anything else DER. The key may be a
+/// traditional or PKCS#8 key, optionally encrypted with `passphrase`. Keys
+/// written by the IdP are encrypted PKCS#8 when a passphrase is set, and only
+/// readable by their owner. A PEM certificate file may hold intermediate
+/// certificates after the IdP's own.
+#[derive(Debug, Clone)]
+pub struct KeyFiles {
+    pub key_path: PathBuf,
+    pub cert_path: PathBuf,
+    pub passphrase: Option<String>,
+}
 
-        // Deserialize the IdentityProvider from the key DER
-        match IdentityProvider::from_rsa_private_key_der(&key_der) {
-            Ok(idp) => {
-                info!("Successfully loaded IdP from private key");
-                Ok((idp, cert_der))
-            }
-            Err(e) => {
-                error!("Failed to deserialize IdentityProvider from key: {}", e);
-                Err(io::Error::new(
-                    io::ErrorKind::InvalidData,
-                    format!("Failed to deserialize key: {}", e),
-                ))
-            }
+impl KeyFiles {
+    pub fn exist(&self) -> bool {
+        self.key_path.exists() && self.cert_path.exists()
+    }
+
+    /// Where a certificate signing request for the key is written: the
+    /// certificate file with a `.csr` extension
+    pub fn csr_path(&self) -> PathBuf {
+        self.cert_path.with_extension("csr")
+    }
+
+    /// Files next to these ones with `label` added before the extension, such
+    /// as `idp_certificate.next.der` for `idp_certificate.der`
+    pub fn sibling(&self, label: &str) -> Self {
+        Self {
+            key_path: labelled_path(&self.key_path, label),
+            cert_path: labelled_path(&self.cert_path, label),
+            passphrase: self.passphrase.clone(),
         }
-    } else {
-        info!("No existing IdP certificate found. Generating new IdP identity");
-        // Generate new IdP identity
-        let idp = match IdentityProvider::generate_new(KeyType::Rsa(Rsa::Rsa2048)) {
-            Ok(idp) => idp,
-            Err(e) => {
-                error!("Failed to generate new IdP identity: {}", e);
-                return Err(io::Error::new(
-                    io::ErrorKind::Other,
-                    format!("Failed to generate IdP: {}", e),
-                ));
+    }
+}
+
+/// How the certificate of a newly generated IdP key is obtained
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CertificateMode {
+    /// The IdP signs the certificate itself
+    SelfSigned,
+    /// The IdP writes a certificate signing request next to the certificate
+    /// file, and the key is used once the issued certificate is saved there
+    Csr,
+}
+
+impl FromStr for CertificateMode {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.to_ascii_lowercase().as_str() {
+            "self-signed" => Ok(Self::SelfSigned),
+            "csr" => Ok(Self::Csr),
+            other => Err(format!("Unsupported certificate mode: {}", other)),
+        }
+    }
+}
+
+/// How the serial number of a self-signed certificate is chosen
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SerialStrategy {
+    /// A random 128-bit number
+    Random,
+    /// Milliseconds since the Unix epoch at creation
+    Timestamp,
+}
+
+impl SerialStrategy {
+    fn serial_number(self) -> Result<BigNum, ErrorStack> {
+        match self {
+            Self::Random => {
+                let mut serial = BigNum::new()?;
+                serial.rand(128, MsbOption::MAYBE_ZERO, false)?;
+                Ok(serial)
             }
-        };
+            Self::Timestamp => BigNum::from_dec_str(&Utc::now().timestamp_millis().to_string()),
+        }
+    }
+}
 
-        // Create certificate
-        let cert_params = CertificateParams {
-            common_name: "My Identity Provider",
-            issuer_name: "My Identity Provider",
-            days_until_expiration: 1000,
-        };
+impl FromStr for SerialStrategy {
+    type Err = String;
 
-        let cert_der = match idp.create_certificate(&cert_params) {
-            Ok(cert) => cert,
-            Err(e) => {
-                error!("Failed to create certificate: {}", e);
-                return Err(io::Error::new(
-                    io::ErrorKind::Other,
-                    format!("Failed to create certificate: {}", e),
-                ));
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.to_ascii_lowercase().as_str() {
+            "random" => Ok(Self::Random),
+            "timestamp" => Ok(Self::Timestamp),
+            other => Err(format!("Unsupported serial number strategy: {}", other)),
+        }
+    }
+}
+
+/// A distinguished name written as comma-separated components, such as
+/// `CN=My Identity Provider,O=Example,C=US`. Commas within a value are
+/// escaped as `\,`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DistinguishedName(Vec<(String, String)>);
+
+impl DistinguishedName {
+    fn to_x509_name(&self) -> Result<X509Name, ErrorStack> {
+        let mut name = X509NameBuilder::new()?;
+        for (field, value) in &self.0 {
+            name.append_entry_by_text(field, value)?;
+        }
+        Ok(name.build())
+    }
+}
+
+impl FromStr for DistinguishedName {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let mut components = Vec::new();
+        let mut component = String::new();
+        let mut chars = value.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '\\' => component.extend(chars.next()),
+                ',' => components.push(std::mem::take(&mut component)),
+                c => component.push(c),
             }
+        }
+        components.push(component);
+
+        let name = Self(
+            components
+                .iter()
+                .map(|component| match component.split_once('=') {
+                    Some((field, value)) if !field.trim().is_empty() => {
+                        Ok((field.trim().to_string(), value.trim().to_string()))
+                    }
+                    _ => Err(format!(
+                        "Invalid distinguished name component: {}",
+                        component
+                    )),
+                })
+                .collect::<Result<_, _>>()?,
+        );
+        // Let openssl reject unknown attribute types now rather than when a
+        // certificate is first generated
+        name.to_x509_name()
+            .map_err(|e| format!("Invalid distinguished name {}: {}", value, e))?;
+        Ok(name)
+    }
+}
+
+/// A key usage set in generated certificates
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyUsageFlag {
+    DigitalSignature,
+    NonRepudiation,
+    KeyEncipherment,
+    DataEncipherment,
+    KeyAgreement,
+}
+
+/// Comma-separated key usages, such as `digitalSignature,nonRepudiation`. An
+/// empty list leaves the extension out.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct KeyUsageFlags(Vec<KeyUsageFlag>);
+
+impl KeyUsageFlags {
+    // The extension is marked critical, as RFC 5280 recommends
+    fn to_extension(&self) -> Result<Option<X509Extension>, ErrorStack> {
+        if self.0.is_empty() {
+            return Ok(None);
+        }
+        let mut key_usage = KeyUsage::new();
+        key_usage.critical();
+        for flag in &self.0 {
+            match flag {
+                KeyUsageFlag::DigitalSignature => key_usage.digital_signature(),
+                KeyUsageFlag::NonRepudiation => key_usage.non_repudiation(),
+                KeyUsageFlag::KeyEncipherment => key_usage.key_encipherment(),
+                KeyUsageFlag::DataEncipherment => key_usage.data_encipherment(),
+                KeyUsageFlag::KeyAgreement => key_usage.key_agreement(),
+            };
+        }
+        key_usage.build().map(Some)
+    }
+}
+
+impl FromStr for KeyUsageFlags {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        value
+            .split(',')
+            .map(str::trim)
+            .filter(|flag| !flag.is_empty())
+            .map(|flag| match flag.to_ascii_lowercase().as_str() {
+                "digitalsignature" => Ok(KeyUsageFlag::DigitalSignature),
+                "nonrepudiation" => Ok(KeyUsageFlag::NonRepudiation),
+                "keyencipherment" => Ok(KeyUsageFlag::KeyEncipherment),
+                "dataencipherment" => Ok(KeyUsageFlag::DataEncipherment),
+                "keyagreement" => Ok(KeyUsageFlag::KeyAgreement),
+                other => Err(format!("Unsupported key usage: {}", other)),
+            })
+            .collect::<Result<_, _>>()
+            .map(Self)
+    }
+}
+
+/// What goes into the certificate, or certificate signing request, of a newly
+/// generated IdP key
+#[derive(Debug, Clone)]
+pub struct CertificateProfile {
+    pub mode: CertificateMode,
+    pub subject: DistinguishedName,
+    /// Issuer of a self-signed certificate, the subject when unset
+    pub issuer: Option<DistinguishedName>,
+    pub validity_days: u32,
+    pub serial: SerialStrategy,
+    pub key_usage: KeyUsageFlags,
+}
+
+impl Default for CertificateProfile {
+    fn default() -> Self {
+        Self {
+            mode: CertificateMode::SelfSigned,
+            subject: DistinguishedName(vec![(
+                "CN".to_string(),
+                "My Identity Provider".to_string(),
+            )]),
+            issuer: None,
+            validity_days: 1000,
+            serial: SerialStrategy::Random,
+            key_usage: KeyUsageFlags::default(),
+        }
+    }
+}
+
+/// Type of key generated for the IdP identity
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IdpKeyType {
+    Rsa2048,
+    Rsa3072,
+    Rsa4096,
+    EcdsaP256,
+    EcdsaP384,
+}
+
+impl IdpKeyType {
+    pub fn id(self) -> Id {
+        match self {
+            Self::Rsa2048 | Self::Rsa3072 | Self::Rsa4096 => Id::RSA,
+            Self::EcdsaP256 | Self::EcdsaP384 => Id::EC,
+        }
+    }
+
+    fn generate(self) -> Result<PKey<Private>, ErrorStack> {
+        let rsa_bits = match self {
+            Self::Rsa2048 => 2048,
+            Self::Rsa3072 => 3072,
+            Self::Rsa4096 => 4096,
+            Self::EcdsaP256 => return generate_ec_key(Nid::X9_62_PRIME256V1),
+            Self::EcdsaP384 => return generate_ec_key(Nid::SECP384R1),
         };
+        PKey::from_rsa(Rsa::generate(rsa_bits)?)
+    }
+}
+
+impl FromStr for IdpKeyType {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.to_ascii_lowercase().as_str() {
+            "rsa-2048" => Ok(Self::Rsa2048),
+            "rsa-3072" => Ok(Self::Rsa3072),
+            "rsa-4096" => Ok(Self::Rsa4096),
+            "ecdsa-p256" => Ok(Self::EcdsaP256),
+            "ecdsa-p384" => Ok(Self::EcdsaP384),
+            other => Err(format!("Unsupported key type: {}", other)),
+        }
+    }
+}
+
+impl fmt::Display for IdpKeyType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Rsa2048 => "rsa-2048",
+            Self::Rsa3072 => "rsa-3072",
+            Self::Rsa4096 => "rsa-4096",
+            Self::EcdsaP256 => "ecdsa-p256",
+            Self::EcdsaP384 => "ecdsa-p384",
+        })
+    }
+}
+
+/// Loads or creates an IdentityProvider with certificate. A new identity uses
+/// `key_type` and `profile`; an existing one is loaded whatever its key type.
+///
+/// In CSR mode no identity can be created on the spot: a key and certificate
+/// signing request are written instead, and an error asks for the issued
+/// certificate.
+pub fn load_or_create_identity_provider(
+    files: &KeyFiles,
+    key_type: IdpKeyType,
+    profile: &CertificateProfile,
+) -> io::Result<(IdentityProvider, CertificateChain)> {
+    // Check if certificate and key files already exist
+    if files.exist() {
+        info!("Loading existing IdP certificate and key from files");
+        load_identity_provider(files)
+    } else if profile.mode == CertificateMode::Csr {
+        let csr_path = request_certificate(files, key_type, profile)?;
+        Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!(
+                "No IdP certificate found. Have the certificate signing request in {} issued and save the certificate as {}",
+                csr_path.display(),
+                files.cert_path.display()
+            ),
+        ))
+    } else {
+        info!("No existing IdP certificate found. Generating new IdP identity");
+        let (idp, cert_chain) = generate_identity_provider(key_type, profile)?;
 
         // Save to files for future use
-        match persist_idp_identity(&idp, &cert_der) {
+        match persist_idp_identity(&idp, &cert_chain, files) {
             Ok(_) => {
                 info!("Successfully saved IdP identity to disk");
-                Ok((idp, cert_der))
+                Ok((idp, cert_chain))
             }
             Err(e) => {
                 warn!("Failed to persist IdP identity: {}", e);
                 warn!("Continuing with generated identity, but it won't be persisted");
-                Ok((idp, cert_der)) // Still return the identity even if persistence fails
+                Ok((idp, cert_chain)) // Still return the identity even if persistence fails
             }
         }
     }
 }
 
-/// Persists the IdP identity to disk
-fn persist_idp_identity(idp: &IdentityProvider, cert_der: &[u8]) -> io::Result<()> {
+/// Loads an IdentityProvider and its certificate chain, checking that the
+/// certificate belongs to the private key. RSA and EC keys are both accepted.
+pub fn load_identity_provider(
+    files: &KeyFiles,
+) -> io::Result<(IdentityProvider, CertificateChain)> {
+    let private_key = load_private_key(&files.key_path, files.passphrase.as_deref())?;
+    let cert_chain = load_certificate_chain(&files.cert_path)?;
+    debug!(
+        "Found certificate chain of {} certificate(s) in {}",
+        cert_chain.len(),
+        files.cert_path.display()
+    );
+
+    let certificate = X509::from_der(&cert_chain[0])?;
+    if !certificate.public_key()?.public_eq(&private_key) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "The certificate in {} does not match the private key in {}",
+                files.cert_path.display(),
+                files.key_path.display()
+            ),
+        ));
+    }
+
+    // Deserialize the IdentityProvider from the key DER
+    match IdentityProvider::from_private_key_der(&private_key.private_key_to_der()?) {
+        Ok(idp) => {
+            info!("Successfully loaded IdP from private key");
+            Ok((idp, cert_chain))
+        }
+        Err(e) => {
+            error!("Failed to deserialize IdentityProvider from key: {}", e);
+            Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Failed to deserialize key: {}", e),
+            ))
+        }
+    }
+}
+
+// Reads a PEM or DER private key, decrypting it with `passphrase` if it is
+// encrypted. A key readable by all users is refused, and an unencrypted key is
+// encrypted in place when a passphrase is set.
+fn load_private_key(path: &Path, passphrase: Option<&str>) -> io::Result<PKey<Private>> {
+    check_key_permissions(path)?;
+    let contents = fs::read(path)?;
+    debug!("Found key file of size: {} bytes", contents.len());
+
+    let private_key = match (is_pem(&contents), passphrase) {
+        (true, Some(passphrase)) => {
+            PKey::private_key_from_pem_passphrase(&contents, passphrase.as_bytes())
+        }
+        // Fail rather than prompt on the terminal for the passphrase of an
+        // encrypted key
+        (true, None) => PKey::private_key_from_pem_callback(&contents, |_| Ok(0)),
+        (false, Some(passphrase)) => {
+            PKey::private_key_from_pkcs8_passphrase(&contents, passphrase.as_bytes())
+                .or_else(|_| PKey::private_key_from_der(&contents))
+        }
+        (false, None) => PKey::private_key_from_der(&contents),
+    };
+    let private_key = private_key.map_err(|e| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "Failed to read private key from {} (is a passphrase needed?): {}",
+                path.display(),
+                e
+            ),
+        )
+    })?;
+
+    if passphrase.is_some() && !is_encrypted_key(&contents) {
+        info!(
+            "Encrypting the unencrypted private key in {}",
+            path.display()
+        );
+        write_private_key(path, private_key.private_key_to_der()?, passphrase)?;
+    }
+    Ok(private_key)
+}
+
+// Refuses a private key file that any user on the system can read
+#[cfg(unix)]
+fn check_key_permissions(path: &Path) -> io::Result<()> {
+    let mode = fs::metadata(path)?.permissions().mode();
+    if mode & 0o004 != 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!(
+                "Private key {} is readable by all users (mode {:o}). Restrict it with chmod 600",
+                path.display(),
+                mode & 0o777
+            ),
+        ));
+    }
+    if mode & 0o040 != 0 {
+        warn!(
+            "Private key {} is readable by its group (mode {:o})",
+            path.display(),
+            mode & 0o777
+        );
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn check_key_permissions(_path: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+fn is_encrypted_key(contents: &[u8]) -> bool {
+    if is_pem(contents) {
+        // Both PKCS#8 "ENCRYPTED PRIVATE KEY" and traditional "Proc-Type:
+        // 4,ENCRYPTED" headers
+        contents.windows(9).any(|window| window == b"ENCRYPTED")
+    } else {
+        PKey::private_key_from_der(contents).is_err()
+    }
+}
+
+/// Reads a DER certificate, or a PEM file with one or more certificates
+pub fn load_certificate_chain(path: &Path) -> io::Result<CertificateChain> {
+    let contents = fs::read(path)?;
+    if !is_pem(&contents) {
+        return Ok(vec![contents]);
+    }
+
+    let chain = X509::stack_from_pem(&contents)?
+        .iter()
+        .map(|certificate| certificate.to_der())
+        .collect::<Result<CertificateChain, _>>()?;
+    if chain.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("No certificate found in {}", path.display()),
+        ));
+    }
+    Ok(chain)
+}
+
+/// Generates a new IdentityProvider key of `key_type` and a self-signed
+/// certificate for it following `profile`
+pub fn generate_identity_provider(
+    key_type: IdpKeyType,
+    profile: &CertificateProfile,
+) -> io::Result<(IdentityProvider, CertificateChain)> {
+    info!("Generating {} IdP signing key", key_type);
+    let private_key = key_type.generate()?;
+    let idp = match IdentityProvider::from_private_key_der(&private_key.private_key_to_der()?) {
+        Ok(idp) => idp,
+        Err(e) => {
+            error!("Failed to generate new IdP identity: {}", e);
+            return Err(io::Error::other(format!("Failed to generate IdP: {}", e)));
+        }
+    };
+
+    match create_self_signed_certificate(&private_key, profile) {
+        Ok(cert_der) => Ok((idp, vec![cert_der])),
+        Err(e) => {
+            error!("Failed to create certificate: {}", e);
+            Err(io::Error::other(format!(
+                "Failed to create certificate: {}",
+                e
+            )))
+        }
+    }
+}
+
+/// Writes a PEM certificate signing request for the key in `files`, next to
+/// its certificate file, generating a key of `key_type` when there is none.
+/// Returns the path of the request.
+pub fn request_certificate(
+    files: &KeyFiles,
+    key_type: IdpKeyType,
+    profile: &CertificateProfile,
+) -> io::Result<PathBuf> {
+    let private_key = if files.key_path.exists() {
+        load_private_key(&files.key_path, files.passphrase.as_deref())?
+    } else {
+        info!("Generating {} IdP signing key", key_type);
+        let private_key = key_type.generate()?;
+        write_private_key(
+            &files.key_path,
+            private_key.private_key_to_der()?,
+            files.passphrase.as_deref(),
+        )?;
+        private_key
+    };
+
+    let csr_path = files.csr_path();
+    write_atomically(
+        &csr_path,
+        &create_certificate_request(&private_key, profile)?,
+        CERTIFICATE_FILE_MODE,
+    )?;
+    info!(
+        "Wrote certificate signing request to {}",
+        csr_path.display()
+    );
+    Ok(csr_path)
+}
+
+fn generate_ec_key(curve: Nid) -> Result<PKey<Private>, ErrorStack> {
+    let group = EcGroup::from_curve_name(curve)?;
+    PKey::from_ec_key(EcKey::generate(&group)?)
+}
+
+// Creates a self-signed certificate for the key following `profile`
+fn create_self_signed_certificate(
+    private_key: &PKey<Private>,
+    profile: &CertificateProfile,
+) -> Result<Vec<u8>, ErrorStack> {
+    let subject = profile.subject.to_x509_name()?;
+    let issuer = match &profile.issuer {
+        Some(issuer) => issuer.to_x509_name()?,
+        None => profile.subject.to_x509_name()?,
+    };
+
+    let mut builder = X509Builder::new()?;
+    builder.set_version(2)?;
+    builder.set_serial_number(&profile.serial.serial_number()?.to_asn1_integer()?)?;
+    builder.set_subject_name(&subject)?;
+    builder.set_issuer_name(&issuer)?;
+    builder.set_pubkey(private_key)?;
+    builder.set_not_before(&Asn1Time::days_from_now(0)?)?;
+    builder.set_not_after(&Asn1Time::days_from_now(profile.validity_days)?)?;
+    if let Some(key_usage) = profile.key_usage.to_extension()? {
+        builder.append_extension(key_usage)?;
+    }
+
+    builder.sign(private_key, signing_digest(private_key))?;
+    builder.build().to_der()
+}
+
+// Creates a PEM certificate signing request for the key with the subject and
+// key usage of `profile`. Validity and serial number are left to the CA.
+fn create_certificate_request(
+    private_key: &PKey<Private>,
+    profile: &CertificateProfile,
+) -> Result<Vec<u8>, ErrorStack> {
+    let mut builder = X509ReqBuilder::new()?;
+    builder.set_version(0)?;
+    builder.set_subject_name(&profile.subject.to_x509_name()?)?;
+    builder.set_pubkey(private_key)?;
+    if let Some(key_usage) = profile.key_usage.to_extension()? {
+        let mut extensions = Stack::new()?;
+        extensions.push(key_usage)?;
+        builder.add_extensions(&extensions)?;
+    }
+
+    builder.sign(private_key, signing_digest(private_key))?;
+    builder.build().to_pem()
+}
+
+// P-384 keys sign with SHA-384 to match their strength, other keys with SHA-256
+fn signing_digest(private_key: &PKey<Private>) -> MessageDigest {
+    match private_key.ec_key() {
+        Ok(ec_key) if ec_key.group().order_bits() > 256 => MessageDigest::sha384(),
+        _ => MessageDigest::sha256(),
+    }
+}
+
+/// Persists an IdP identity to the given key and certificate files, as PEM or
+/// DER depending on their extension
+pub fn persist_idp_identity(
+    idp: &IdentityProvider,
+    cert_chain: &[Vec<u8>],
+    files: &KeyFiles,
+) -> io::Result<()> {
     debug!("Persisting IdP identity to disk");
 
-    // Get the RSA private key in DER format
+    // Get the private key in DER format
     let key_der = match idp.export_private_key_der() {
         Ok(key) => key,
         Err(e) => {
             error!("Failed to get private key DER from IdentityProvider: {}", e);
-            return Err(io::Error::new(
-                io::ErrorKind::Other,
-                format!("Failed to get private key DER: {}", e),
-            ));
+            return Err(io::Error::other(format!(
+                "Failed to get private key DER: {}",
+                e
+            )));
         }
     };
 
-    debug!("Writing private key to {}", KEY_FILE_PATH);
-    fs::write(KEY_FILE_PATH, key_der)?;
+    write_private_key(&files.key_path, key_der, files.passphrase.as_deref())?;
 
     // Save certificate
-    debug!("Writing certificate to {}", CERT_FILE_PATH);
-    fs::write(CERT_FILE_PATH, cert_der)?;
+    write_certificate_chain(&files.cert_path, cert_chain)?;
 
     info!("IdP identity successfully persisted to disk");
     Ok(())
 }
+
+// Writes a private key as PKCS#8 PEM or DER depending on the file extension,
+// encrypted with AES-256 when a passphrase is given
+fn write_private_key(path: &Path, key_der: Vec<u8>, passphrase: Option<&str>) -> io::Result<()> {
+    debug!("Writing private key to {}", path.display());
+    let private_key = PKey::private_key_from_der(&key_der)?;
+    let key = match (has_pem_extension(path), passphrase) {
+        (true, Some(passphrase)) => private_key
+            .private_key_to_pem_pkcs8_passphrase(Cipher::aes_256_cbc(), passphrase.as_bytes())?,
+        (false, Some(passphrase)) => private_key
+            .private_key_to_pkcs8_passphrase(Cipher::aes_256_cbc(), passphrase.as_bytes())?,
+        (true, None) => private_key.private_key_to_pem_pkcs8()?,
+        (false, None) => key_der,
+    };
+    if passphrase.is_none() {
+        warn!(
+            "Writing unencrypted private key to {}. Set IDP_KEY_PASSPHRASE or \
+             IDP_KEY_ENCRYPTION_KEY to encrypt it",
+            path.display()
+        );
+    }
+    write_atomically(path, &key, PRIVATE_KEY_FILE_MODE)
+}
+
+/// Replaces `path` with `contents` through a temporary file, so readers never
+/// see a partially written file. The file is created with `mode` on Unix.
+pub fn write_atomically(path: &Path, contents: &[u8], mode: u32) -> io::Result<()> {
+    let mut temporary_name = OsString::from(".");
+    temporary_name.push(path.file_name().unwrap_or_default());
+    temporary_name.push(".tmp");
+    let temporary_path = path.with_file_name(temporary_name);
+
+    // A file left by an interrupted write may have other permissions
+    match fs::remove_file(&temporary_path) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e),
+    }
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    options.mode(mode);
+    #[cfg(not(unix))]
+    let _ = mode;
+    let mut file = options.open(&temporary_path)?;
+    file.write_all(contents)?;
+    file.sync_all()?;
+    fs::rename(&temporary_path, path)
+}
+
+/// Writes a certificate chain as PEM or DER depending on the file extension. A
+/// DER file only holds the first certificate.
+pub fn write_certificate_chain(path: &Path, cert_chain: &[Vec<u8>]) -> io::Result<()> {
+    debug!("Writing certificate to {}", path.display());
+    let contents = if has_pem_extension(path) {
+        cert_chain
+            .iter()
+            .map(|cert_der| pem::encode(&pem::Pem::new("CERTIFICATE", cert_der.clone())))
+            .collect::<String>()
+            .into_bytes()
+    } else {
+        cert_chain[0].clone()
+    };
+    write_atomically(path, &contents, CERTIFICATE_FILE_MODE)
+}
+
+/// Loads an X.509 certificate from a PEM or DER file, returning it as DER
+pub fn load_certificate_der<P: AsRef<Path>>(path: P) -> io::Result<Vec<u8>> {
+    let contents = fs::read(&path)?;
+
+    match pem::parse(&contents) {
+        Ok(parsed) if parsed.tag() == "CERTIFICATE" => {
+            debug!("Loaded PEM certificate from {}", path.as_ref().display());
+            Ok(parsed.into_contents())
+        }
+        Ok(parsed) => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Expected a CERTIFICATE PEM block, found {}", parsed.tag()),
+        )),
+        Err(_) => {
+            debug!("Loaded DER certificate from {}", path.as_ref().display());
+            Ok(contents)
+        }
+    }
+}
+
+/// Returns when a DER certificate stops being valid
+pub fn certificate_expiry(cert_der: &[u8]) -> io::Result<DateTime<Utc>> {
+    let cert = X509::from_der(cert_der)?;
+    let since_epoch = Asn1Time::from_unix(0)?.diff(cert.not_after())?;
+    DateTime::from_timestamp(
+        i64::from(since_epoch.days) * 86_400 + i64::from(since_epoch.secs),
+        0,
+    )
+    .ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Certificate expiry is out of range",
+        )
+    })
+}
+
+/// Returns the SHA-256 fingerprint of a DER certificate as colon-separated hex
+pub fn certificate_fingerprint(cert_der: &[u8]) -> io::Result<String> {
+    let digest = hash(MessageDigest::sha256(), cert_der)?;
+    Ok(digest
+        .iter()
+        .map(|byte| format!("{:02X}", byte))
+        .collect::<Vec<_>>()
+        .join(":"))
+}
+
+fn is_pem(contents: &[u8]) -> bool {
+    contents.trim_ascii_start().starts_with(b"-----BEGIN")
+}
+
+fn has_pem_extension(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|extension| extension.eq_ignore_ascii_case("pem"))
+}
+
+// Inserts `label` before the extension of the file name in `path`
+fn labelled_path(path: &Path, label: &str) -> PathBuf {
+    let mut file_name = OsString::from(path.file_stem().unwrap_or_default());
+    file_name.push(".");
+    file_name.push(label);
+    if let Some(extension) = path.extension() {
+        file_name.push(".");
+        file_name.push(extension);
+    }
+    path.with_file_name(file_name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use openssl::x509::X509Req;
+    use uuid::Uuid;
+
+    #[test]
+    fn key_types_round_trip_through_their_names() {
+        for key_type in [
+            IdpKeyType::Rsa2048,
+            IdpKeyType::Rsa3072,
+            IdpKeyType::Rsa4096,
+            IdpKeyType::EcdsaP256,
+            IdpKeyType::EcdsaP384,
+        ] {
+            assert_eq!(key_type.to_string().parse::<IdpKeyType>(), Ok(key_type));
+        }
+        assert_eq!(
+            "ECDSA-P256".parse::<IdpKeyType>(),
+            Ok(IdpKeyType::EcdsaP256)
+        );
+        assert!("dsa-1024".parse::<IdpKeyType>().is_err());
+    }
+
+    #[test]
+    fn generate_identity_provider_uses_the_key_type() {
+        for (key_type, rsa_bits, curve, digest) in [
+            (
+                IdpKeyType::Rsa3072,
+                Some(3072),
+                None,
+                Nid::SHA256WITHRSAENCRYPTION,
+            ),
+            (
+                IdpKeyType::EcdsaP256,
+                None,
+                Some(Nid::X9_62_PRIME256V1),
+                Nid::ECDSA_WITH_SHA256,
+            ),
+            (
+                IdpKeyType::EcdsaP384,
+                None,
+                Some(Nid::SECP384R1),
+                Nid::ECDSA_WITH_SHA384,
+            ),
+        ] {
+            let (idp, cert_chain) =
+                generate_identity_provider(key_type, &CertificateProfile::default()).unwrap();
+            let cert = X509::from_der(&cert_chain[0]).unwrap();
+            let public_key = cert.public_key().unwrap();
+            assert_eq!(public_key.id(), key_type.id());
+            assert_eq!(public_key.rsa().ok().map(|rsa| rsa.size() * 8), rsa_bits);
+            assert_eq!(
+                public_key
+                    .ec_key()
+                    .ok()
+                    .and_then(|ec_key| ec_key.group().curve_name()),
+                curve
+            );
+            assert_eq!(cert.signature_algorithm().object().nid(), digest);
+
+            let private_key =
+                PKey::private_key_from_der(&idp.export_private_key_der().unwrap()).unwrap();
+            assert!(public_key.public_eq(&private_key));
+        }
+    }
+
+    fn temp_dir() -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("idp-cert-util-{}", Uuid::new_v4().simple()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn key_files(dir: &Path, extension: &str, passphrase: Option<&str>) -> KeyFiles {
+        KeyFiles {
+            key_path: dir.join(format!("key.{}", extension)),
+            cert_path: dir.join(format!("cert.{}", extension)),
+            passphrase: passphrase.map(str::to_string),
+        }
+    }
+
+    #[test]
+    fn persisted_ec_identity_loads_back() {
+        let dir = temp_dir();
+        let files = key_files(&dir, "der", None);
+
+        let (idp, cert_chain) =
+            generate_identity_provider(IdpKeyType::EcdsaP256, &CertificateProfile::default())
+                .unwrap();
+        persist_idp_identity(&idp, &cert_chain, &files).unwrap();
+        let (loaded, loaded_cert_chain) = load_identity_provider(&files).unwrap();
+        assert_eq!(loaded_cert_chain, cert_chain);
+        assert_eq!(
+            loaded.export_private_key_der().unwrap(),
+            idp.export_private_key_der().unwrap()
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn pem_files_keep_the_certificate_chain() {
+        let dir = temp_dir();
+        let files = key_files(&dir, "pem", None);
+
+        let (idp, mut cert_chain) =
+            generate_identity_provider(IdpKeyType::Rsa2048, &CertificateProfile::default())
+                .unwrap();
+        let (_, intermediate) =
+            generate_identity_provider(IdpKeyType::EcdsaP256, &CertificateProfile::default())
+                .unwrap();
+        cert_chain.extend(intermediate);
+        persist_idp_identity(&idp, &cert_chain, &files).unwrap();
+        assert!(is_pem(&fs::read(&files.key_path).unwrap()));
+
+        let (_, loaded_cert_chain) = load_identity_provider(&files).unwrap();
+        assert_eq!(loaded_cert_chain, cert_chain);
+
+        // A DER file only has room for the IdP certificate
+        let der_path = dir.join("chain.der");
+        write_certificate_chain(&der_path, &cert_chain).unwrap();
+        assert_eq!(
+            load_certificate_chain(&der_path).unwrap(),
+            vec![cert_chain[0].clone()]
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn load_identity_provider_rejects_a_certificate_for_another_key() {
+        let dir = temp_dir();
+        let files = key_files(&dir, "der", None);
+
+        let (idp, _) =
+            generate_identity_provider(IdpKeyType::EcdsaP256, &CertificateProfile::default())
+                .unwrap();
+        let (_, other_cert_chain) =
+            generate_identity_provider(IdpKeyType::EcdsaP256, &CertificateProfile::default())
+                .unwrap();
+        persist_idp_identity(&idp, &other_cert_chain, &files).unwrap();
+        let Err(error) = load_identity_provider(&files) else {
+            panic!("loaded a certificate that does not match the key");
+        };
+        assert!(error.to_string().contains("does not match"), "{}", error);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn encrypted_keys_need_their_passphrase() {
+        let dir = temp_dir();
+        let files = key_files(&dir, "pem", Some("secret"));
+
+        let (idp, cert_chain) =
+            generate_identity_provider(IdpKeyType::EcdsaP256, &CertificateProfile::default())
+                .unwrap();
+        persist_idp_identity(&idp, &cert_chain, &files).unwrap();
+        let private_key =
+            PKey::private_key_from_der(&idp.export_private_key_der().unwrap()).unwrap();
+        let encrypted = private_key
+            .private_key_to_pem_pkcs8_passphrase(Cipher::aes_256_cbc(), b"secret")
+            .unwrap();
+        write_atomically(&files.key_path, &encrypted, PRIVATE_KEY_FILE_MODE).unwrap();
+
+        load_identity_provider(&files).unwrap();
+        for passphrase in [None, Some("wrong")] {
+            let files = KeyFiles {
+                passphrase: passphrase.map(str::to_string),
+                ..files.clone()
+            };
+            assert!(load_identity_provider(&files).is_err());
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    fn key_file_mode(files: &KeyFiles) -> u32 {
+        fs::metadata(&files.key_path).unwrap().permissions().mode() & 0o777
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn unencrypted_keys_are_encrypted_in_place_with_a_passphrase() {
+        let dir = temp_dir();
+        let files = key_files(&dir, "der", None);
+
+        let (idp, cert_chain) =
+            generate_identity_provider(IdpKeyType::EcdsaP256, &CertificateProfile::default())
+                .unwrap();
+        persist_idp_identity(&idp, &cert_chain, &files).unwrap();
+        assert_eq!(key_file_mode(&files), PRIVATE_KEY_FILE_MODE);
+        assert!(!is_encrypted_key(&fs::read(&files.key_path).unwrap()));
+
+        let files = KeyFiles {
+            passphrase: Some("secret".to_string()),
+            ..files
+        };
+        load_identity_provider(&files).unwrap();
+        assert!(is_encrypted_key(&fs::read(&files.key_path).unwrap()));
+        assert_eq!(key_file_mode(&files), PRIVATE_KEY_FILE_MODE);
+
+        let (loaded, _) = load_identity_provider(&files).unwrap();
+        assert_eq!(
+            loaded.export_private_key_der().unwrap(),
+            idp.export_private_key_der().unwrap()
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn keys_readable_by_all_users_are_refused() {
+        let dir = temp_dir();
+        let files = key_files(&dir, "pem", None);
+
+        let (idp, cert_chain) =
+            generate_identity_provider(IdpKeyType::EcdsaP256, &CertificateProfile::default())
+                .unwrap();
+        persist_idp_identity(&idp, &cert_chain, &files).unwrap();
+        fs::set_permissions(&files.key_path, fs::Permissions::from_mode(0o644)).unwrap();
+        let Err(error) = load_identity_provider(&files) else {
+            panic!("loaded a private key readable by all users");
+        };
+        assert_eq!(error.kind(), io::ErrorKind::PermissionDenied);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn sibling_files_insert_the_label_before_the_extension() {
+        let files = key_files(Path::new("/etc/idp"), "pem", None).sibling("next");
+        assert_eq!(files.key_path, Path::new("/etc/idp/key.next.pem"));
+        assert_eq!(files.cert_path, Path::new("/etc/idp/cert.next.pem"));
+    }
+
+    #[test]
+    fn distinguished_names_parse_escaped_commas() {
+        let name: DistinguishedName = r"CN=My IdP, O=Example\, Inc,C=US".parse().unwrap();
+        assert_eq!(
+            name,
+            DistinguishedName(vec![
+                ("CN".to_string(), "My IdP".to_string()),
+                ("O".to_string(), "Example, Inc".to_string()),
+                ("C".to_string(), "US".to_string()),
+            ])
+        );
+        assert!("CN=My IdP,Example".parse::<DistinguishedName>().is_err());
+        assert!("XX=unknown".parse::<DistinguishedName>().is_err());
+    }
+
+    #[test]
+    fn key_usage_and_serial_settings_parse() {
+        assert_eq!(
+            "digitalSignature, NonRepudiation".parse(),
+            Ok(KeyUsageFlags(vec![
+                KeyUsageFlag::DigitalSignature,
+                KeyUsageFlag::NonRepudiation,
+            ]))
+        );
+        assert_eq!("".parse(), Ok(KeyUsageFlags::default()));
+        assert!("certSign".parse::<KeyUsageFlags>().is_err());
+
+        assert_eq!("Timestamp".parse(), Ok(SerialStrategy::Timestamp));
+        assert_eq!("csr".parse(), Ok(CertificateMode::Csr));
+        assert!("sequential".parse::<SerialStrategy>().is_err());
+    }
+
+    fn custom_profile() -> CertificateProfile {
+        CertificateProfile {
+            mode: CertificateMode::SelfSigned,
+            subject: "CN=idp.example.com,O=Example".parse().unwrap(),
+            issuer: Some("CN=Example CA".parse().unwrap()),
+            validity_days: 30,
+            serial: SerialStrategy::Timestamp,
+            key_usage: "digitalSignature,nonRepudiation".parse().unwrap(),
+        }
+    }
+
+    fn name_entry(name: &openssl::x509::X509NameRef, nid: Nid) -> String {
+        name.entries_by_nid(nid)
+            .next()
+            .unwrap()
+            .data()
+            .as_utf8()
+            .unwrap()
+            .to_string()
+    }
+
+    #[test]
+    fn generated_certificates_follow_the_profile() {
+        let before = Utc::now().timestamp_millis();
+        let (_, cert_chain) =
+            generate_identity_provider(IdpKeyType::EcdsaP256, &custom_profile()).unwrap();
+        let cert = X509::from_der(&cert_chain[0]).unwrap();
+
+        assert_eq!(
+            name_entry(cert.subject_name(), Nid::COMMONNAME),
+            "idp.example.com"
+        );
+        assert_eq!(
+            name_entry(cert.subject_name(), Nid::ORGANIZATIONNAME),
+            "Example"
+        );
+        assert_eq!(
+            name_entry(cert.issuer_name(), Nid::COMMONNAME),
+            "Example CA"
+        );
+
+        let validity = cert.not_before().diff(cert.not_after()).unwrap();
+        assert_eq!(validity.days, 30);
+
+        let serial: i64 = cert
+            .serial_number()
+            .to_bn()
+            .unwrap()
+            .to_dec_str()
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert!(serial >= before && serial <= Utc::now().timestamp_millis());
+
+        let text = String::from_utf8(cert.to_text().unwrap()).unwrap();
+        assert!(text.contains("X509v3 Key Usage: critical"), "{}", text);
+        assert!(
+            text.contains("Digital Signature, Non Repudiation"),
+            "{}",
+            text
+        );
+    }
+
+    #[test]
+    fn csr_mode_writes_a_request_instead_of_a_certificate() {
+        let dir = temp_dir();
+        let files = key_files(&dir, "pem", None);
+        let profile = CertificateProfile {
+            mode: CertificateMode::Csr,
+            ..custom_profile()
+        };
+
+        let Err(error) = load_or_create_identity_provider(&files, IdpKeyType::EcdsaP256, &profile)
+        else {
+            panic!("created an identity in CSR mode");
+        };
+        assert_eq!(error.kind(), io::ErrorKind::NotFound);
+        assert!(files.key_path.exists());
+        assert!(!files.cert_path.exists());
+        assert_eq!(files.csr_path(), dir.join("cert.csr"));
+
+        let request = X509Req::from_pem(&fs::read(files.csr_path()).unwrap()).unwrap();
+        let public_key = request.public_key().unwrap();
+        assert!(request.verify(&public_key).unwrap());
+        assert!(public_key.public_eq(&load_private_key(&files.key_path, None).unwrap()));
+        assert_eq!(
+            name_entry(request.subject_name(), Nid::COMMONNAME),
+            "idp.example.com"
+        );
+
+        // A second run reuses the key rather than replacing it
+        let key = fs::read(&files.key_path).unwrap();
+        request_certificate(&files, IdpKeyType::EcdsaP256, &profile).unwrap();
+        assert_eq!(fs::read(&files.key_path).unwrap(), key);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
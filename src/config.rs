@@ -1,30 +1,88 @@
 use actix_web::web;
-use log::{error, info};
+use chrono::Duration;
+use log::{debug, error, info, warn};
 use std::env;
+use std::fs;
+use std::str::FromStr;
 use std::sync::Arc;
 
-use crate::cert_util::load_or_create_identity_provider;
+use crate::cert_expiry::{ExpiryThresholds, spawn_certificate_expiry_monitor};
+use crate::cert_util::{
+    CertificateProfile, DEFAULT_CERT_FILE_PATH, DEFAULT_KEY_FILE_PATH, IdpKeyType, KeyFiles,
+};
+use crate::key_rotation::{KeyRing, KeyStore, SigningKey, spawn_key_rotation};
+use crate::models::idp_metadata::{
+    ContactType, IdpMetadataConfig, MetadataContact, MetadataOrganization,
+};
+use crate::models::logout::LogoutStore;
+use crate::models::name_id::{NameIdService, SUPPORTED_NAME_ID_FORMATS, is_supported_format};
+use crate::models::pending_request::PendingRequestStore;
+use crate::models::service_provider::{ServiceProviderConfig, ServiceProviderRegistry};
+use crate::models::session::SessionStore;
 use crate::models::state::AppState;
 use crate::models::user::UserDatabase;
+use crate::pkcs11::{Pkcs11Config, Pkcs11Token};
+use crate::sp_metadata::{load_metadata_source, spawn_metadata_refresh};
 
-pub fn create_app_state() -> Result<web::Data<AppState>, Box<dyn std::error::Error>> {
-    // Load or create identity provider
-    let (idp, cert_der) = load_or_create_identity_provider()?;
+pub async fn create_app_state() -> Result<web::Data<AppState>, Box<dyn std::error::Error>> {
+    // Load or create the IdP signing keys
+    let key_files = KeyFiles {
+        key_path: env_or("IDP_KEY_PATH", DEFAULT_KEY_FILE_PATH.into())?,
+        cert_path: env_or("IDP_CERT_PATH", DEFAULT_CERT_FILE_PATH.into())?,
+        passphrase: key_passphrase()?,
+    };
+    let key_type: IdpKeyType = env_or("IDP_KEY_TYPE", IdpKeyType::Rsa2048)?;
+    let key_rotation_overlap_hours: i64 = env_or("KEY_ROTATION_OVERLAP_HOURS", 7 * 24)?;
+    let key_rotation_interval_days: i64 = env_or("KEY_ROTATION_INTERVAL_DAYS", 0)?;
+    let key_rotation_before_expiry_days: i64 = env_or("KEY_ROTATION_BEFORE_EXPIRY_DAYS", 0)?;
+    let signing_keys = KeyRing::load(
+        key_files,
+        key_store()?,
+        key_type,
+        certificate_profile()?,
+        Duration::hours(key_rotation_overlap_hours),
+        (key_rotation_interval_days > 0).then(|| Duration::days(key_rotation_interval_days)),
+        (key_rotation_before_expiry_days > 0)
+            .then(|| Duration::days(key_rotation_before_expiry_days)),
+    )?;
+    let signing_key = signing_keys.active();
 
     info!(
         "IdP initialized with certificate of size: {} bytes",
-        cert_der.len()
+        signing_key.cert_der.len()
     );
+    if key_rotation_interval_days > 0 {
+        info!(
+            "Signing keys rotate every {} days with {} hours of overlap",
+            key_rotation_interval_days, key_rotation_overlap_hours
+        );
+    }
+    if key_rotation_before_expiry_days > 0 {
+        info!(
+            "A new signing key is staged {} days before the active certificate expires",
+            key_rotation_before_expiry_days
+        );
+        if key_rotation_before_expiry_days * 24 <= key_rotation_overlap_hours {
+            warn!(
+                "KEY_ROTATION_BEFORE_EXPIRY_DAYS is not longer than the overlap period, so the \
+                 active certificate expires before its replacement is activated"
+            );
+        }
+    }
+
+    let cert_expiry_warning_days: i64 = env_or("CERT_EXPIRY_WARNING_DAYS", 30)?;
+    let cert_expiry_critical_days: i64 = env_or("CERT_EXPIRY_CRITICAL_DAYS", 7)?;
+    if cert_expiry_critical_days > cert_expiry_warning_days {
+        return Err("CERT_EXPIRY_CRITICAL_DAYS must not exceed CERT_EXPIRY_WARNING_DAYS".into());
+    }
 
     // Get configuration from environment variables
     let idp_entity_id =
         env::var("IDP_ENTITY_ID").map_err(|_| "IDP_ENTITY_ID environment variable is not set")?;
-    let sp_entity_id =
-        env::var("SP_ENTITY_ID").map_err(|_| "SP_ENTITY_ID environment variable is not set")?;
-    let sp_acs_url =
-        env::var("SP_ACS_URL").map_err(|_| "SP_ACS_URL environment variable is not set")?;
     let user_database_path = env::var("USER_DATABASE_PATH")
         .map_err(|_| "USER_DATABASE_PATH environment variable is not set")?;
+    let sp_config_path =
+        env::var("SP_CONFIG_PATH").map_err(|_| "SP_CONFIG_PATH environment variable is not set")?;
 
     // Load user database
     let user_database = UserDatabase::load_from_file(&user_database_path).map_err(|e| {
@@ -37,13 +95,307 @@ pub fn create_app_state() -> Result<web::Data<AppState>, Box<dyn std::error::Err
         user_database.users.len()
     );
 
+    // Load service provider registry
+    let sp_config = ServiceProviderConfig::load_from_file(&sp_config_path).map_err(|e| {
+        error!("Failed to load service provider configuration: {}", e);
+        format!("Failed to load service provider configuration: {}", e)
+    })?;
+    let service_providers = ServiceProviderRegistry::default();
+    for sp in sp_config.service_providers {
+        service_providers.register(sp)?;
+    }
+    let mut metadata_sources = Vec::new();
+    for source in sp_config.metadata_sources {
+        let sp = load_metadata_source(&source).await.map_err(|e| {
+            error!(
+                "Failed to load SP metadata from {}: {}",
+                source.describe(),
+                e
+            );
+            format!(
+                "Failed to load SP metadata from {}: {}",
+                source.describe(),
+                e
+            )
+        })?;
+        info!("Imported SP {} from {}", sp.entity_id, source.describe());
+        metadata_sources.push((sp.entity_id.clone(), source));
+        service_providers.register(sp)?;
+    }
+    if service_providers.is_empty() {
+        return Err(format!("No service providers configured in {}", sp_config_path).into());
+    }
+
+    info!("Loaded {} service provider(s)", service_providers.len());
+    let idp_key_type = signing_key.key_type();
+    if idp_key_type != key_type.id() {
+        info!(
+            "The active signing key is {:?}; keys generated from now on are {}",
+            idp_key_type, key_type
+        );
+    }
+    for sp in service_providers.all() {
+        sp.check_idp_key_type(idp_key_type)?;
+        if let Some(algorithm) = sp.signature_algorithm
+            && algorithm.key_type() != key_type.id()
+        {
+            warn!(
+                "SP {} is configured for {}, which will not work once the key is rotated to {}",
+                sp.entity_id,
+                algorithm.uri(),
+                key_type
+            );
+        }
+        debug!("Trusting service provider: {}", sp.entity_id);
+    }
+
+    let test_mode = env::var("TEST_MODE")
+        .map(|v| v.eq_ignore_ascii_case("true"))
+        .unwrap_or(false);
+    if test_mode {
+        warn!("TEST_MODE is enabled: users can be impersonated with the user_id query parameter");
+    }
+
+    let session_idle_timeout_minutes: i64 = env_or("SESSION_IDLE_TIMEOUT_MINUTES", 30)?;
+    let session_absolute_timeout_minutes: i64 = env_or("SESSION_ABSOLUTE_TIMEOUT_MINUTES", 8 * 60)?;
+    info!(
+        "IdP sessions expire after {} idle minutes or {} minutes in total",
+        session_idle_timeout_minutes, session_absolute_timeout_minutes
+    );
+
+    let name_id_salt_path =
+        env::var("NAME_ID_SALT_PATH").unwrap_or_else(|_| "name_id_salt.bin".to_string());
+    let persistent_name_ids_path = env::var("PERSISTENT_NAME_IDS_PATH")
+        .unwrap_or_else(|_| "persistent_name_ids.txt".to_string());
+    let name_ids =
+        NameIdService::load(&name_id_salt_path, &persistent_name_ids_path).map_err(|e| {
+            format!(
+                "Failed to load NameID salt from {}: {}",
+                name_id_salt_path, e
+            )
+        })?;
+
+    let metadata = metadata_config()?;
+
+    let admin_token = env::var("ADMIN_TOKEN")
+        .ok()
+        .filter(|token| !token.is_empty());
+    if admin_token.is_none() {
+        debug!("ADMIN_TOKEN is not set, admin endpoints are disabled");
+    }
+
     // Create AppState with configuration
-    Ok(web::Data::new(AppState {
-        idp: Arc::new(idp),
-        cert_der,
+    let state = web::Data::new(AppState {
+        signing_keys,
+        certificate_expiry: ExpiryThresholds {
+            warning: Duration::days(cert_expiry_warning_days),
+            critical: Duration::days(cert_expiry_critical_days),
+        },
         idp_entity_id,
-        sp_entity_id,
-        sp_acs_url,
+        metadata,
+        service_providers,
         user_database,
-    }))
+        pending_requests: PendingRequestStore::default(),
+        sessions: SessionStore::new(
+            Duration::minutes(session_idle_timeout_minutes),
+            Duration::minutes(session_absolute_timeout_minutes),
+        ),
+        logouts: LogoutStore::default(),
+        name_ids,
+        test_mode,
+        admin_token,
+    });
+
+    spawn_metadata_refresh(state.clone(), metadata_sources);
+    spawn_key_rotation(state.clone());
+    spawn_certificate_expiry_monitor(state.clone());
+
+    Ok(state)
+}
+
+// Reads how the IdP metadata is published. A dedicated signing key is used
+// when METADATA_SIGNING_KEY_PATH is set, and implies signing.
+fn metadata_config() -> Result<IdpMetadataConfig, Box<dyn std::error::Error>> {
+    let signing_key = match env::var("METADATA_SIGNING_KEY_PATH") {
+        Ok(key_path) => {
+            let files = KeyFiles {
+                key_path: key_path.into(),
+                cert_path: env::var("METADATA_SIGNING_CERT_PATH")
+                    .map_err(
+                        |_| "METADATA_SIGNING_CERT_PATH must be set with METADATA_SIGNING_KEY_PATH",
+                    )?
+                    .into(),
+                passphrase: secret("METADATA_SIGNING_KEY_PASSPHRASE")?,
+            };
+            let key = SigningKey::from_files(&files).map_err(|e| {
+                format!(
+                    "Failed to load the metadata signing key from {}: {}",
+                    files.key_path.display(),
+                    e
+                )
+            })?;
+            info!(
+                "Signing metadata with the key in {}",
+                files.key_path.display()
+            );
+            Some(key)
+        }
+        Err(_) => None,
+    };
+    let sign = signing_key.is_some() || env_or("METADATA_SIGN", false)?;
+    if sign && signing_key.is_none() {
+        info!("Signing metadata with the active IdP signing key");
+    }
+
+    let validity_hours: i64 = env_or("METADATA_VALIDITY_HOURS", 0)?;
+    let cache_duration_hours: i64 = env_or("METADATA_CACHE_DURATION_HOURS", 0)?;
+    if sign && validity_hours <= 0 {
+        warn!("Signed metadata has no validUntil; set METADATA_VALIDITY_HOURS to limit replay");
+    }
+
+    let name_id_formats = match env::var("METADATA_NAME_ID_FORMATS") {
+        Ok(formats) => formats
+            .split(',')
+            .map(str::trim)
+            .filter(|format| !format.is_empty())
+            .map(|format| {
+                if is_supported_format(format) {
+                    Ok(format.to_string())
+                } else {
+                    Err(format!(
+                        "Unsupported NameID format in METADATA_NAME_ID_FORMATS: {}",
+                        format
+                    ))
+                }
+            })
+            .collect::<Result<Vec<_>, _>>()?,
+        Err(_) => SUPPORTED_NAME_ID_FORMATS
+            .iter()
+            .map(|format| format.to_string())
+            .collect(),
+    };
+
+    let organization = match env::var("METADATA_ORGANIZATION_NAME") {
+        Ok(name) => Some(MetadataOrganization {
+            display_name: env::var("METADATA_ORGANIZATION_DISPLAY_NAME")
+                .unwrap_or_else(|_| name.clone()),
+            url: env::var("METADATA_ORGANIZATION_URL").map_err(
+                |_| "METADATA_ORGANIZATION_URL must be set with METADATA_ORGANIZATION_NAME",
+            )?,
+            name,
+        }),
+        Err(_) => None,
+    };
+    let mut contacts = Vec::new();
+    for contact_type in ContactType::ALL {
+        let variable = format!("METADATA_{}_CONTACT", contact_type.as_str().to_uppercase());
+        if let Ok(value) = env::var(&variable) {
+            contacts.push(
+                MetadataContact::parse(contact_type, &value)
+                    .map_err(|e| format!("{} is invalid: {}", variable, e))?,
+            );
+        }
+    }
+    if contacts.is_empty() {
+        debug!("No metadata contacts configured");
+    }
+
+    Ok(IdpMetadataConfig {
+        sign,
+        signing_key,
+        validity: (validity_hours > 0).then(|| Duration::hours(validity_hours)),
+        cache_duration: (cache_duration_hours > 0).then(|| Duration::hours(cache_duration_hours)),
+        name_id_formats,
+        organization,
+        contacts,
+    })
+}
+
+// Reads where the IdP private keys are kept: a PKCS#11 token when
+// IDP_PKCS11_MODULE is set, key files otherwise
+fn key_store() -> Result<KeyStore, Box<dyn std::error::Error>> {
+    let Ok(module_path) = env::var("IDP_PKCS11_MODULE") else {
+        return Ok(KeyStore::Files);
+    };
+    let config = Pkcs11Config {
+        module_path: module_path.into(),
+        token_label: env::var("IDP_PKCS11_TOKEN_LABEL")
+            .map_err(|_| "IDP_PKCS11_TOKEN_LABEL must be set with IDP_PKCS11_MODULE")?,
+        pin: secret("IDP_PKCS11_PIN")?
+            .ok_or("IDP_PKCS11_PIN or IDP_PKCS11_PIN_FILE must be set with IDP_PKCS11_MODULE")?,
+    };
+    let key_label = env_or("IDP_PKCS11_KEY_LABEL", "idp-signing".to_string())?;
+    info!(
+        "Signing with the key labelled {} in PKCS#11 token {}",
+        key_label, config.token_label
+    );
+    Ok(KeyStore::Pkcs11 {
+        token: Arc::new(Pkcs11Token::open(&config)?),
+        key_label,
+    })
+}
+
+// Reads a secret from the environment variable `name`, or from the file named
+// by `name` with a `_FILE` suffix
+fn secret(name: &str) -> Result<Option<String>, Box<dyn std::error::Error>> {
+    if let Ok(value) = env::var(name) {
+        return Ok(Some(value));
+    }
+    let file_variable = format!("{}_FILE", name);
+    match env::var(&file_variable) {
+        Ok(path) => {
+            let value = fs::read_to_string(&path)
+                .map_err(|e| format!("Failed to read {} {}: {}", file_variable, path, e))?;
+            Ok(Some(value.trim_end_matches(['\r', '\n']).to_string()))
+        }
+        Err(_) => Ok(None),
+    }
+}
+
+// Reads the secret protecting the IdP key files: a passphrase, or a
+// key-encryption key such as one provisioned by a secrets manager
+fn key_passphrase() -> Result<Option<String>, Box<dyn std::error::Error>> {
+    match (
+        secret("IDP_KEY_PASSPHRASE")?,
+        secret("IDP_KEY_ENCRYPTION_KEY")?,
+    ) {
+        (Some(_), Some(_)) => {
+            Err("Set only one of IDP_KEY_PASSPHRASE and IDP_KEY_ENCRYPTION_KEY".into())
+        }
+        (Some(secret), None) | (None, Some(secret)) if secret.is_empty() => {
+            Err("The IdP key passphrase must not be empty".into())
+        }
+        (passphrase, encryption_key) => Ok(passphrase.or(encryption_key)),
+    }
+}
+
+// Reads how certificates for newly generated keys are made, each setting
+// falling back to the default profile
+fn certificate_profile() -> Result<CertificateProfile, Box<dyn std::error::Error>> {
+    let default = CertificateProfile::default();
+    Ok(CertificateProfile {
+        mode: env_or("IDP_CERT_MODE", default.mode)?,
+        subject: env_or("IDP_CERT_SUBJECT", default.subject)?,
+        issuer: env::var("IDP_CERT_ISSUER")
+            .ok()
+            .map(|issuer| {
+                issuer
+                    .parse()
+                    .map_err(|e| format!("IDP_CERT_ISSUER has an invalid value: {}", e))
+            })
+            .transpose()?,
+        validity_days: env_or("IDP_CERT_VALIDITY_DAYS", default.validity_days)?,
+        serial: env_or("IDP_CERT_SERIAL", default.serial)?,
+        key_usage: env_or("IDP_CERT_KEY_USAGE", default.key_usage)?,
+    })
+}
+
+// Reads an optional environment variable, falling back to a default when unset
+fn env_or<T: FromStr>(name: &str, default: T) -> Result<T, Box<dyn std::error::Error>> {
+    match env::var(name) {
+        Ok(value) => value
+            .parse()
+            .map_err(|_| format!("{} has an invalid value: {}", name, value).into()),
+        Err(_) => Ok(default),
+    }
 }
@@ -0,0 +1,111 @@
+use actix_web::http::header::AUTHORIZATION;
+use actix_web::{HttpRequest, HttpResponse, Responder, web};
+use log::{error, info, warn};
+use openssl::memcmp;
+use std::io;
+
+use crate::models::state::AppState;
+
+/// Shows the IdP signing keys and their rotation schedule
+pub async fn key_status(req: HttpRequest, state: web::Data<AppState>) -> impl Responder {
+    if let Err(response) = authorize(&req, &state) {
+        return response;
+    }
+    key_status_response(&state)
+}
+
+/// Stages a new signing key, publishing its certificate in metadata
+pub async fn stage_key(req: HttpRequest, state: web::Data<AppState>) -> impl Responder {
+    if let Err(response) = authorize(&req, &state) {
+        return response;
+    }
+    info!("Admin request to stage a new signing key");
+    key_operation(state, |state| state.signing_keys.stage()).await
+}
+
+/// Switches signing to the staged key
+pub async fn activate_key(req: HttpRequest, state: web::Data<AppState>) -> impl Responder {
+    if let Err(response) = authorize(&req, &state) {
+        return response;
+    }
+    info!("Admin request to activate the staged signing key");
+    key_operation(state, |state| {
+        state.signing_keys.activate(&state.service_providers)
+    })
+    .await
+}
+
+/// Stops publishing the previous signing certificate
+pub async fn retire_key(req: HttpRequest, state: web::Data<AppState>) -> impl Responder {
+    if let Err(response) = authorize(&req, &state) {
+        return response;
+    }
+    info!("Admin request to retire the previous signing certificate");
+    key_operation(state, |state| state.signing_keys.retire()).await
+}
+
+// Checks the request carries `Authorization: Bearer <ADMIN_TOKEN>`. The admin
+// endpoints do not exist when no token is configured.
+fn authorize(req: &HttpRequest, state: &AppState) -> Result<(), HttpResponse> {
+    let Some(admin_token) = &state.admin_token else {
+        return Err(HttpResponse::NotFound().finish());
+    };
+    let token = req
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    match token {
+        Some(token)
+            if token.len() == admin_token.len()
+                && memcmp::eq(token.as_bytes(), admin_token.as_bytes()) =>
+        {
+            Ok(())
+        }
+        _ => {
+            warn!("Rejected unauthorized admin request to {}", req.path());
+            Err(HttpResponse::Unauthorized().finish())
+        }
+    }
+}
+
+// Runs a key operation off the async workers, as it reads and writes key files
+// and may generate a key, and responds with the resulting key status
+async fn key_operation(
+    state: web::Data<AppState>,
+    operation: fn(&AppState) -> io::Result<()>,
+) -> HttpResponse {
+    let operation_state = state.clone();
+    let result = web::block(move || operation(&operation_state))
+        .await
+        .unwrap_or_else(|e| Err(io::Error::other(e.to_string())));
+    match result {
+        Ok(()) => key_status_response(&state),
+        Err(e)
+            if matches!(
+                e.kind(),
+                io::ErrorKind::AlreadyExists
+                    | io::ErrorKind::NotFound
+                    | io::ErrorKind::Unsupported
+                    | io::ErrorKind::InvalidInput
+            ) =>
+        {
+            warn!("Rejected signing key operation: {}", e);
+            HttpResponse::Conflict().body(e.to_string())
+        }
+        Err(e) => {
+            error!("Signing key operation failed: {}", e);
+            HttpResponse::InternalServerError().body(format!("Signing key operation failed: {}", e))
+        }
+    }
+}
+
+fn key_status_response(state: &AppState) -> HttpResponse {
+    match state.signing_keys.status() {
+        Ok(status) => HttpResponse::Ok().json(status),
+        Err(e) => {
+            error!("Failed to read signing key status: {}", e);
+            HttpResponse::InternalServerError().body("Failed to read signing key status")
+        }
+    }
+}
@@ -0,0 +1,708 @@
+use actix_web::{HttpResponse, web};
+use base64::Engine as _;
+use base64::engine::general_purpose;
+use flate2::Compression;
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+use log::{debug, trace};
+use openssl::hash::MessageDigest;
+use openssl::pkey::Id;
+use openssl::sign::Verifier;
+use openssl::x509::X509;
+use samael::crypto;
+use samael::signature::Signature;
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use url::form_urlencoded;
+
+use crate::models::service_provider::{ServiceProvider, SignatureAlgorithm};
+use crate::signing::{SigningBackend, raw_ecdsa_to_der};
+
+/// Upper bound on the size of an inflated Redirect binding message, to guard
+/// against decompression bombs
+const MAX_INFLATED_MESSAGE_SIZE: u64 = 256 * 1024;
+
+/// Maximum RelayState length allowed by the SAML bindings specification
+pub const MAX_RELAY_STATE_LENGTH: usize = 80;
+
+/// Decodes a SAML message received over the HTTP-POST binding
+pub fn decode_post_message(encoded: &str) -> Result<String, Box<dyn std::error::Error>> {
+    let decoded = decode_base64(encoded)?;
+    let xml = String::from_utf8(decoded)
+        .map_err(|e| format!("Decoded SAML message is not valid UTF-8: {}", e))?;
+    trace!("Decoded POST binding message: {}", xml);
+    Ok(xml)
+}
+
+/// Decodes a SAML message received over the HTTP-Redirect binding.
+///
+/// The value is expected to already be URL-decoded; it is then base64 decoded
+/// and inflated using raw DEFLATE (no zlib header) as required by the binding.
+pub fn decode_redirect_message(encoded: &str) -> Result<String, Box<dyn std::error::Error>> {
+    let compressed = decode_base64(encoded)?;
+    debug!(
+        "Inflating Redirect binding message of {} compressed bytes",
+        compressed.len()
+    );
+
+    let mut inflated = Vec::new();
+    DeflateDecoder::new(compressed.as_slice())
+        .take(MAX_INFLATED_MESSAGE_SIZE + 1)
+        .read_to_end(&mut inflated)
+        .map_err(|e| format!("Failed to inflate SAML message: {}", e))?;
+
+    if inflated.len() as u64 > MAX_INFLATED_MESSAGE_SIZE {
+        return Err(format!(
+            "Inflated SAML message exceeds {} bytes",
+            MAX_INFLATED_MESSAGE_SIZE
+        )
+        .into());
+    }
+
+    let xml = String::from_utf8(inflated)
+        .map_err(|e| format!("Inflated SAML message is not valid UTF-8: {}", e))?;
+    trace!("Decoded Redirect binding message: {}", xml);
+    Ok(xml)
+}
+
+/// Builds an auto-submitting HTML form delivering a SAML message over the
+/// HTTP-POST binding. `message_param` is either `SAMLRequest` or `SAMLResponse`.
+pub fn post_binding_form(
+    action: &str,
+    message_param: &str,
+    xml: &str,
+    relay_state: &str,
+) -> HttpResponse {
+    let encoded_message = general_purpose::STANDARD.encode(xml.as_bytes());
+
+    // Only include RelayState when there is one to echo back
+    let relay_state_input = if relay_state.is_empty() {
+        String::new()
+    } else {
+        format!(
+            r#"<input type="hidden" name="RelayState" value="{}" />"#,
+            escape_html(relay_state)
+        )
+    };
+
+    // Create auto-submit form for the browser
+    let form = format!(
+        r#"
+    <html>
+        <head>
+            <title>SAML {}</title>
+        </head>
+        <body>
+            <form method="post" action="{}" id="SAMLForm">
+                <input type="hidden" name="{}" value="{}" />
+                {}
+                <input id="SAMLSubmitButton" type="submit" value="Submit" />
+            </form>
+            <script>
+                document.getElementById('SAMLSubmitButton').style.visibility="hidden";
+                document.getElementById('SAMLForm').submit();
+            </script>
+        </body>
+    </html>
+    "#,
+        message_param,
+        escape_html(action),
+        message_param,
+        encoded_message,
+        relay_state_input
+    );
+
+    HttpResponse::Ok()
+        .content_type("text/html; charset=utf-8")
+        .body(form)
+}
+
+/// Builds the URL delivering a SAML message over the HTTP-Redirect binding,
+/// signed with the IdP's private key using `algorithm`
+pub fn signed_redirect_url(
+    location: &str,
+    message_param: &str,
+    xml: &str,
+    relay_state: &str,
+    signer: &dyn SigningBackend,
+    algorithm: SignatureAlgorithm,
+) -> Result<String, Box<dyn std::error::Error>> {
+    let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(xml.as_bytes())?;
+    let encoded_message = general_purpose::STANDARD.encode(encoder.finish()?);
+
+    if signer.key_type() != algorithm.key_type() {
+        return Err(format!(
+            "Signature algorithm {} does not match the IdP key type {:?}",
+            algorithm.uri(),
+            signer.key_type()
+        )
+        .into());
+    }
+
+    let mut query = form_urlencoded::Serializer::new(String::new());
+    query.append_pair(message_param, &encoded_message);
+    if !relay_state.is_empty() {
+        query.append_pair("RelayState", relay_state);
+    }
+    query.append_pair("SigAlg", algorithm.uri());
+    let signed_octets = query.finish();
+
+    let signature = signer.sign(algorithm.message_digest(), signed_octets.as_bytes())?;
+
+    let signature_param = form_urlencoded::Serializer::new(String::new())
+        .append_pair("Signature", &general_purpose::STANDARD.encode(signature))
+        .finish();
+    let separator = if location.contains('?') { '&' } else { '?' };
+    Ok(format!(
+        "{}{}{}&{}",
+        location, separator, signed_octets, signature_param
+    ))
+}
+
+/// Escapes a value for safe inclusion in HTML text or attributes
+pub fn escape_html(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#x27;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+/// Checks that a RelayState value is acceptable to echo back to the SP
+pub fn validate_relay_state(relay_state: &str) -> Result<(), Box<dyn std::error::Error>> {
+    if relay_state.len() > MAX_RELAY_STATE_LENGTH {
+        return Err(format!(
+            "RelayState exceeds {} bytes ({} bytes)",
+            MAX_RELAY_STATE_LENGTH,
+            relay_state.len()
+        )
+        .into());
+    }
+    Ok(())
+}
+
+/// Verifies the enveloped XML signature of a message received over the
+/// HTTP-POST binding, checking that it covers the root element `root_id`.
+///
+/// SHA-1 signature and digest algorithms are refused unless `allow_sha1` is set.
+pub fn verify_post_signature(
+    xml: &str,
+    signature: Option<&Signature>,
+    root_id: &str,
+    cert_der: &[u8],
+    allow_sha1: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let signature = signature.ok_or("SAML message is not signed")?;
+
+    if !allow_sha1 {
+        let signed_info = &signature.signed_info;
+        let uses_sha1 = is_sha1_algorithm(&signed_info.signature_method.algorithm)
+            || signed_info
+                .reference
+                .iter()
+                .any(|reference| is_sha1_algorithm(&reference.digest_method.algorithm));
+        if uses_sha1 {
+            return Err("SHA-1 signatures are not accepted from this SP".into());
+        }
+    }
+
+    let expected_uri = format!("#{}", root_id);
+    let covers_root = signature
+        .signed_info
+        .reference
+        .iter()
+        .any(|reference| reference.uri.as_deref() == Some(expected_uri.as_str()));
+    if !covers_root {
+        return Err(format!("Signature does not reference the message ID {}", root_id).into());
+    }
+
+    crypto::verify_signed_xml(xml.as_bytes(), cert_der, Some("ID"))
+        .map_err(|e| format!("XML signature verification failed: {}", e))?;
+    debug!("Verified XML signature on message {}", root_id);
+    Ok(())
+}
+
+/// Verifies the signature of a message received from `sp`. Unsigned messages
+/// are accepted unless the SP is configured to sign its requests.
+///
+/// `raw_query` is the query string for messages received over the
+/// HTTP-Redirect binding and `None` for HTTP-POST.
+pub fn verify_sp_message(
+    sp: &ServiceProvider,
+    raw_query: Option<&str>,
+    message_param: &str,
+    xml: &str,
+    signature: Option<&Signature>,
+    message_id: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let is_signed = match raw_query {
+        Some(raw_query) => raw_query_param(raw_query, "Signature").is_some(),
+        None => signature.is_some(),
+    };
+    if !sp.authn_requests_signed && !is_signed {
+        return Ok(());
+    }
+
+    if sp.signing_certificates_der.is_empty() {
+        return Err(format!(
+            "No signing certificate is registered for SP {}",
+            sp.entity_id
+        )
+        .into());
+    }
+    // An SP rolling over its key publishes both certificates, and may sign
+    // with either of them
+    let mut result = Ok(());
+    for cert_der in &sp.signing_certificates_der {
+        result = match raw_query {
+            Some(raw_query) => {
+                verify_redirect_signature(raw_query, message_param, cert_der, sp.allow_sha1)
+            }
+            None => verify_post_signature(xml, signature, message_id, cert_der, sp.allow_sha1),
+        };
+        if result.is_ok() {
+            break;
+        }
+    }
+    result
+}
+
+/// Verifies the `SigAlg`/`Signature` query parameters of a message received
+/// over the HTTP-Redirect binding.
+///
+/// `raw_query` must be the query string exactly as sent by the SP, since the
+/// signature covers the URL-encoded parameter values. `message_param` is
+/// either `SAMLRequest` or `SAMLResponse`. SHA-1 is refused unless
+/// `allow_sha1` is set.
+pub fn verify_redirect_signature(
+    raw_query: &str,
+    message_param: &str,
+    cert_der: &[u8],
+    allow_sha1: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let params = web::Query::<HashMap<String, String>>::from_query(raw_query)
+        .map_err(|e| format!("Invalid query string: {}", e))?;
+    let sig_alg = params.get("SigAlg").ok_or("Missing SigAlg parameter")?;
+    if !allow_sha1 && is_sha1_algorithm(sig_alg) {
+        return Err("SHA-1 signatures are not accepted from this SP".into());
+    }
+    let signature = params
+        .get("Signature")
+        .ok_or("Missing Signature parameter")?;
+    let signature = decode_base64(signature)?;
+
+    // The signed octets are built from the raw, still URL-encoded values in a
+    // fixed order, regardless of their order in the received URL
+    let mut signed_octets = format!(
+        "{}={}",
+        message_param,
+        raw_query_param(raw_query, message_param)
+            .ok_or_else(|| format!("Missing {} parameter", message_param))?
+    );
+    if let Some(relay_state) = raw_query_param(raw_query, "RelayState") {
+        signed_octets.push_str(&format!("&RelayState={}", relay_state));
+    }
+    signed_octets.push_str(&format!(
+        "&SigAlg={}",
+        raw_query_param(raw_query, "SigAlg").ok_or("Missing SigAlg parameter")?
+    ));
+    trace!("Redirect binding signed octets: {}", signed_octets);
+
+    let (digest, key_id) = signature_algorithm(sig_alg)?;
+    let cert = X509::from_der(cert_der)?;
+    let public_key = cert.public_key()?;
+    if public_key.id() != key_id {
+        return Err(format!(
+            "SigAlg {} does not match the SP certificate key type",
+            sig_alg
+        )
+        .into());
+    }
+
+    // XML DSig encodes ECDSA signatures as raw r || s, while OpenSSL expects DER
+    let signature = if key_id == Id::EC {
+        raw_ecdsa_to_der(&signature)?
+    } else {
+        signature
+    };
+
+    let mut verifier = Verifier::new(digest, &public_key)?;
+    verifier.update(signed_octets.as_bytes())?;
+    if !verifier.verify(&signature)? {
+        return Err("Redirect binding signature is invalid".into());
+    }
+
+    debug!("Verified Redirect binding signature using {}", sig_alg);
+    Ok(())
+}
+
+// Maps an XML DSig signature algorithm URI to its digest and key type
+fn signature_algorithm(sig_alg: &str) -> Result<(MessageDigest, Id), Box<dyn std::error::Error>> {
+    match sig_alg {
+        "http://www.w3.org/2000/09/xmldsig#rsa-sha1" => Ok((MessageDigest::sha1(), Id::RSA)),
+        "http://www.w3.org/2001/04/xmldsig-more#rsa-sha256" => {
+            Ok((MessageDigest::sha256(), Id::RSA))
+        }
+        "http://www.w3.org/2001/04/xmldsig-more#rsa-sha384" => {
+            Ok((MessageDigest::sha384(), Id::RSA))
+        }
+        "http://www.w3.org/2001/04/xmldsig-more#rsa-sha512" => {
+            Ok((MessageDigest::sha512(), Id::RSA))
+        }
+        "http://www.w3.org/2001/04/xmldsig-more#ecdsa-sha256" => {
+            Ok((MessageDigest::sha256(), Id::EC))
+        }
+        "http://www.w3.org/2001/04/xmldsig-more#ecdsa-sha384" => {
+            Ok((MessageDigest::sha384(), Id::EC))
+        }
+        "http://www.w3.org/2001/04/xmldsig-more#ecdsa-sha512" => {
+            Ok((MessageDigest::sha512(), Id::EC))
+        }
+        other => Err(format!("Unsupported signature algorithm: {}", other).into()),
+    }
+}
+
+// Returns whether an XML DSig algorithm URI names a SHA-1 based algorithm
+fn is_sha1_algorithm(uri: &str) -> bool {
+    uri.ends_with("sha1")
+}
+
+// Returns the raw (still URL-encoded) value of a query parameter
+fn raw_query_param<'a>(raw_query: &'a str, name: &str) -> Option<&'a str> {
+    raw_query.split('&').find_map(|pair| {
+        let (key, value) = pair.split_once('=')?;
+        (key == name).then_some(value)
+    })
+}
+
+// Some SPs wrap or pad the encoded message, so strip whitespace before decoding
+fn decode_base64(encoded: &str) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let cleaned: String = encoded.chars().filter(|c| !c.is_whitespace()).collect();
+    general_purpose::STANDARD
+        .decode(cleaned)
+        .map_err(|e| format!("Invalid base64 in SAML message: {}", e).into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cert_util::{CertificateProfile, IdpKeyType, generate_identity_provider};
+    use crate::signing::FileKey;
+    use flate2::write::ZlibEncoder;
+    use openssl::asn1::Asn1Time;
+    use openssl::ec::{EcGroup, EcKey};
+    use openssl::ecdsa::EcdsaSig;
+    use openssl::nid::Nid;
+    use openssl::pkey::{PKey, Private};
+    use openssl::rsa::Rsa;
+    use openssl::sign::Signer;
+    use openssl::x509::{X509Builder, X509NameBuilder};
+    use samael::schema::AuthnRequest;
+
+    fn deflate_and_encode(data: &[u8]) -> String {
+        let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(data).unwrap();
+        general_purpose::STANDARD.encode(encoder.finish().unwrap())
+    }
+
+    #[test]
+    fn decode_redirect_message_inflates_raw_deflate() {
+        let xml = r#"<samlp:AuthnRequest ID="_1"/>"#;
+        assert_eq!(
+            decode_redirect_message(&deflate_and_encode(xml.as_bytes())).unwrap(),
+            xml
+        );
+    }
+
+    #[test]
+    fn decode_redirect_message_accepts_wrapped_base64() {
+        let xml = r#"<samlp:AuthnRequest ID="_1"/>"#;
+        let encoded = deflate_and_encode(xml.as_bytes());
+        let (head, tail) = encoded.split_at(8);
+        assert_eq!(
+            decode_redirect_message(&format!("{}\r\n {}", head, tail)).unwrap(),
+            xml
+        );
+    }
+
+    #[test]
+    fn decode_redirect_message_caps_inflated_size() {
+        let limit = MAX_INFLATED_MESSAGE_SIZE as usize;
+        assert!(decode_redirect_message(&deflate_and_encode(&vec![b'a'; limit])).is_ok());
+        let error = decode_redirect_message(&deflate_and_encode(&vec![b'a'; limit + 1]))
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("exceeds"), "{}", error);
+    }
+
+    #[test]
+    fn decode_redirect_message_rejects_zlib_header() {
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(b"<samlp:AuthnRequest/>").unwrap();
+        let encoded = general_purpose::STANDARD.encode(encoder.finish().unwrap());
+        assert!(decode_redirect_message(&encoded).is_err());
+    }
+
+    const RSA_SHA256: &str = "http://www.w3.org/2001/04/xmldsig-more#rsa-sha256";
+    const RSA_SHA1: &str = "http://www.w3.org/2000/09/xmldsig#rsa-sha1";
+    const ECDSA_SHA256: &str = "http://www.w3.org/2001/04/xmldsig-more#ecdsa-sha256";
+
+    fn rsa_key() -> PKey<Private> {
+        PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap()
+    }
+
+    fn ec_key() -> PKey<Private> {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap()
+    }
+
+    fn self_signed_certificate(key: &PKey<Private>) -> Vec<u8> {
+        let mut name = X509NameBuilder::new().unwrap();
+        name.append_entry_by_text("CN", "sp.example.com").unwrap();
+        let name = name.build();
+
+        let mut builder = X509Builder::new().unwrap();
+        builder.set_version(2).unwrap();
+        builder.set_subject_name(&name).unwrap();
+        builder.set_issuer_name(&name).unwrap();
+        builder.set_pubkey(key).unwrap();
+        builder
+            .set_not_before(&Asn1Time::days_from_now(0).unwrap())
+            .unwrap();
+        builder
+            .set_not_after(&Asn1Time::days_from_now(1).unwrap())
+            .unwrap();
+        builder.sign(key, MessageDigest::sha256()).unwrap();
+        builder.build().to_der().unwrap()
+    }
+
+    // Signs like an SP does, producing raw r || s for ECDSA keys
+    fn sign(key: &PKey<Private>, digest: MessageDigest, data: &[u8]) -> Vec<u8> {
+        let mut signer = Signer::new(digest, key).unwrap();
+        signer.update(data).unwrap();
+        let signature = signer.sign_to_vec().unwrap();
+        if key.id() != Id::EC {
+            return signature;
+        }
+        let signature = EcdsaSig::from_der(&signature).unwrap();
+        let mut raw = signature.r().to_vec_padded(32).unwrap();
+        raw.extend(signature.s().to_vec_padded(32).unwrap());
+        raw
+    }
+
+    // Percent-encodes like some SPs do, with lowercase hex digits and `%20`
+    // for spaces, which a re-encoding verifier would get wrong
+    fn encode_lowercase(value: &str) -> String {
+        value
+            .bytes()
+            .map(|byte| match byte {
+                b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' => {
+                    (byte as char).to_string()
+                }
+                _ => format!("%{:02x}", byte),
+            })
+            .collect()
+    }
+
+    // Signs a Redirect binding message the way an SP does and returns the
+    // query string with the parameters in a different order
+    fn signed_query(key: &PKey<Private>, sig_alg: &str, digest: MessageDigest) -> String {
+        let message = encode_lowercase(&deflate_and_encode(b"<samlp:AuthnRequest/>"));
+        let relay_state = encode_lowercase("return to /app?x=1");
+        let sig_alg = encode_lowercase(sig_alg);
+        let signed_octets = format!(
+            "SAMLRequest={}&RelayState={}&SigAlg={}",
+            message, relay_state, sig_alg
+        );
+        let signature = sign(key, digest, signed_octets.as_bytes());
+        format!(
+            "SigAlg={}&Signature={}&RelayState={}&SAMLRequest={}",
+            sig_alg,
+            encode_lowercase(&general_purpose::STANDARD.encode(signature)),
+            relay_state,
+            message
+        )
+    }
+
+    #[test]
+    fn verify_redirect_signature_uses_raw_query_octets() {
+        let key = rsa_key();
+        let cert_der = self_signed_certificate(&key);
+        let query = signed_query(&key, RSA_SHA256, MessageDigest::sha256());
+        verify_redirect_signature(&query, "SAMLRequest", &cert_der, false).unwrap();
+
+        let tampered = query.replace("x%3d1", "x%3d2");
+        assert!(verify_redirect_signature(&tampered, "SAMLRequest", &cert_der, false).is_err());
+    }
+
+    #[test]
+    fn verify_redirect_signature_accepts_raw_ecdsa_signatures() {
+        let key = ec_key();
+        let cert_der = self_signed_certificate(&key);
+        let query = signed_query(&key, ECDSA_SHA256, MessageDigest::sha256());
+        verify_redirect_signature(&query, "SAMLRequest", &cert_der, false).unwrap();
+
+        let other_cert_der = self_signed_certificate(&ec_key());
+        assert!(verify_redirect_signature(&query, "SAMLRequest", &other_cert_der, false).is_err());
+    }
+
+    #[test]
+    fn verify_redirect_signature_accepts_idp_redirect_urls() {
+        for (key_type, algorithm) in [
+            (IdpKeyType::Rsa2048, SignatureAlgorithm::RsaSha256),
+            (IdpKeyType::EcdsaP384, SignatureAlgorithm::EcdsaSha384),
+        ] {
+            let (idp, cert_chain) =
+                generate_identity_provider(key_type, &CertificateProfile::default()).unwrap();
+            let url = signed_redirect_url(
+                "https://sp.example.com/slo",
+                "SAMLResponse",
+                "<samlp:LogoutResponse/>",
+                "state",
+                &FileKey::new(&idp).unwrap(),
+                algorithm,
+            )
+            .unwrap();
+            let (_, query) = url.split_once('?').unwrap();
+            verify_redirect_signature(query, "SAMLResponse", &cert_chain[0], false).unwrap();
+        }
+    }
+
+    #[test]
+    fn verify_sp_message_accepts_any_signing_certificate() {
+        let mut sp: ServiceProvider = serde_yaml::from_str(
+            "entity_id: https://sp.example.com\nacs_endpoints: []\nauthn_requests_signed: true\n",
+        )
+        .unwrap();
+        let key = rsa_key();
+        let query = signed_query(&key, RSA_SHA256, MessageDigest::sha256());
+        let verify = |sp: &ServiceProvider| {
+            verify_sp_message(sp, Some(&query), "SAMLRequest", "", None, "_1")
+        };
+        assert!(verify(&sp).is_err());
+
+        sp.signing_certificates_der = vec![self_signed_certificate(&rsa_key())];
+        assert!(verify(&sp).is_err());
+        sp.signing_certificates_der
+            .push(self_signed_certificate(&key));
+        verify(&sp).unwrap();
+    }
+
+    #[test]
+    fn verify_redirect_signature_checks_the_key_type() {
+        let key = rsa_key();
+        let query = signed_query(&key, RSA_SHA256, MessageDigest::sha256());
+        let ec_cert_der = self_signed_certificate(&ec_key());
+        let error = verify_redirect_signature(&query, "SAMLRequest", &ec_cert_der, false)
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("key type"), "{}", error);
+    }
+
+    #[test]
+    fn verify_redirect_signature_refuses_sha1_unless_allowed() {
+        let key = rsa_key();
+        let cert_der = self_signed_certificate(&key);
+        let query = signed_query(&key, RSA_SHA1, MessageDigest::sha1());
+        let error = verify_redirect_signature(&query, "SAMLRequest", &cert_der, false)
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("SHA-1"), "{}", error);
+        verify_redirect_signature(&query, "SAMLRequest", &cert_der, true).unwrap();
+    }
+
+    // Builds an AuthnRequest with an enveloped signature over the given ID
+    fn signed_authn_request(
+        key: &PKey<Private>,
+        cert_der: &[u8],
+        id: &str,
+        sig_alg: &str,
+        digest_alg: &str,
+    ) -> String {
+        let xml = format!(
+            r##"<samlp:AuthnRequest xmlns:samlp="urn:oasis:names:tc:SAML:2.0:protocol" xmlns:saml="urn:oasis:names:tc:SAML:2.0:assertion" ID="{id}" Version="2.0" IssueInstant="2026-01-01T00:00:00Z" Destination="https://idp.example.com/sso"><saml:Issuer>https://sp.example.com</saml:Issuer><ds:Signature xmlns:ds="http://www.w3.org/2000/09/xmldsig#"><ds:SignedInfo><ds:CanonicalizationMethod Algorithm="http://www.w3.org/2001/10/xml-exc-c14n#"/><ds:SignatureMethod Algorithm="{sig_alg}"/><ds:Reference URI="#{id}"><ds:Transforms><ds:Transform Algorithm="http://www.w3.org/2000/09/xmldsig#enveloped-signature"/><ds:Transform Algorithm="http://www.w3.org/2001/10/xml-exc-c14n#"/></ds:Transforms><ds:DigestMethod Algorithm="{digest_alg}"/><ds:DigestValue/></ds:Reference></ds:SignedInfo><ds:SignatureValue/><ds:KeyInfo><ds:X509Data><ds:X509Certificate>{cert}</ds:X509Certificate></ds:X509Data></ds:KeyInfo></ds:Signature></samlp:AuthnRequest>"##,
+            cert = general_purpose::STANDARD.encode(cert_der),
+        );
+        crypto::sign_xml(xml.as_str(), key.private_key_to_der().unwrap().as_slice()).unwrap()
+    }
+
+    fn signed_rsa_sha256_request(key: &PKey<Private>, cert_der: &[u8]) -> String {
+        signed_authn_request(
+            key,
+            cert_der,
+            "_request",
+            RSA_SHA256,
+            "http://www.w3.org/2001/04/xmlenc#sha256",
+        )
+    }
+
+    fn verify_authn_request(
+        xml: &str,
+        root_id: &str,
+        cert_der: &[u8],
+        allow_sha1: bool,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let request: AuthnRequest = xml.parse().unwrap();
+        verify_post_signature(
+            xml,
+            request.signature.as_ref(),
+            root_id,
+            cert_der,
+            allow_sha1,
+        )
+    }
+
+    #[test]
+    fn verify_post_signature_checks_the_signature() {
+        let key = rsa_key();
+        let cert_der = self_signed_certificate(&key);
+        let xml = signed_rsa_sha256_request(&key, &cert_der);
+        verify_authn_request(&xml, "_request", &cert_der, false).unwrap();
+
+        let other_cert_der = self_signed_certificate(&rsa_key());
+        assert!(verify_authn_request(&xml, "_request", &other_cert_der, false).is_err());
+
+        let tampered = xml.replace("https://sp.example.com<", "https://evil.example.com<");
+        assert!(verify_authn_request(&tampered, "_request", &cert_der, false).is_err());
+    }
+
+    #[test]
+    fn verify_post_signature_requires_reference_to_message_id() {
+        let key = rsa_key();
+        let cert_der = self_signed_certificate(&key);
+        let xml = signed_rsa_sha256_request(&key, &cert_der);
+        let error = verify_authn_request(&xml, "_other", &cert_der, false)
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("does not reference"), "{}", error);
+    }
+
+    #[test]
+    fn verify_post_signature_requires_a_signature() {
+        let cert_der = self_signed_certificate(&rsa_key());
+        assert!(
+            verify_post_signature("<samlp:AuthnRequest/>", None, "_request", &cert_der, false)
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn verify_post_signature_refuses_sha1_unless_allowed() {
+        let key = rsa_key();
+        let cert_der = self_signed_certificate(&key);
+        let xml = signed_authn_request(
+            &key,
+            &cert_der,
+            "_request",
+            RSA_SHA256,
+            "http://www.w3.org/2000/09/xmldsig#sha1",
+        );
+        let error = verify_authn_request(&xml, "_request", &cert_der, false)
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("SHA-1"), "{}", error);
+        verify_authn_request(&xml, "_request", &cert_der, true).unwrap();
+    }
+}
@@ -0,0 +1,242 @@
+use base64::Engine as _;
+use base64::engine::general_purpose;
+use log::debug;
+use openssl::encrypt::Encrypter;
+use openssl::hash::MessageDigest;
+use openssl::pkey::Id;
+use openssl::rand::rand_bytes;
+use openssl::rsa::Padding;
+use openssl::symm::{Cipher, encrypt, encrypt_aead};
+use openssl::x509::X509;
+
+use crate::models::service_provider::{DataEncryptionAlgorithm, KeyTransportAlgorithm};
+
+const GCM_IV_LENGTH: usize = 12;
+const GCM_TAG_LENGTH: usize = 16;
+const CBC_IV_LENGTH: usize = 16;
+
+/// How to encrypt an assertion for an SP
+pub struct AssertionEncryption<'a> {
+    /// The SP's RSA encryption certificate
+    pub certificate_der: &'a [u8],
+    pub data_algorithm: DataEncryptionAlgorithm,
+    pub key_transport: KeyTransportAlgorithm,
+}
+
+/// Encrypts a serialized `Assertion` into an `EncryptedAssertion` element.
+///
+/// The assertion is encrypted with a fresh random key, which is in turn
+/// encrypted to the SP's certificate and carried in an `EncryptedKey`.
+pub fn encrypt_assertion(
+    assertion_xml: &str,
+    encryption: &AssertionEncryption,
+) -> Result<String, Box<dyn std::error::Error>> {
+    let certificate = X509::from_der(encryption.certificate_der)?;
+    let public_key = certificate.public_key()?;
+    if public_key.id() != Id::RSA {
+        return Err("Assertion encryption requires an RSA encryption certificate".into());
+    }
+
+    let (cipher, is_gcm) = match encryption.data_algorithm {
+        DataEncryptionAlgorithm::Aes128Gcm => (Cipher::aes_128_gcm(), true),
+        DataEncryptionAlgorithm::Aes256Gcm => (Cipher::aes_256_gcm(), true),
+        DataEncryptionAlgorithm::Aes128Cbc => (Cipher::aes_128_cbc(), false),
+        DataEncryptionAlgorithm::Aes256Cbc => (Cipher::aes_256_cbc(), false),
+    };
+    let mut key = vec![0; cipher.key_len()];
+    rand_bytes(&mut key)?;
+
+    // XML Encryption prefixes the ciphertext with the IV, and for GCM appends the tag
+    let cipher_value = if is_gcm {
+        let mut iv = [0; GCM_IV_LENGTH];
+        rand_bytes(&mut iv)?;
+        let mut tag = [0; GCM_TAG_LENGTH];
+        let ciphertext = encrypt_aead(
+            cipher,
+            &key,
+            Some(&iv),
+            &[],
+            assertion_xml.as_bytes(),
+            &mut tag,
+        )?;
+        [&iv[..], &ciphertext[..], &tag[..]].concat()
+    } else {
+        let mut iv = [0; CBC_IV_LENGTH];
+        rand_bytes(&mut iv)?;
+        let ciphertext = encrypt(cipher, &key, Some(&iv), assertion_xml.as_bytes())?;
+        [&iv[..], &ciphertext[..]].concat()
+    };
+
+    let mut encrypter = Encrypter::new(&public_key)?;
+    encrypter.set_rsa_padding(Padding::PKCS1_OAEP)?;
+    let key_transport_params = match encryption.key_transport {
+        KeyTransportAlgorithm::RsaOaepMgf1p => {
+            r#"<ds:DigestMethod Algorithm="http://www.w3.org/2000/09/xmldsig#sha1"/>"#
+        }
+        KeyTransportAlgorithm::RsaOaep => {
+            encrypter.set_rsa_oaep_md(MessageDigest::sha256())?;
+            encrypter.set_rsa_mgf1_md(MessageDigest::sha256())?;
+            concat!(
+                r#"<ds:DigestMethod Algorithm="http://www.w3.org/2001/04/xmlenc#sha256"/>"#,
+                r#"<xenc11:MGF xmlns:xenc11="http://www.w3.org/2009/xmlenc11#" "#,
+                r#"Algorithm="http://www.w3.org/2009/xmlenc11#mgf1sha256"/>"#
+            )
+        }
+    };
+    let mut encrypted_key = vec![0; encrypter.encrypt_len(&key)?];
+    let encrypted_key_length = encrypter.encrypt(&key, &mut encrypted_key)?;
+    encrypted_key.truncate(encrypted_key_length);
+
+    debug!(
+        "Encrypted assertion using {} with key transport {}",
+        encryption.data_algorithm.uri(),
+        encryption.key_transport.uri()
+    );
+
+    Ok(format!(
+        concat!(
+            r#"<saml2:EncryptedAssertion xmlns:saml2="urn:oasis:names:tc:SAML:2.0:assertion">"#,
+            r#"<xenc:EncryptedData xmlns:xenc="http://www.w3.org/2001/04/xmlenc#" "#,
+            r#"Type="http://www.w3.org/2001/04/xmlenc#Element">"#,
+            r#"<xenc:EncryptionMethod Algorithm="{data_algorithm}"/>"#,
+            r#"<ds:KeyInfo xmlns:ds="http://www.w3.org/2000/09/xmldsig#">"#,
+            r#"<xenc:EncryptedKey>"#,
+            r#"<xenc:EncryptionMethod Algorithm="{key_transport}">{key_transport_params}</xenc:EncryptionMethod>"#,
+            r#"<ds:KeyInfo><ds:X509Data><ds:X509Certificate>{certificate}</ds:X509Certificate></ds:X509Data></ds:KeyInfo>"#,
+            r#"<xenc:CipherData><xenc:CipherValue>{encrypted_key}</xenc:CipherValue></xenc:CipherData>"#,
+            r#"</xenc:EncryptedKey>"#,
+            r#"</ds:KeyInfo>"#,
+            r#"<xenc:CipherData><xenc:CipherValue>{cipher_value}</xenc:CipherValue></xenc:CipherData>"#,
+            r#"</xenc:EncryptedData>"#,
+            r#"</saml2:EncryptedAssertion>"#
+        ),
+        data_algorithm = encryption.data_algorithm.uri(),
+        key_transport = encryption.key_transport.uri(),
+        key_transport_params = key_transport_params,
+        certificate = general_purpose::STANDARD.encode(encryption.certificate_der),
+        encrypted_key = general_purpose::STANDARD.encode(encrypted_key),
+        cipher_value = general_purpose::STANDARD.encode(cipher_value),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cert_util::{CertificateProfile, IdpKeyType, generate_identity_provider};
+    use openssl::encrypt::Decrypter;
+    use openssl::pkey::{PKey, Private};
+    use openssl::symm::{decrypt, decrypt_aead};
+
+    const ASSERTION: &str = r#"<saml2:Assertion xmlns:saml2="urn:oasis:names:tc:SAML:2.0:assertion" ID="_a"><saml2:Issuer>https://idp.example.com</saml2:Issuer></saml2:Assertion>"#;
+
+    fn sp_key(key_type: IdpKeyType) -> (PKey<Private>, Vec<u8>) {
+        let (idp, cert_chain) =
+            generate_identity_provider(key_type, &CertificateProfile::default()).unwrap();
+        let key = PKey::private_key_from_der(&idp.export_private_key_der().unwrap()).unwrap();
+        (key, cert_chain[0].clone())
+    }
+
+    // The EncryptedKey's CipherValue followed by the EncryptedData's
+    fn cipher_values(xml: &str) -> Vec<Vec<u8>> {
+        xml.split("<xenc:CipherValue>")
+            .skip(1)
+            .map(|value| {
+                let (value, _) = value.split_once("</xenc:CipherValue>").unwrap();
+                general_purpose::STANDARD.decode(value).unwrap()
+            })
+            .collect()
+    }
+
+    // Decrypts an EncryptedAssertion the way an SP would
+    fn decrypt_assertion(
+        xml: &str,
+        key: &PKey<Private>,
+        data_algorithm: DataEncryptionAlgorithm,
+        key_transport: KeyTransportAlgorithm,
+    ) -> String {
+        let values = cipher_values(xml);
+        let [encrypted_key, cipher_value] = values.as_slice() else {
+            panic!("expected the CipherValues of the EncryptedKey and EncryptedData");
+        };
+
+        let mut decrypter = Decrypter::new(key).unwrap();
+        decrypter.set_rsa_padding(Padding::PKCS1_OAEP).unwrap();
+        if key_transport == KeyTransportAlgorithm::RsaOaep {
+            decrypter.set_rsa_oaep_md(MessageDigest::sha256()).unwrap();
+            decrypter.set_rsa_mgf1_md(MessageDigest::sha256()).unwrap();
+        }
+        let mut data_key = vec![0; decrypter.decrypt_len(encrypted_key).unwrap()];
+        let data_key_length = decrypter.decrypt(encrypted_key, &mut data_key).unwrap();
+        data_key.truncate(data_key_length);
+
+        let plaintext = match data_algorithm {
+            DataEncryptionAlgorithm::Aes128Gcm | DataEncryptionAlgorithm::Aes256Gcm => {
+                let cipher = if data_algorithm == DataEncryptionAlgorithm::Aes128Gcm {
+                    Cipher::aes_128_gcm()
+                } else {
+                    Cipher::aes_256_gcm()
+                };
+                let (iv, rest) = cipher_value.split_at(GCM_IV_LENGTH);
+                let (ciphertext, tag) = rest.split_at(rest.len() - GCM_TAG_LENGTH);
+                decrypt_aead(cipher, &data_key, Some(iv), &[], ciphertext, tag).unwrap()
+            }
+            DataEncryptionAlgorithm::Aes128Cbc | DataEncryptionAlgorithm::Aes256Cbc => {
+                let cipher = if data_algorithm == DataEncryptionAlgorithm::Aes128Cbc {
+                    Cipher::aes_128_cbc()
+                } else {
+                    Cipher::aes_256_cbc()
+                };
+                let (iv, ciphertext) = cipher_value.split_at(CBC_IV_LENGTH);
+                decrypt(cipher, &data_key, Some(iv), ciphertext).unwrap()
+            }
+        };
+        String::from_utf8(plaintext).unwrap()
+    }
+
+    #[test]
+    fn encrypted_assertions_decrypt_with_the_sp_key() {
+        let (key, certificate_der) = sp_key(IdpKeyType::Rsa2048);
+        for data_algorithm in [
+            DataEncryptionAlgorithm::Aes128Gcm,
+            DataEncryptionAlgorithm::Aes256Gcm,
+            DataEncryptionAlgorithm::Aes128Cbc,
+            DataEncryptionAlgorithm::Aes256Cbc,
+        ] {
+            for key_transport in [
+                KeyTransportAlgorithm::RsaOaepMgf1p,
+                KeyTransportAlgorithm::RsaOaep,
+            ] {
+                let encryption = AssertionEncryption {
+                    certificate_der: &certificate_der,
+                    data_algorithm,
+                    key_transport,
+                };
+                let xml = encrypt_assertion(ASSERTION, &encryption).unwrap();
+                assert!(xml.contains(&format!(
+                    r#"<xenc:EncryptionMethod Algorithm="{}"/>"#,
+                    data_algorithm.uri()
+                )));
+                assert!(xml.contains(&format!(
+                    r#"<xenc:EncryptionMethod Algorithm="{}">"#,
+                    key_transport.uri()
+                )));
+                assert!(!xml.contains("https://idp.example.com"));
+                assert_eq!(
+                    decrypt_assertion(&xml, &key, data_algorithm, key_transport),
+                    ASSERTION
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn encrypt_assertion_requires_an_rsa_certificate() {
+        let (_, certificate_der) = sp_key(IdpKeyType::EcdsaP256);
+        let encryption = AssertionEncryption {
+            certificate_der: &certificate_der,
+            data_algorithm: DataEncryptionAlgorithm::Aes256Gcm,
+            key_transport: KeyTransportAlgorithm::RsaOaep,
+        };
+        assert!(encrypt_assertion(ASSERTION, &encryption).is_err());
+    }
+}
@@ -59,7 +59,8 @@ pub async fn index() -> impl Responder {
             <p>This is a demonstration Identity Provider (IdP) for SAML authentication.</p>
             <div class="links">
                 <a href="/metadata">View IdP Metadata</a>
-                <a href="/idp-init?user_id=testuser">Initiate SSO (Test User)</a>
+                <a href="/idp-init">Initiate SSO</a>
+                <a href="/slo">Sign Out</a>
                 <a href="/certificate/pem">Download Certificate (PEM)</a>
                 <a href="/certificate/der">Download Certificate (DER)</a>
             </div>
@@ -0,0 +1,164 @@
+use actix_web::http::StatusCode;
+use actix_web::{HttpResponse, Responder, web};
+use log::{info, warn};
+
+use crate::handlers::binding::escape_html;
+use crate::handlers::response_builder::{ErrorStatus, STATUS_AUTHN_FAILED};
+use crate::handlers::sso::{issue_authn_response, send_error_response, with_session_cookie};
+use crate::models::request::LoginForm;
+use crate::models::session::AUTHN_CONTEXT_PASSWORD_PROTECTED_TRANSPORT;
+use crate::models::state::AppState;
+
+/// Handles a submitted login form, continuing the pending SSO request once the
+/// user's password has been verified
+pub async fn handle_login(
+    form: web::Form<LoginForm>,
+    state: web::Data<AppState>,
+) -> impl Responder {
+    if state.pending_requests.get(&form.request_id).is_none() {
+        return request_expired(&form.request_id);
+    }
+
+    if form.cancel.is_some() {
+        info!("User cancelled sign-in for request {}", form.request_id);
+        let Some(pending) = state.pending_requests.take(&form.request_id) else {
+            return request_expired(&form.request_id);
+        };
+        return send_error_response(
+            &state,
+            &pending.sp_entity_id,
+            &pending.acs_url,
+            pending.in_response_to.clone(),
+            &pending.relay_state,
+            &ErrorStatus::responder("The user cancelled signing in")
+                .with_second_level_code(STATUS_AUTHN_FAILED),
+        );
+    }
+
+    info!("Handling login for user: {}", form.username);
+
+    let user = state
+        .user_database
+        .authenticate(&form.username, &form.password);
+    let Some(user) = user else {
+        warn!("Failed login attempt for user: {}", form.username);
+        return login_form(&form.request_id, Some("Invalid username or password"));
+    };
+
+    info!("User {} authenticated with password", user.user_id);
+    // Only one submission may consume the request, even if several race past
+    // the check above
+    let Some(pending) = state.pending_requests.take(&form.request_id) else {
+        return request_expired(&form.request_id);
+    };
+    let session = state
+        .sessions
+        .create(&user.user_id, AUTHN_CONTEXT_PASSWORD_PROTECTED_TRANSPORT);
+    let response = issue_authn_response(&state, user, &session, &pending);
+    with_session_cookie(response, &state, &session)
+}
+
+fn request_expired(request_id: &str) -> HttpResponse {
+    warn!("Login for unknown or expired request: {}", request_id);
+    HttpResponse::BadRequest()
+        .body("Your sign-in request has expired. Please return to the application and try again.")
+}
+
+/// Renders the login page for a pending SSO request
+pub fn login_form(request_id: &str, error: Option<&str>) -> HttpResponse {
+    let error_html = error
+        .map(|message| format!(r#"<p class="error">{}</p>"#, escape_html(message)))
+        .unwrap_or_default();
+
+    let html = format!(
+        r#"
+    <!DOCTYPE html>
+    <html lang="en">
+    <head>
+        <meta charset="UTF-8">
+        <meta name="viewport" content="width=device-width, initial-scale=1.0">
+        <title>Sign in - SAML Demo IdP</title>
+        <style>
+            body {{
+                font-family: Arial, sans-serif;
+                margin: 0;
+                padding: 0;
+                display: flex;
+                justify-content: center;
+                align-items: center;
+                min-height: 100vh;
+                background-color: #f5f5f5;
+            }}
+            .container {{
+                background-color: white;
+                border-radius: 8px;
+                padding: 40px;
+                box-shadow: 0 4px 6px rgba(0, 0, 0, 0.1);
+                width: 320px;
+            }}
+            h1 {{
+                color: #333;
+                margin-bottom: 20px;
+                text-align: center;
+            }}
+            label {{
+                display: block;
+                color: #666;
+                margin-bottom: 5px;
+            }}
+            input[type="text"], input[type="password"] {{
+                width: 100%;
+                padding: 8px;
+                margin-bottom: 15px;
+                box-sizing: border-box;
+            }}
+            button {{
+                width: 100%;
+                padding: 10px;
+                background-color: #0066cc;
+                color: white;
+                border: none;
+                border-radius: 4px;
+                cursor: pointer;
+            }}
+            button.secondary {{
+                margin-top: 10px;
+                background-color: white;
+                color: #0066cc;
+                border: 1px solid #0066cc;
+            }}
+            .error {{
+                color: #cc0000;
+            }}
+        </style>
+    </head>
+    <body>
+        <div class="container">
+            <h1>Sign in</h1>
+            {}
+            <form method="post" action="/login">
+                <input type="hidden" name="request_id" value="{}" />
+                <label for="username">Username</label>
+                <input type="text" id="username" name="username" autocomplete="username" autofocus required />
+                <label for="password">Password</label>
+                <input type="password" id="password" name="password" autocomplete="current-password" required />
+                <button type="submit">Sign in</button>
+                <button type="submit" name="cancel" value="true" class="secondary" formnovalidate>Cancel</button>
+            </form>
+        </div>
+    </body>
+    </html>
+    "#,
+        error_html,
+        escape_html(request_id)
+    );
+
+    let status = if error.is_some() {
+        StatusCode::UNAUTHORIZED
+    } else {
+        StatusCode::OK
+    };
+    HttpResponse::build(status)
+        .content_type("text/html; charset=utf-8")
+        .body(html)
+}
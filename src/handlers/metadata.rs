@@ -1,35 +1,66 @@
 use actix_web::{HttpResponse, Responder, web};
 use base64::Engine as _;
 use base64::engine::general_purpose;
+use chrono::Utc;
 use log::{debug, error, info};
 use pem;
+use samael::attribute::Attribute;
+use samael::crypto;
 use samael::key_info::{KeyInfo, X509Data};
-use samael::metadata::{Endpoint, EntityDescriptor, IdpSsoDescriptor, KeyDescriptor};
+use samael::metadata::{
+    ContactPerson, Endpoint, EntityDescriptor, IdpSsoDescriptor, KeyDescriptor, LocalizedName,
+    LocalizedUri, Organization,
+};
 use samael::metadata::{HTTP_POST_BINDING, HTTP_REDIRECT_BINDING};
 use samael::traits::ToXml;
+use std::sync::Arc;
 
+use crate::handlers::response_builder::{signature_template, strip_xml_declaration};
+use crate::key_rotation::SigningKey;
+use crate::models::idp_metadata::{IdpMetadataConfig, xml_duration};
+use crate::models::service_provider::{
+    AttributeRequest, DigestAlgorithm, ServiceProvider, SignatureAlgorithm, SigningAlgorithms,
+};
 use crate::models::state::AppState;
 
+const ALGORITHM_SUPPORT_NAMESPACE: &str = "urn:oasis:names:tc:SAML:metadata:algsupport";
+
 pub async fn metadata(state: web::Data<AppState>) -> impl Responder {
     info!("Serving IdP metadata");
     debug!("Generating metadata for entity ID: {}", state.idp_entity_id);
-    let cert_b64 = general_purpose::STANDARD.encode(&state.cert_der);
-
-    let key_descriptor = KeyDescriptor {
-        key_use: Some("signing".to_string()),
-        key_info: KeyInfo {
-            id: None,
-            x509_data: Some(X509Data {
-                certificates: vec![cert_b64],
-            }),
-        },
-        encryption_methods: None,
-    };
+
+    // During a key rotation the staged or previous certificate is published
+    // alongside the active one. Intermediate certificates follow the IdP's own
+    let key_descriptors = state
+        .signing_keys
+        .published_certificates()
+        .iter()
+        .map(|cert_chain| KeyDescriptor {
+            key_use: Some("signing".to_string()),
+            key_info: KeyInfo {
+                id: None,
+                x509_data: Some(X509Data {
+                    certificates: cert_chain
+                        .iter()
+                        .map(|cert_der| general_purpose::STANDARD.encode(cert_der))
+                        .collect(),
+                }),
+            },
+            encryption_methods: None,
+        })
+        .collect();
     let sso_service_endpoint = format!("{}/sso", state.idp_entity_id);
+    let slo_service_endpoint = format!("{}/slo", state.idp_entity_id);
     let idp_descriptor = IdpSsoDescriptor {
         protocol_support_enumeration: Some("urn:oasis:names:tc:SAML:2.0:protocol".to_string()),
-        key_descriptors: vec![key_descriptor],
-        want_authn_requests_signed: Some(false),
+        key_descriptors,
+        want_authn_requests_signed: Some(
+            state
+                .service_providers
+                .all()
+                .iter()
+                .any(|sp| sp.authn_requests_signed),
+        ),
         single_sign_on_services: vec![
             Endpoint {
                 binding: HTTP_POST_BINDING.to_string(),
@@ -42,30 +73,56 @@ pub async fn metadata(state: web::Data<AppState>) -> impl Responder {
                 response_location: None,
             },
         ],
-        id: Some("idstring".to_string()),
+        id: Some(crypto::gen_saml_response_id()),
         valid_until: None,
         cache_duration: None,
         error_url: None,
         signature: None,
-        organization: None,
-        contact_people: vec![],
+        organization: organization(&state.metadata),
+        contact_people: contact_people(&state.metadata),
+        // The HTTP-Artifact binding is not supported
         artifact_resolution_service: vec![],
         manage_name_id_services: vec![],
         name_id_mapping_services: vec![],
         assertion_id_request_services: vec![],
         attribute_profiles: vec![],
-        attributes: vec![],
-        single_logout_services: vec![],
-        name_id_formats: vec![],
+        attributes: released_attributes(
+            &state.user_database.attribute_names(),
+            state.service_providers.all(),
+        ),
+        single_logout_services: vec![
+            Endpoint {
+                binding: HTTP_POST_BINDING.to_string(),
+                location: slo_service_endpoint.clone(),
+                response_location: None,
+            },
+            Endpoint {
+                binding: HTTP_REDIRECT_BINDING.to_string(),
+                location: slo_service_endpoint,
+                response_location: None,
+            },
+        ],
+        name_id_formats: state.metadata.name_id_formats.clone(),
     };
 
+    let metadata_id = crypto::gen_saml_response_id();
     let entity_descriptor = EntityDescriptor {
         entity_id: Some(state.idp_entity_id.clone()),
+        id: Some(metadata_id.clone()),
+        valid_until: state
+            .metadata
+            .validity
+            .map(|validity| Utc::now() + validity),
+        cache_duration: state.metadata.cache_duration.map(xml_duration),
         idp_sso_descriptors: Some(vec![idp_descriptor]),
         ..EntityDescriptor::default()
     };
 
-    let xml = match entity_descriptor.to_string() {
+    let xml = match entity_descriptor
+        .to_string()
+        .and_then(|xml| add_algorithm_support(&state, xml))
+        .and_then(|xml| sign_metadata(&state, &metadata_id, xml))
+    {
         Ok(xml_str) => {
             debug!("Successfully generated metadata XML");
             xml_str
@@ -77,29 +134,204 @@ pub async fn metadata(state: web::Data<AppState>) -> impl Responder {
     };
 
     info!("Returning metadata XML");
-    HttpResponse::Ok().content_type("application/xml").body(xml)
+    let mut response = HttpResponse::Ok();
+    response.content_type("application/xml");
+    if let Some(cache_duration) = state.metadata.cache_duration {
+        response.append_header((
+            "Cache-Control",
+            format!("max-age={}", cache_duration.num_seconds()),
+        ));
+    }
+    response.body(xml)
+}
+
+// The attributes released to at least one SP, under the names and formats
+// they are released with and without values. Attributes no SP's policy allows
+// are left out.
+fn released_attributes(
+    sources: &[String],
+    mut service_providers: Vec<Arc<ServiceProvider>>,
+) -> Vec<Attribute> {
+    service_providers.sort_by(|a, b| a.entity_id.cmp(&b.entity_id));
+    let request = AttributeRequest::default();
+    let mut attributes: Vec<Attribute> = Vec::new();
+    for sp in &service_providers {
+        for source in sources {
+            let name = sp.attribute_name(source);
+            if !sp.releases_attribute(source, &name.name, &request) {
+                continue;
+            }
+            let name_format = name.name_format.uri();
+            let published = attributes.iter().any(|attribute| {
+                attribute.name.as_deref() == Some(name.name.as_str())
+                    && attribute.name_format.as_deref() == Some(name_format)
+            });
+            if !published {
+                attributes.push(Attribute {
+                    friendly_name: name.friendly_name,
+                    name: Some(name.name),
+                    name_format: Some(name_format.to_string()),
+                    values: vec![],
+                });
+            }
+        }
+    }
+    attributes
+}
+
+fn organization(config: &IdpMetadataConfig) -> Option<Organization> {
+    config
+        .organization
+        .as_ref()
+        .map(|organization| Organization {
+            organization_names: Some(vec![LocalizedName {
+                lang: Some("en".to_string()),
+                value: organization.name.clone(),
+            }]),
+            organization_display_names: Some(vec![LocalizedName {
+                lang: Some("en".to_string()),
+                value: organization.display_name.clone(),
+            }]),
+            organization_urls: Some(vec![LocalizedUri {
+                lang: Some("en".to_string()),
+                value: organization.url.clone(),
+            }]),
+        })
+}
+
+fn contact_people(config: &IdpMetadataConfig) -> Vec<ContactPerson> {
+    config
+        .contacts
+        .iter()
+        .map(|contact| ContactPerson {
+            contact_type: Some(contact.contact_type.as_str().to_string()),
+            company: config
+                .organization
+                .as_ref()
+                .map(|organization| organization.display_name.clone()),
+            given_name: contact.name.clone(),
+            sur_name: None,
+            email_addresses: Some(vec![format!("mailto:{}", contact.email)]),
+            telephone_numbers: None,
+        })
+        .collect()
+}
+
+// Adds an enveloped signature over the whole EntityDescriptor when metadata
+// signing is enabled, using the dedicated metadata key if one is configured
+fn sign_metadata(
+    state: &AppState,
+    metadata_id: &str,
+    xml: String,
+) -> Result<String, Box<dyn std::error::Error>> {
+    if !state.metadata.sign {
+        return Ok(xml);
+    }
+    let key = state
+        .metadata
+        .signing_key
+        .clone()
+        .unwrap_or_else(|| state.signing_keys.active());
+    sign_entity_descriptor(&key, metadata_id, xml)
+}
+
+// Signs the serialized EntityDescriptor `metadata_id` with `key`
+fn sign_entity_descriptor(
+    key: &SigningKey,
+    metadata_id: &str,
+    mut xml: String,
+) -> Result<String, Box<dyn std::error::Error>> {
+    let algorithms = SigningAlgorithms {
+        signature: SignatureAlgorithm::default_for_key(key.key_type()),
+        digest: DigestAlgorithm::default(),
+    };
+    let template = signature_template(metadata_id, &key.cert_der, algorithms).to_string()?;
+
+    // The signature must be the first child of the EntityDescriptor, before
+    // the Extensions
+    insert_first_child(&mut xml, strip_xml_declaration(&template))?;
+    let signed_xml = key.signer.sign_xml(&xml)?;
+    debug!("Signed the metadata");
+    Ok(signed_xml)
+}
+
+// Advertises the digest and signature algorithms used for the registered SPs
+// as `alg:DigestMethod`/`alg:SigningMethod` extensions. samael has no schema
+// for metadata extensions, so they are added to the serialized XML.
+fn add_algorithm_support(
+    state: &AppState,
+    mut xml: String,
+) -> Result<String, Box<dyn std::error::Error>> {
+    let mut digest_methods = Vec::new();
+    let mut signing_methods = Vec::new();
+    let key = state.signing_keys.active();
+    for sp in state.service_providers.all() {
+        let algorithms = key.signing_algorithms(&sp);
+        if !digest_methods.contains(&algorithms.digest.uri()) {
+            digest_methods.push(algorithms.digest.uri());
+        }
+        if !signing_methods.contains(&algorithms.signature.uri()) {
+            signing_methods.push(algorithms.signature.uri());
+        }
+    }
+
+    let mut extensions = format!(
+        r#"<Extensions xmlns="urn:oasis:names:tc:SAML:2.0:metadata" xmlns:alg="{}">"#,
+        ALGORITHM_SUPPORT_NAMESPACE
+    );
+    for algorithm in digest_methods {
+        extensions.push_str(&format!(r#"<alg:DigestMethod Algorithm="{}"/>"#, algorithm));
+    }
+    for algorithm in signing_methods {
+        extensions.push_str(&format!(
+            r#"<alg:SigningMethod Algorithm="{}"/>"#,
+            algorithm
+        ));
+    }
+    extensions.push_str("</Extensions>");
+
+    // Extensions must be the first child of the EntityDescriptor
+    insert_first_child(&mut xml, &extensions)?;
+    Ok(xml)
+}
+
+// Inserts `fragment` right after the start tag of the root element
+fn insert_first_child(xml: &mut String, fragment: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let root_start = xml
+        .match_indices('<')
+        .find(|(index, _)| !xml[index + 1..].starts_with('?'))
+        .map(|(index, _)| index)
+        .ok_or("Serialized metadata has no root element")?;
+    let root_tag_end = xml[root_start..]
+        .find('>')
+        .map(|end| root_start + end + 1)
+        .ok_or("Serialized metadata has a malformed root element")?;
+    xml.insert_str(root_tag_end, fragment);
+    Ok(())
 }
 
 /// Provides the IdP signing certificate in DER format
 pub async fn certificate_der(state: web::Data<AppState>) -> impl Responder {
     info!("Serving IdP certificate in DER format");
-    debug!("Certificate size: {} bytes", state.cert_der.len());
+    let cert_der = state.signing_keys.active().cert_der;
+    debug!("Certificate size: {} bytes", cert_der.len());
     HttpResponse::Ok()
         .content_type("application/x-x509-ca-cert")
         .append_header((
             "Content-Disposition",
             "attachment; filename=\"idp-certificate.der\"",
         ))
-        .body(state.cert_der.clone())
+        .body(cert_der)
 }
 
 /// Provides the IdP signing certificate in PEM format
 pub async fn certificate_pem(state: web::Data<AppState>) -> impl Responder {
     info!("Serving IdP certificate in PEM format");
-    debug!("Certificate size: {} bytes", state.cert_der.len());
+    let cert_der = state.signing_keys.active().cert_der;
+    debug!("Certificate size: {} bytes", cert_der.len());
     // Convert DER to PEM
     // Create a PEM with "CERTIFICATE" tag and the certificate data
-    let pem_string = pem::encode(&pem::Pem::new("CERTIFICATE", state.cert_der.clone()));
+    let pem_string = pem::encode(&pem::Pem::new("CERTIFICATE", cert_der));
 
     HttpResponse::Ok()
         .content_type("application/x-pem-file")
@@ -109,3 +341,113 @@ pub async fn certificate_pem(state: web::Data<AppState>) -> impl Responder {
         ))
         .body(pem_string)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cert_util::{CertificateProfile, IdpKeyType, generate_identity_provider};
+    use crate::models::attribute_mapping::AttributeNameFormat;
+    use crate::signing::FileKey;
+    use chrono::Duration;
+
+    #[test]
+    fn signed_metadata_publishes_validity_and_cache_duration() {
+        let (idp, mut cert_chain) =
+            generate_identity_provider(IdpKeyType::EcdsaP256, &CertificateProfile::default())
+                .unwrap();
+        let key = SigningKey {
+            signer: Arc::new(FileKey::new(&idp).unwrap()),
+            cert_der: cert_chain.remove(0),
+            intermediates_der: cert_chain,
+            expires_at: Utc::now() + Duration::days(1),
+        };
+
+        let valid_until = Utc::now() + Duration::days(7);
+        let entity_descriptor = EntityDescriptor {
+            entity_id: Some("https://idp.example.com".to_string()),
+            id: Some("_metadata".to_string()),
+            valid_until: Some(valid_until),
+            cache_duration: Some(xml_duration(Duration::hours(6))),
+            ..EntityDescriptor::default()
+        };
+        let xml = sign_entity_descriptor(&key, "_metadata", entity_descriptor.to_string().unwrap())
+            .unwrap();
+        crypto::verify_signed_xml(xml.as_bytes(), &key.cert_der, Some("ID")).unwrap();
+
+        let signed: EntityDescriptor = xml.parse().unwrap();
+        assert_eq!(
+            signed.valid_until.map(|time| time.timestamp()),
+            Some(valid_until.timestamp())
+        );
+        assert_eq!(signed.cache_duration.as_deref(), Some("PT21600S"));
+        let signature = signed.signature.unwrap();
+        assert_eq!(
+            signature.signed_info.signature_method.algorithm,
+            SignatureAlgorithm::EcdsaSha256.uri()
+        );
+        assert_eq!(
+            signature.signed_info.reference[0].uri.as_deref(),
+            Some("#_metadata")
+        );
+    }
+
+    fn service_provider(yaml: &str) -> Arc<ServiceProvider> {
+        Arc::new(serde_yaml::from_str(yaml).unwrap())
+    }
+
+    #[test]
+    fn metadata_attributes_follow_the_sp_release_policies() {
+        let sources = ["firstName", "lastName", "email", "permissions"].map(str::to_string);
+        let service_providers = vec![
+            service_provider(
+                "entity_id: https://b.example.com
+acs_endpoints: []
+denied_attributes: [permissions]
+",
+            ),
+            service_provider(
+                "entity_id: https://a.example.com
+acs_endpoints: []
+attribute_profile: x500
+released_attributes: [firstName, "urn:oid:0.9.2342.19200300.100.1.3"]
+",
+            ),
+            service_provider(
+                "entity_id: https://c.example.com
+acs_endpoints: []
+released_attributes: [email]
+",
+            ),
+        ];
+
+        let attributes = released_attributes(&sources, service_providers);
+        let published: Vec<(&str, &str, Option<&str>)> = attributes
+            .iter()
+            .map(|attribute| {
+                (
+                    attribute.name.as_deref().unwrap(),
+                    attribute.name_format.as_deref().unwrap(),
+                    attribute.friendly_name.as_deref(),
+                )
+            })
+            .collect();
+        let uri = AttributeNameFormat::Uri.uri();
+        assert_eq!(
+            published,
+            [
+                ("urn:oid:2.5.4.42", uri, Some("givenName")),
+                ("urn:oid:0.9.2342.19200300.100.1.3", uri, Some("mail")),
+                ("firstName", uri, None),
+                ("lastName", uri, None),
+                ("email", uri, None),
+            ]
+        );
+        assert!(
+            attributes
+                .iter()
+                .all(|attribute| attribute.values.is_empty())
+        );
+
+        assert!(released_attributes(&sources, vec![]).is_empty());
+    }
+}
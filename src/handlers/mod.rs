@@ -1,4 +1,10 @@
+pub mod admin;
+pub mod binding;
+pub mod encryption;
 pub mod landing;
+pub mod login;
 pub mod metadata;
 pub mod response_builder;
+pub mod slo;
 pub mod sso;
+pub mod status;
@@ -1,51 +1,128 @@
-use chrono::Utc;
+use chrono::{DateTime, Duration, Utc};
+use libxml::parser::Parser;
+use libxml::tree::Node;
 use log::debug;
 use samael::attribute::{Attribute, AttributeValue};
 use samael::crypto;
-use samael::idp::IdentityProvider;
-use samael::idp::response_builder::ResponseAttribute;
 use samael::schema::{
     Assertion, AttributeStatement, AudienceRestriction, AuthnContext, AuthnContextClassRef,
-    AuthnStatement, Conditions, Issuer, Response, Status, StatusCode, Subject, SubjectConfirmation,
+    AuthnStatement, Conditions, Issuer, LogoutRequest, LogoutResponse, OneTimeUse, Response,
+    SessionIndex, Status, StatusCode, StatusMessage, Subject, SubjectConfirmation,
     SubjectConfirmationData, SubjectNameID,
 };
 use samael::signature::Signature;
 use samael::traits::ToXml;
-use std::str::FromStr;
 
-fn build_conditions(audience: &str) -> Conditions {
+use crate::handlers::encryption::{AssertionEncryption, encrypt_assertion};
+use crate::models::attribute_mapping::AttributeName;
+use crate::models::service_provider::{SigningAlgorithms, SigningMode};
+use crate::models::session::{IdpSession, SessionParticipant};
+use crate::signing::SigningBackend;
+
+const PROTOCOL_NAMESPACE: &str = "urn:oasis:names:tc:SAML:2.0:protocol";
+
+pub const STATUS_SUCCESS: &str = "urn:oasis:names:tc:SAML:2.0:status:Success";
+pub const STATUS_REQUESTER: &str = "urn:oasis:names:tc:SAML:2.0:status:Requester";
+pub const STATUS_RESPONDER: &str = "urn:oasis:names:tc:SAML:2.0:status:Responder";
+pub const STATUS_AUTHN_FAILED: &str = "urn:oasis:names:tc:SAML:2.0:status:AuthnFailed";
+pub const STATUS_INVALID_NAME_ID_POLICY: &str =
+    "urn:oasis:names:tc:SAML:2.0:status:InvalidNameIDPolicy";
+pub const STATUS_NO_PASSIVE: &str = "urn:oasis:names:tc:SAML:2.0:status:NoPassive";
+pub const STATUS_REQUEST_DENIED: &str = "urn:oasis:names:tc:SAML:2.0:status:RequestDenied";
+pub const STATUS_UNKNOWN_PRINCIPAL: &str = "urn:oasis:names:tc:SAML:2.0:status:UnknownPrincipal";
+pub const STATUS_PARTIAL_LOGOUT: &str = "urn:oasis:names:tc:SAML:2.0:status:PartialLogout";
+
+/// Status reported in a non-success Response
+#[derive(Debug, Clone)]
+pub struct ErrorStatus {
+    /// Top-level `StatusCode`, either `Requester` or `Responder`
+    pub code: &'static str,
+    /// Optional second-level `StatusCode` giving the specific failure
+    pub second_level_code: Option<&'static str>,
+    /// Details of the failure for the IdP's log. They are not sent to the SP,
+    /// which receives the generic `status_message` instead.
+    pub message: String,
+}
+
+impl ErrorStatus {
+    /// The request was invalid or was refused
+    pub fn requester(message: impl Into<String>) -> Self {
+        Self {
+            code: STATUS_REQUESTER,
+            second_level_code: None,
+            message: message.into(),
+        }
+    }
+
+    /// The IdP was unable to process a valid request
+    pub fn responder(message: impl Into<String>) -> Self {
+        Self {
+            code: STATUS_RESPONDER,
+            second_level_code: None,
+            message: message.into(),
+        }
+    }
+
+    pub fn with_second_level_code(mut self, code: &'static str) -> Self {
+        self.second_level_code = Some(code);
+        self
+    }
+
+    /// The `StatusMessage` sent to the SP, which only depends on the status codes
+    pub fn status_message(&self) -> &'static str {
+        match (self.second_level_code, self.code) {
+            (Some(STATUS_AUTHN_FAILED), _) => "The user could not be authenticated",
+            (Some(STATUS_INVALID_NAME_ID_POLICY), _) => {
+                "The requested NameID policy cannot be satisfied"
+            }
+            (Some(STATUS_NO_PASSIVE), _) => "The user cannot be authenticated passively",
+            (Some(STATUS_REQUEST_DENIED), _) => "The request was denied",
+            (Some(STATUS_UNKNOWN_PRINCIPAL), _) => "The user is not known to the identity provider",
+            (_, STATUS_REQUESTER) => "The request could not be processed",
+            _ => "The identity provider could not process the request",
+        }
+    }
+}
+
+fn build_conditions(fields: &AuthnResponseFields, issue_instant: DateTime<Utc>) -> Conditions {
     Conditions {
-        not_before: None,
-        not_on_or_after: None,
+        not_before: Some(issue_instant - fields.clock_skew),
+        not_on_or_after: Some(issue_instant + fields.assertion_lifetime),
         audience_restrictions: Some(vec![AudienceRestriction {
-            audience: vec![audience.to_string()],
+            audience: vec![fields.audience.to_string()],
         }]),
-        one_time_use: None,
+        one_time_use: fields.one_time_use.then_some(OneTimeUse {}),
         proxy_restriction: None,
     }
 }
 
-fn build_authn_statement(class: &str) -> AuthnStatement {
+fn build_authn_statement(session: &IdpSession) -> AuthnStatement {
     AuthnStatement {
-        authn_instant: Some(Utc::now()),
-        session_index: None,
-        session_not_on_or_after: None,
+        authn_instant: Some(session.authn_instant),
+        session_index: Some(session.session_index.clone()),
+        session_not_on_or_after: Some(session.not_on_or_after),
         subject_locality: None,
         authn_context: Some(AuthnContext {
             value: Some(AuthnContextClassRef {
-                value: Some(class.to_string()),
+                value: Some(session.authn_context_class.clone()),
             }),
         }),
     }
 }
 
-fn build_attributes(formats_names_values: &[ResponseAttribute]) -> Vec<Attribute> {
-    formats_names_values
+/// A user attribute value, under the name it is released to the SP with
+pub struct ReleasedAttribute<'a> {
+    pub name: AttributeName,
+    pub value: &'a str,
+}
+
+fn build_attributes(attributes: &[ReleasedAttribute]) -> Vec<Attribute> {
+    attributes
         .iter()
         .map(|attr| Attribute {
-            friendly_name: None,
-            name: Some(attr.required_attribute.name.clone()),
-            name_format: attr.required_attribute.format.clone(),
+            friendly_name: attr.name.friendly_name.clone(),
+            name: Some(attr.name.name.clone()),
+            name_format: Some(attr.name.name_format.uri().to_string()),
             values: vec![AttributeValue {
                 attribute_type: Some("xs:string".to_string()),
                 value: Some(attr.value.to_string()),
@@ -54,134 +131,562 @@ fn build_attributes(formats_names_values: &[ResponseAttribute]) -> Vec<Attribute
         .collect()
 }
 
+/// Fields used to build a signed authentication Response
+pub struct AuthnResponseFields<'a> {
+    pub idp_x509_cert_der: &'a [u8],
+    pub subject_name_id: &'a str,
+    pub name_id_format: &'a str,
+    pub audience: &'a str,
+    pub acs_url: &'a str,
+    pub issuer: &'a str,
+    pub in_response_to_id: Option<String>,
+    pub attributes: &'a [ReleasedAttribute<'a>],
+    /// IdP session the user authenticated in
+    pub session: &'a IdpSession,
+    /// How long the assertion is valid for after it is issued
+    pub assertion_lifetime: Duration,
+    /// Allowance for the SP's clock running behind, subtracted from `NotBefore`
+    pub clock_skew: Duration,
+    pub one_time_use: bool,
+    /// Whether the Response, the Assertion or both are signed
+    pub signing_mode: SigningMode,
+    /// Signature and digest algorithms chosen for the SP
+    pub algorithms: SigningAlgorithms,
+    /// Set when the assertion is sent as an `EncryptedAssertion`
+    pub encryption: Option<AssertionEncryption<'a>>,
+}
+
+/// A Response serialized to XML with the signatures required by the SP, ready
+/// to be delivered
+pub struct SignedResponse {
+    pub id: String,
+    pub xml: String,
+}
+
+/// Builds an enveloped signature template for the element `id`, using the
+/// signature and digest algorithms chosen for the SP
+pub fn signature_template(
+    id: &str,
+    idp_x509_cert_der: &[u8],
+    algorithms: SigningAlgorithms,
+) -> Signature {
+    let mut signature = Signature::template(id, idp_x509_cert_der);
+    signature.signed_info.signature_method.algorithm = algorithms.signature.uri().to_string();
+    for reference in &mut signature.signed_info.reference {
+        reference.digest_method.algorithm = algorithms.digest.uri().to_string();
+    }
+    signature
+}
+
 fn build_assertion(
-    name_id: &str,
-    request_id: Option<String>,
+    fields: &AuthnResponseFields,
     issuer: Issuer,
-    recipient: &str,
-    audience: &str,
-    attributes: &[ResponseAttribute],
+    issue_instant: DateTime<Utc>,
 ) -> Assertion {
     let assertion_id = crypto::gen_saml_assertion_id();
 
     Assertion {
         id: assertion_id,
-        issue_instant: Utc::now(),
+        issue_instant,
         version: "2.0".to_string(),
         issuer,
         signature: None,
         subject: Some(Subject {
             name_id: Some(SubjectNameID {
-                format: Some("urn:oasis:names:tc:SAML:2.0:nameid-format:unspecified".to_string()),
-                value: name_id.to_owned(),
+                format: Some(fields.name_id_format.to_string()),
+                value: fields.subject_name_id.to_owned(),
             }),
             subject_confirmations: Some(vec![SubjectConfirmation {
                 method: Some("urn:oasis:names:tc:SAML:2.0:cm:bearer".to_string()),
                 name_id: None,
                 subject_confirmation_data: Some(SubjectConfirmationData {
+                    // Bearer confirmations must not carry NotBefore
                     not_before: None,
-                    not_on_or_after: None,
-                    recipient: Some(recipient.to_owned()),
-                    in_response_to: request_id,
+                    not_on_or_after: Some(issue_instant + fields.assertion_lifetime),
+                    recipient: Some(fields.acs_url.to_owned()),
+                    in_response_to: fields.in_response_to_id.clone(),
                     address: None,
                     content: None,
                 }),
             }]),
         }),
-        conditions: Some(build_conditions(audience)),
-        authn_statements: Some(vec![build_authn_statement(
-            "urn:oasis:names:tc:SAML:2.0:ac:classes:unspecified",
-        )]),
+        conditions: Some(build_conditions(fields, issue_instant)),
+        authn_statements: Some(vec![build_authn_statement(fields.session)]),
         attribute_statements: Some(vec![AttributeStatement {
-            attributes: build_attributes(attributes),
+            attributes: build_attributes(fields.attributes),
         }]),
     }
 }
 
-fn build_response(
-    name_id: &str,
-    issuer: &str,
-    request_id: Option<String>,
-    attributes: &[ResponseAttribute],
-    destination: &str,
-    audience: &str,
-    x509_cert: &[u8],
-) -> Response {
+pub fn build_response_template(fields: &AuthnResponseFields) -> Response {
     let issuer = Issuer {
-        value: Some(issuer.to_string()),
+        value: Some(fields.issuer.to_string()),
         ..Default::default()
     };
 
     let response_id = crypto::gen_saml_response_id();
+    // The response, assertion and validity window share a single timestamp
+    let issue_instant = Utc::now();
 
     Response {
         id: response_id.clone(),
-        // TODO: Figure out if we can do this without cloning
-        in_response_to: request_id.clone(),
+        in_response_to: fields.in_response_to_id.clone(),
         version: "2.0".to_string(),
-        issue_instant: Utc::now(),
-        destination: Some(destination.to_string()),
+        issue_instant,
+        destination: Some(fields.acs_url.to_string()),
         consent: None,
         issuer: Some(issuer.clone()),
-        signature: Some(Signature::template(&response_id, x509_cert)),
+        signature: fields
+            .signing_mode
+            .signs_response()
+            .then(|| signature_template(&response_id, fields.idp_x509_cert_der, fields.algorithms)),
         status: Some(Status {
             status_code: StatusCode {
-                value: Some("urn:oasis:names:tc:SAML:2.0:status:Success".to_string()),
+                value: Some(STATUS_SUCCESS.to_string()),
             },
             status_message: None,
             status_detail: None,
         }),
         encrypted_assertion: None,
-        assertion: Some(build_assertion(
-            name_id,
-            request_id,
-            issuer,
-            destination,
-            audience,
-            attributes,
-        )),
+        assertion: Some(build_assertion(fields, issuer, issue_instant)),
     }
 }
 
-pub fn build_response_template(
-    cert_der: &[u8],
-    name_id: &str,
-    audience: &str,
-    issuer: &str,
-    acs_url: &str,
-    request_id: Option<String>,
-    attributes: &[ResponseAttribute],
-) -> Response {
-    build_response(
-        name_id, issuer, request_id, attributes, acs_url, audience, cert_der,
-    )
-}
+/// Builds an authentication Response, signing the Response, the Assertion or
+/// both as configured for the SP.
+///
+/// The assertion is signed on its own before being placed in the Response, so
+/// its signature stays valid when the SP extracts it, and before it is
+/// encrypted, so the SP can verify it after decryption.
 pub fn sign_authn_response(
-    idp: &IdentityProvider,
+    signer: &dyn SigningBackend,
+    fields: &AuthnResponseFields,
+) -> Result<SignedResponse, Box<dyn std::error::Error>> {
+    let mut response = build_response_template(fields);
+    let mut assertion = response
+        .assertion
+        .take()
+        .ok_or("Response template has no assertion")?;
+
+    let assertion_xml = if fields.signing_mode.signs_assertion() {
+        assertion.signature = Some(signature_template(
+            &assertion.id,
+            fields.idp_x509_cert_der,
+            fields.algorithms,
+        ));
+        let signed_xml = signer.sign_xml(assertion.to_string()?.as_str())?;
+        debug!("signed the assertion");
+        signed_xml
+    } else {
+        assertion.to_string()?
+    };
+    let assertion_xml = strip_xml_declaration(&assertion_xml);
+
+    // An encrypted assertion takes the place of the plaintext one
+    let assertion_xml = match &fields.encryption {
+        Some(encryption) => encrypt_assertion(assertion_xml, encryption)?,
+        None => assertion_xml.to_string(),
+    };
+
+    // The assertion is the last child of the Response
+    let mut response_xml = response.to_string()?;
+    let root_end = response_xml
+        .rfind("</")
+        .ok_or("Serialized Response has no closing tag")?;
+    response_xml.insert_str(root_end, &assertion_xml);
+
+    if fields.signing_mode.signs_response() {
+        sign_response_xml(signer, &response.id, &response_xml)
+    } else {
+        Ok(SignedResponse {
+            id: response.id,
+            xml: response_xml,
+        })
+    }
+}
+
+/// Builds and signs a non-success Response carrying no assertion, used to
+/// report a failed request back to the SP
+pub fn sign_error_response(
+    signer: &dyn SigningBackend,
     idp_x509_cert_der: &[u8],
-    subject_name_id: &str,
-    audience: &str,
-    acs_url: &str,
     issuer: &str,
+    destination: &str,
     in_response_to_id: Option<String>,
-    attributes: &[ResponseAttribute],
-) -> Result<Response, Box<dyn std::error::Error>> {
-    let response = build_response_template(
-        idp_x509_cert_der,
-        subject_name_id,
-        audience,
-        issuer,
-        acs_url,
-        in_response_to_id,
-        attributes,
-    );
-
-    let response_xml_unsigned = response.to_string()?;
-    let signed_xml = crypto::sign_xml(
-        response_xml_unsigned.as_str(),
-        idp.export_private_key_der()?.as_slice(),
-    )?;
+    status: &ErrorStatus,
+    algorithms: SigningAlgorithms,
+) -> Result<SignedResponse, Box<dyn std::error::Error>> {
+    let response_id = crypto::gen_saml_response_id();
+    let response = Response {
+        id: response_id.clone(),
+        in_response_to: in_response_to_id,
+        version: "2.0".to_string(),
+        issue_instant: Utc::now(),
+        destination: Some(destination.to_string()),
+        consent: None,
+        issuer: Some(Issuer {
+            value: Some(issuer.to_string()),
+            ..Default::default()
+        }),
+        signature: Some(signature_template(
+            &response_id,
+            idp_x509_cert_der,
+            algorithms,
+        )),
+        status: Some(Status {
+            status_code: StatusCode {
+                value: Some(status.code.to_string()),
+            },
+            status_message: Some(StatusMessage {
+                value: Some(status.status_message().to_string()),
+            }),
+            status_detail: None,
+        }),
+        encrypted_assertion: None,
+        assertion: None,
+    };
+
+    let mut response_xml_unsigned = response.to_string()?;
+    if let Some(second_level_code) = status.second_level_code {
+        response_xml_unsigned = nest_status_code(&response_xml_unsigned, second_level_code)?;
+    }
+    sign_response_xml(signer, &response.id, &response_xml_unsigned)
+}
+
+/// Builds a LogoutRequest asking an SP to end its session for `participant`.
+///
+/// `signature` is a template from [`signature_template`] for delivery over the
+/// HTTP-POST binding, and `None` for the HTTP-Redirect binding.
+pub fn build_logout_request(
+    id: &str,
+    issuer: &str,
+    destination: &str,
+    participant: &SessionParticipant,
+    session_index: &str,
+    signature: Option<Signature>,
+) -> LogoutRequest {
+    LogoutRequest {
+        id: Some(id.to_string()),
+        version: Some("2.0".to_string()),
+        issue_instant: Some(Utc::now()),
+        destination: Some(destination.to_string()),
+        issuer: Some(Issuer {
+            value: Some(issuer.to_string()),
+            ..Default::default()
+        }),
+        signature,
+        session_index: Some(SessionIndex {
+            value: Some(session_index.to_string()),
+        }),
+        name_id: Some(SubjectNameID {
+            format: Some(participant.name_id_format.clone()),
+            value: participant.name_id.clone(),
+        }),
+    }
+}
+
+/// Builds a LogoutResponse answering the LogoutRequest `in_response_to_id`.
+///
+/// `signature` is a template from [`signature_template`] for delivery over the
+/// HTTP-POST binding, and `None` for the HTTP-Redirect binding.
+pub fn build_logout_response(
+    id: &str,
+    issuer: &str,
+    destination: &str,
+    in_response_to_id: &str,
+    status_code: &str,
+    status_message: Option<&str>,
+    signature: Option<Signature>,
+) -> LogoutResponse {
+    LogoutResponse {
+        id: Some(id.to_string()),
+        in_response_to: Some(in_response_to_id.to_string()),
+        version: Some("2.0".to_string()),
+        issue_instant: Some(Utc::now()),
+        destination: Some(destination.to_string()),
+        consent: None,
+        issuer: Some(Issuer {
+            value: Some(issuer.to_string()),
+            ..Default::default()
+        }),
+        signature,
+        status: Some(Status {
+            status_code: StatusCode {
+                value: Some(status_code.to_string()),
+            },
+            status_message: status_message.map(|message| StatusMessage {
+                value: Some(message.to_string()),
+            }),
+            status_detail: None,
+        }),
+    }
+}
+
+// Signs a serialized Response containing a signature template with the IdP key
+fn sign_response_xml(
+    signer: &dyn SigningBackend,
+    response_id: &str,
+    response_xml_unsigned: &str,
+) -> Result<SignedResponse, Box<dyn std::error::Error>> {
+    let signed_xml = signer.sign_xml(response_xml_unsigned)?;
     debug!("signed the response");
-    let signed_response = samael::schema::Response::from_str(signed_xml.as_str())?;
-    Ok(signed_response)
+    Ok(SignedResponse {
+        id: response_id.to_string(),
+        xml: signed_xml,
+    })
+}
+
+// Removes a leading `<?xml ...?>` declaration so the document can be embedded
+// in another one
+pub fn strip_xml_declaration(xml: &str) -> &str {
+    let xml = xml.trim_start();
+    match xml.strip_prefix("<?xml") {
+        Some(rest) => rest
+            .find("?>")
+            .map_or(xml, |end| rest[end + 2..].trim_start()),
+        None => xml,
+    }
+}
+
+// Adds a second-level StatusCode inside the top-level one of a serialized
+// Response or LogoutResponse. The samael schema has no field for nested status
+// codes, so the element is added to the parsed document.
+pub fn nest_status_code(
+    xml: &str,
+    second_level_code: &str,
+) -> Result<String, Box<dyn std::error::Error>> {
+    let document = Parser::default().parse_string(xml)?;
+    let root = document
+        .get_root_element()
+        .ok_or("Serialized message has no root element")?;
+    let mut status_code = protocol_child(&root, "Status")
+        .and_then(|status| protocol_child(&status, "StatusCode"))
+        .ok_or("Serialized message has no top-level StatusCode")?;
+    let mut nested = status_code.new_child(status_code.get_namespace(), "StatusCode")?;
+    nested.set_attribute("Value", second_level_code)?;
+    Ok(document.to_string())
+}
+
+fn protocol_child(node: &Node, name: &str) -> Option<Node> {
+    node.get_child_elements().into_iter().find(|child| {
+        child.get_name() == name
+            && child
+                .get_namespace()
+                .is_some_and(|ns| ns.get_href() == PROTOCOL_NAMESPACE)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::service_provider::{DigestAlgorithm, SignatureAlgorithm};
+    use crate::models::session::SessionStore;
+    use crate::signing::FileKey;
+    use samael::idp::{CertificateParams, IdentityProvider, KeyType, Rsa};
+
+    const LOGOUT_RESPONSE: &str = r#"<samlp:LogoutResponse xmlns:samlp="urn:oasis:names:tc:SAML:2.0:protocol" ID="_1" Version="2.0"><samlp:Status><samlp:StatusCode Value="urn:oasis:names:tc:SAML:2.0:status:Success"/></samlp:Status></samlp:LogoutResponse>"#;
+
+    #[test]
+    fn nest_status_code_adds_second_level_code() {
+        let xml = nest_status_code(LOGOUT_RESPONSE, STATUS_UNKNOWN_PRINCIPAL).unwrap();
+        let document = Parser::default().parse_string(&xml).unwrap();
+        let root = document.get_root_element().unwrap();
+        let top = protocol_child(&root, "Status")
+            .and_then(|status| protocol_child(&status, "StatusCode"))
+            .unwrap();
+        assert_eq!(top.get_attribute("Value").as_deref(), Some(STATUS_SUCCESS));
+        let nested = protocol_child(&top, "StatusCode").unwrap();
+        assert_eq!(
+            nested.get_attribute("Value").as_deref(),
+            Some(STATUS_UNKNOWN_PRINCIPAL)
+        );
+    }
+
+    #[test]
+    fn nest_status_code_escapes_value() {
+        let xml = nest_status_code(LOGOUT_RESPONSE, "urn:x\"/><evil/>").unwrap();
+        assert!(!xml.contains("<evil/>"));
+    }
+
+    #[test]
+    fn nest_status_code_requires_status() {
+        let xml = r#"<samlp:LogoutResponse xmlns:samlp="urn:oasis:names:tc:SAML:2.0:protocol"/>"#;
+        assert!(nest_status_code(xml, STATUS_UNKNOWN_PRINCIPAL).is_err());
+    }
+
+    #[test]
+    fn status_message_hides_details() {
+        let status = ErrorStatus::requester("Signature verification failed: bad key")
+            .with_second_level_code(STATUS_REQUEST_DENIED);
+        assert_eq!(status.status_message(), "The request was denied");
+        assert_eq!(
+            ErrorStatus::responder("database down").status_message(),
+            "The identity provider could not process the request"
+        );
+    }
+
+    const PASSWORD_PROTECTED_TRANSPORT: &str =
+        "urn:oasis:names:tc:SAML:2.0:ac:classes:PasswordProtectedTransport";
+
+    fn session() -> IdpSession {
+        SessionStore::new(Duration::minutes(30), Duration::hours(8))
+            .create("alice", PASSWORD_PROTECTED_TRANSPORT)
+    }
+
+    fn fields(session: &IdpSession) -> AuthnResponseFields<'_> {
+        AuthnResponseFields {
+            idp_x509_cert_der: &[],
+            subject_name_id: "alice",
+            name_id_format: "urn:oasis:names:tc:SAML:1.1:nameid-format:unspecified",
+            audience: "https://sp.example.com",
+            acs_url: "https://sp.example.com/acs",
+            issuer: "https://idp.example.com",
+            in_response_to_id: Some("_request".to_string()),
+            attributes: &[],
+            session,
+            assertion_lifetime: Duration::seconds(300),
+            clock_skew: Duration::seconds(60),
+            one_time_use: false,
+            signing_mode: SigningMode::Response,
+            algorithms: SigningAlgorithms {
+                signature: SignatureAlgorithm::RsaSha256,
+                digest: DigestAlgorithm::Sha256,
+            },
+            encryption: None,
+        }
+    }
+
+    #[test]
+    fn build_conditions_applies_clock_skew_and_lifetime() {
+        let session = session();
+        let issue_instant = Utc::now();
+        let conditions = build_conditions(&fields(&session), issue_instant);
+        assert_eq!(
+            conditions.not_before,
+            Some(issue_instant - Duration::seconds(60))
+        );
+        assert_eq!(
+            conditions.not_on_or_after,
+            Some(issue_instant + Duration::seconds(300))
+        );
+        assert_eq!(
+            conditions.audience_restrictions.unwrap()[0].audience,
+            vec!["https://sp.example.com".to_string()]
+        );
+        assert!(conditions.one_time_use.is_none());
+    }
+
+    #[test]
+    fn build_conditions_adds_one_time_use_when_configured() {
+        let session = session();
+        let fields = AuthnResponseFields {
+            one_time_use: true,
+            ..fields(&session)
+        };
+        assert!(build_conditions(&fields, Utc::now()).one_time_use.is_some());
+    }
+
+    #[test]
+    fn build_authn_statement_publishes_the_session_window() {
+        let session = session();
+        let statement = build_authn_statement(&session);
+        assert_eq!(statement.authn_instant, Some(session.authn_instant));
+        assert_eq!(
+            statement.session_not_on_or_after,
+            Some(session.not_on_or_after)
+        );
+        assert_eq!(statement.session_index, Some(session.session_index.clone()));
+    }
+
+    #[test]
+    fn bearer_confirmation_expires_with_the_assertion() {
+        let session = session();
+        let response = build_response_template(&fields(&session));
+        let assertion = response.assertion.as_ref().unwrap();
+        let subject = assertion.subject.as_ref().unwrap();
+        let confirmation_data = subject.subject_confirmations.as_ref().unwrap()[0]
+            .subject_confirmation_data
+            .as_ref()
+            .unwrap();
+        assert!(confirmation_data.not_before.is_none());
+        assert_eq!(
+            confirmation_data.not_on_or_after,
+            Some(response.issue_instant + Duration::seconds(300))
+        );
+        assert_eq!(
+            assertion.conditions.as_ref().unwrap().not_on_or_after,
+            confirmation_data.not_on_or_after
+        );
+    }
+
+    #[test]
+    fn signing_mode_places_the_signatures() {
+        let idp = IdentityProvider::generate_new(KeyType::Rsa(Rsa::Rsa2048)).unwrap();
+        let cert_der = idp
+            .create_certificate(&CertificateParams {
+                common_name: "Test IdP",
+                issuer_name: "Test IdP",
+                days_until_expiration: 1,
+            })
+            .unwrap();
+        let signer = FileKey::new(&idp).unwrap();
+        let session = session();
+
+        for (signing_mode, response_signed, assertion_signed) in [
+            (SigningMode::Response, true, false),
+            (SigningMode::Assertion, false, true),
+            (SigningMode::Both, true, true),
+        ] {
+            let fields = AuthnResponseFields {
+                idp_x509_cert_der: &cert_der,
+                signing_mode,
+                ..fields(&session)
+            };
+            let signed = sign_authn_response(&signer, &fields).unwrap();
+            crypto::verify_signed_xml(signed.xml.as_bytes(), &cert_der, Some("ID")).unwrap();
+
+            let response: Response = signed.xml.parse().unwrap();
+            let assertion = response.assertion.as_ref().unwrap();
+            assert_eq!(
+                response.signature.is_some(),
+                response_signed,
+                "{:?}",
+                signing_mode
+            );
+            assert_eq!(
+                assertion.signature.is_some(),
+                assertion_signed,
+                "{:?}",
+                signing_mode
+            );
+            if let Some(signature) = &assertion.signature {
+                assert_eq!(
+                    signature.signed_info.reference[0].uri,
+                    Some(format!("#{}", assertion.id))
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn signature_template_uses_the_sp_algorithms() {
+        let signature = signature_template(
+            "_response",
+            &[],
+            SigningAlgorithms {
+                signature: SignatureAlgorithm::EcdsaSha384,
+                digest: DigestAlgorithm::Sha512,
+            },
+        );
+        assert_eq!(
+            signature.signed_info.signature_method.algorithm,
+            "http://www.w3.org/2001/04/xmldsig-more#ecdsa-sha384"
+        );
+        let reference = &signature.signed_info.reference[0];
+        assert_eq!(reference.uri.as_deref(), Some("#_response"));
+        assert_eq!(
+            reference.digest_method.algorithm,
+            "http://www.w3.org/2001/04/xmlenc#sha512"
+        );
+    }
 }
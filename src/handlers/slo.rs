@@ -0,0 +1,527 @@
+use actix_web::cookie::Cookie;
+use actix_web::http::header::LOCATION;
+use actix_web::{HttpRequest, HttpResponse, Responder, web};
+use chrono::Utc;
+use log::{debug, error, info, warn};
+use samael::crypto;
+use samael::metadata::{HTTP_POST_BINDING, HTTP_REDIRECT_BINDING};
+use samael::schema::{LogoutRequest, LogoutResponse};
+use samael::traits::ToXml;
+
+use crate::handlers::binding::{
+    decode_post_message, decode_redirect_message, post_binding_form, signed_redirect_url,
+    validate_relay_state, verify_sp_message,
+};
+use crate::handlers::response_builder::{
+    STATUS_PARTIAL_LOGOUT, STATUS_REQUESTER, STATUS_SUCCESS, STATUS_UNKNOWN_PRINCIPAL,
+    build_logout_request, build_logout_response, nest_status_code, signature_template,
+};
+use crate::models::logout::{LogoutInProgress, LogoutInitiator};
+use crate::models::request::{SloForm, SloQuery};
+use crate::models::service_provider::{ServiceProvider, SigningAlgorithms};
+use crate::models::session::{IdpSession, SESSION_COOKIE_NAME};
+use crate::models::state::AppState;
+
+/// SingleLogoutService endpoint.
+///
+/// Accepts LogoutRequests from SPs, LogoutResponses from SPs the IdP is
+/// propagating a logout to, and plain browser requests to log out at the IdP.
+pub async fn handle_slo(
+    req: HttpRequest,
+    query: web::Query<SloQuery>,
+    form: Option<web::Form<SloForm>>,
+    state: web::Data<AppState>,
+) -> impl Responder {
+    let is_redirect = form.is_none();
+    let (saml_request, saml_response, relay_state) = match form {
+        Some(form) => {
+            let form = form.into_inner();
+            (form.saml_request, form.saml_response, form.relay_state)
+        }
+        None => {
+            let query = query.into_inner();
+            (query.saml_request, query.saml_response, query.relay_state)
+        }
+    };
+    let relay_state = relay_state.unwrap_or_default();
+    if let Err(e) = validate_relay_state(&relay_state) {
+        warn!("Rejecting logout message with invalid RelayState: {}", e);
+        return HttpResponse::BadRequest().body("Invalid RelayState parameter");
+    }
+
+    // The browser's IdP session, if it has a live one
+    let browser_session_id = req
+        .cookie(SESSION_COOKIE_NAME)
+        .map(|cookie| cookie.value().to_string())
+        .filter(|id| state.sessions.get(id).is_some());
+
+    let raw_query = is_redirect.then(|| req.query_string());
+    let mut response = match (saml_request, saml_response) {
+        (Some(encoded), None) => {
+            info!("Handling SP-initiated logout request");
+            handle_logout_request(&req, &state, &encoded, raw_query, relay_state)
+        }
+        (None, Some(encoded)) => {
+            info!("Handling logout response");
+            handle_logout_response(&state, &encoded, raw_query, &relay_state)
+        }
+        (None, None) => {
+            info!("Handling IdP-initiated logout");
+            match session_from_cookie(&req, &state) {
+                Some(session) => {
+                    let logout = LogoutInProgress {
+                        initiator: None,
+                        remaining: session.participants,
+                        session_index: session.session_index,
+                        pending_request_id: None,
+                        partial: false,
+                        created_at: Utc::now(),
+                    };
+                    let logout_id = state.logouts.insert(logout);
+                    continue_logout(&state, &logout_id)
+                }
+                None => signed_out_page(),
+            }
+        }
+        (Some(_), Some(_)) => {
+            warn!("Logout message carries both SAMLRequest and SAMLResponse");
+            HttpResponse::BadRequest().body("Unexpected SAMLRequest and SAMLResponse")
+        }
+    };
+
+    // Drop the browser's cookie once its session has ended. A LogoutRequest
+    // can name another session, which leaves the browser's own one alone.
+    let session_ended = browser_session_id.is_some_and(|id| state.sessions.get(&id).is_none());
+    if session_ended
+        && let Err(e) =
+            response.add_removal_cookie(&Cookie::build(SESSION_COOKIE_NAME, "").path("/").finish())
+    {
+        error!("Failed to clear session cookie: {}", e);
+    }
+    response
+}
+
+// Ends the session named by a LogoutRequest and starts notifying the other SPs
+fn handle_logout_request(
+    req: &HttpRequest,
+    state: &AppState,
+    encoded: &str,
+    raw_query: Option<&str>,
+    relay_state: String,
+) -> HttpResponse {
+    let xml = match decode_message(encoded, raw_query.is_some()) {
+        Ok(xml) => xml,
+        Err(e) => {
+            error!("Failed to decode LogoutRequest: {}", e);
+            return HttpResponse::BadRequest().body("Invalid SAML request encoding");
+        }
+    };
+    let logout_request: LogoutRequest = match xml.parse() {
+        Ok(request) => request,
+        Err(e) => {
+            error!("Failed to parse LogoutRequest: {}", e);
+            return HttpResponse::BadRequest().body("Invalid SAML LogoutRequest");
+        }
+    };
+
+    let Some(request_id) = logout_request.id.clone() else {
+        warn!("LogoutRequest is missing an ID");
+        return HttpResponse::BadRequest().body("LogoutRequest is missing an ID");
+    };
+    let Some(sp_entity_id) = logout_request
+        .issuer
+        .as_ref()
+        .and_then(|i| i.value.as_ref())
+    else {
+        warn!("LogoutRequest is missing an Issuer");
+        return HttpResponse::BadRequest().body("LogoutRequest is missing an Issuer");
+    };
+    let Some(sp) = state.service_providers.find(sp_entity_id) else {
+        warn!("LogoutRequest from unknown SP: {}", sp_entity_id);
+        return HttpResponse::BadRequest()
+            .body(format!("Unknown service provider '{}'", sp_entity_id));
+    };
+
+    if let Err(e) = verify_sp_message(
+        &sp,
+        raw_query,
+        "SAMLRequest",
+        &xml,
+        logout_request.signature.as_ref(),
+        &request_id,
+    ) {
+        warn!(
+            "Rejecting LogoutRequest {} from SP {}: {}",
+            request_id, sp.entity_id, e
+        );
+        return HttpResponse::Forbidden().body("LogoutRequest signature verification failed");
+    }
+
+    let initiator = LogoutInitiator {
+        sp_entity_id: sp.entity_id.clone(),
+        request_id,
+        relay_state,
+    };
+
+    // Prefer the SessionIndex named by the SP, falling back to the browser's
+    // session. The session only ends once the request is known to come from
+    // one of its participants about the session's user.
+    let session = logout_request
+        .session_index
+        .as_ref()
+        .and_then(|index| index.value.as_deref())
+        .and_then(|index| state.sessions.find_by_session_index(index))
+        .or_else(|| {
+            req.cookie(SESSION_COOKIE_NAME)
+                .and_then(|cookie| state.sessions.get(cookie.value()))
+        });
+    let Some(session) = session.filter(|session| {
+        let Some(name_id) = logout_request.name_id.as_ref() else {
+            return false;
+        };
+        session.participants.iter().any(|participant| {
+            participant.sp_entity_id == sp.entity_id
+                && participant.name_id == name_id.value
+                && name_id
+                    .format
+                    .as_ref()
+                    .is_none_or(|format| *format == participant.name_id_format)
+        })
+    }) else {
+        warn!(
+            "LogoutRequest {} from SP {} does not match a session it takes part in",
+            initiator.request_id, sp.entity_id
+        );
+        return send_logout_response(
+            state,
+            &initiator,
+            STATUS_REQUESTER,
+            Some(STATUS_UNKNOWN_PRINCIPAL),
+            None,
+        );
+    };
+    state.sessions.remove(&session.id);
+    debug!(
+        "SP {} ended IdP session {}",
+        sp.entity_id, session.session_index
+    );
+
+    let logout = LogoutInProgress {
+        initiator: Some(initiator),
+        remaining: session
+            .participants
+            .into_iter()
+            .filter(|participant| participant.sp_entity_id != sp.entity_id)
+            .collect(),
+        session_index: session.session_index,
+        pending_request_id: None,
+        partial: false,
+        created_at: Utc::now(),
+    };
+    let logout_id = state.logouts.insert(logout);
+    continue_logout(state, &logout_id)
+}
+
+// Records an SP's answer to a propagated LogoutRequest and moves on to the next SP
+fn handle_logout_response(
+    state: &AppState,
+    encoded: &str,
+    raw_query: Option<&str>,
+    relay_state: &str,
+) -> HttpResponse {
+    let xml = match decode_message(encoded, raw_query.is_some()) {
+        Ok(xml) => xml,
+        Err(e) => {
+            error!("Failed to decode LogoutResponse: {}", e);
+            return HttpResponse::BadRequest().body("Invalid SAML response encoding");
+        }
+    };
+    let logout_response: LogoutResponse = match xml.parse() {
+        Ok(response) => response,
+        Err(e) => {
+            error!("Failed to parse LogoutResponse: {}", e);
+            return HttpResponse::BadRequest().body("Invalid SAML LogoutResponse");
+        }
+    };
+
+    let Some(mut logout) = state.logouts.take(relay_state) else {
+        warn!("LogoutResponse for unknown or completed logout");
+        return HttpResponse::BadRequest().body("Unknown or expired logout");
+    };
+
+    let sp = logout_response
+        .issuer
+        .as_ref()
+        .and_then(|i| i.value.as_ref())
+        .and_then(|entity_id| state.service_providers.find(entity_id));
+    let verified = match &sp {
+        Some(sp) => verify_sp_message(
+            sp,
+            raw_query,
+            "SAMLResponse",
+            &xml,
+            logout_response.signature.as_ref(),
+            logout_response.id.as_deref().unwrap_or_default(),
+        )
+        .map_err(|e| e.to_string()),
+        None => Err("LogoutResponse issuer is missing or unknown".to_string()),
+    };
+    let answers_pending = logout_response.in_response_to.is_some()
+        && logout_response.in_response_to == logout.pending_request_id;
+    let succeeded = logout_response
+        .status
+        .as_ref()
+        .and_then(|status| status.status_code.value.as_deref())
+        == Some(STATUS_SUCCESS);
+
+    match verified {
+        Ok(()) if answers_pending && succeeded => {
+            debug!("SP confirmed its part of logout {}", relay_state)
+        }
+        Ok(()) => {
+            warn!(
+                "LogoutResponse for logout {} did not confirm success",
+                relay_state
+            );
+            logout.partial = true;
+        }
+        Err(e) => {
+            warn!("Ignoring LogoutResponse for logout {}: {}", relay_state, e);
+            logout.partial = true;
+        }
+    }
+
+    state.logouts.put(relay_state, logout);
+    continue_logout(state, relay_state)
+}
+
+// Sends a LogoutRequest to the next SP in the session, or completes the logout
+// once every SP has been notified
+fn continue_logout(state: &AppState, logout_id: &str) -> HttpResponse {
+    let Some(mut logout) = state.logouts.take(logout_id) else {
+        warn!("Logout {} is unknown or already completed", logout_id);
+        return signed_out_page();
+    };
+    while !logout.remaining.is_empty() {
+        let participant = logout.remaining.remove(0);
+        let Some(sp) = state.service_providers.find(&participant.sp_entity_id) else {
+            warn!(
+                "SP {} is no longer registered, skipping logout",
+                participant.sp_entity_id
+            );
+            logout.partial = true;
+            continue;
+        };
+        let Some(endpoint) = sp.slo_endpoint() else {
+            debug!("SP {} has no SingleLogoutService", sp.entity_id);
+            logout.partial = true;
+            continue;
+        };
+
+        let request_id = crypto::gen_saml_response_id();
+        let result = deliver(
+            state,
+            &sp,
+            &endpoint.location,
+            &endpoint.binding,
+            "SAMLRequest",
+            logout_id,
+            |signing| {
+                Ok(build_logout_request(
+                    &request_id,
+                    &state.idp_entity_id,
+                    &endpoint.location,
+                    &participant,
+                    &logout.session_index,
+                    signing.map(|(cert_der, algorithms)| {
+                        signature_template(&request_id, cert_der, algorithms)
+                    }),
+                )
+                .to_string()?)
+            },
+        );
+        match result {
+            Ok(response) => {
+                debug!(
+                    "Sending LogoutRequest {} to SP {}",
+                    request_id, sp.entity_id
+                );
+                logout.pending_request_id = Some(request_id);
+                state.logouts.put(logout_id, logout);
+                return response;
+            }
+            Err(e) => {
+                error!(
+                    "Failed to build LogoutRequest for SP {}: {}",
+                    sp.entity_id, e
+                );
+                logout.partial = true;
+            }
+        }
+    }
+
+    if logout.partial {
+        warn!("Logout {} completed partially", logout_id);
+    } else {
+        info!("Logout {} completed", logout_id);
+    }
+
+    let Some(initiator) = logout.initiator else {
+        return signed_out_page();
+    };
+    let status_message = logout
+        .partial
+        .then_some("Not all service providers could be logged out");
+    send_logout_response(
+        state,
+        &initiator,
+        STATUS_SUCCESS,
+        logout.partial.then_some(STATUS_PARTIAL_LOGOUT),
+        status_message,
+    )
+}
+
+// Answers the SP that started a logout with a LogoutResponse
+fn send_logout_response(
+    state: &AppState,
+    initiator: &LogoutInitiator,
+    status_code: &str,
+    second_level_code: Option<&str>,
+    status_message: Option<&str>,
+) -> HttpResponse {
+    let Some(sp) = state.service_providers.find(&initiator.sp_entity_id) else {
+        return signed_out_page();
+    };
+    let Some(endpoint) = sp.slo_endpoint() else {
+        debug!(
+            "SP {} has no SingleLogoutService to receive the LogoutResponse",
+            sp.entity_id
+        );
+        return signed_out_page();
+    };
+
+    let destination = endpoint
+        .response_location
+        .as_deref()
+        .unwrap_or(&endpoint.location);
+    let response_id = crypto::gen_saml_response_id();
+    let result = deliver(
+        state,
+        &sp,
+        destination,
+        &endpoint.binding,
+        "SAMLResponse",
+        &initiator.relay_state,
+        |signing| {
+            let xml = build_logout_response(
+                &response_id,
+                &state.idp_entity_id,
+                destination,
+                &initiator.request_id,
+                status_code,
+                status_message,
+                signing.map(|(cert_der, algorithms)| {
+                    signature_template(&response_id, cert_der, algorithms)
+                }),
+            )
+            .to_string()?;
+            match second_level_code {
+                Some(code) => nest_status_code(&xml, code),
+                None => Ok(xml),
+            }
+        },
+    );
+    match result {
+        Ok(response) => {
+            debug!(
+                "Sending LogoutResponse {} to SP {}",
+                response_id, sp.entity_id
+            );
+            response
+        }
+        Err(e) => {
+            error!(
+                "Failed to build LogoutResponse for SP {}: {}",
+                sp.entity_id, e
+            );
+            HttpResponse::InternalServerError().body("Failed to complete logout")
+        }
+    }
+}
+
+// Delivers a logout message to `sp` over `binding`. `build_xml` is passed the
+// IdP certificate and the SP's signing algorithms when the message must carry
+// an enveloped signature.
+fn deliver<F>(
+    state: &AppState,
+    sp: &ServiceProvider,
+    location: &str,
+    binding: &str,
+    message_param: &str,
+    relay_state: &str,
+    build_xml: F,
+) -> Result<HttpResponse, Box<dyn std::error::Error>>
+where
+    F: Fn(Option<(&[u8], SigningAlgorithms)>) -> Result<String, Box<dyn std::error::Error>>,
+{
+    let key = state.signing_keys.active();
+    let algorithms = key.signing_algorithms(sp);
+    match binding {
+        HTTP_REDIRECT_BINDING => {
+            let url = signed_redirect_url(
+                location,
+                message_param,
+                &build_xml(None)?,
+                relay_state,
+                key.signer.as_ref(),
+                algorithms.signature,
+            )?;
+            Ok(HttpResponse::Found()
+                .append_header((LOCATION, url))
+                .finish())
+        }
+        HTTP_POST_BINDING => {
+            let unsigned_xml = build_xml(Some((key.cert_der.as_slice(), algorithms)))?;
+            let signed_xml = key.signer.sign_xml(unsigned_xml.as_str())?;
+            Ok(post_binding_form(
+                location,
+                message_param,
+                &signed_xml,
+                relay_state,
+            ))
+        }
+        other => Err(format!("Unsupported SingleLogoutService binding {}", other).into()),
+    }
+}
+
+fn decode_message(encoded: &str, is_redirect: bool) -> Result<String, Box<dyn std::error::Error>> {
+    if is_redirect {
+        decode_redirect_message(encoded)
+    } else {
+        decode_post_message(encoded)
+    }
+}
+
+// Ends the session identified by the browser's session cookie
+fn session_from_cookie(req: &HttpRequest, state: &AppState) -> Option<IdpSession> {
+    req.cookie(SESSION_COOKIE_NAME)
+        .and_then(|cookie| state.sessions.remove(cookie.value()))
+}
+
+fn signed_out_page() -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("text/html; charset=utf-8")
+        .body(
+            r#"
+    <html>
+        <head>
+            <title>Signed out</title>
+        </head>
+        <body>
+            <h1>You have been signed out</h1>
+            <p>You may now close this window.</p>
+        </body>
+    </html>
+    "#,
+        )
+}
@@ -1,332 +1,661 @@
-use actix_web::{HttpResponse, Responder, web};
-use base64::Engine as _;
-use base64::engine::general_purpose;
+use actix_web::cookie::time::Duration as CookieDuration;
+use actix_web::cookie::{Cookie, SameSite};
+use actix_web::{HttpRequest, HttpResponse, Responder, web};
+use chrono::{Duration, Utc};
+use libxml::parser::Parser;
+use libxml::tree::Node;
 use log::{debug, error, info, trace, warn};
-use samael::idp::IdentityProvider;
-use samael::idp::response_builder::ResponseAttribute;
-use samael::idp::sp_extractor::RequiredAttribute;
-use samael::schema::{AuthnRequest, Response};
-use samael::traits::ToXml;
+use samael::schema::AuthnRequest;
 use std::borrow::Borrow;
 
-use crate::handlers::response_builder::sign_authn_response;
+use crate::handlers::binding::{
+    decode_post_message, decode_redirect_message, post_binding_form, validate_relay_state,
+    verify_sp_message,
+};
+use crate::handlers::encryption::AssertionEncryption;
+use crate::handlers::login::login_form;
+use crate::handlers::response_builder::{
+    AuthnResponseFields, ErrorStatus, ReleasedAttribute, STATUS_INVALID_NAME_ID_POLICY,
+    STATUS_NO_PASSIVE, STATUS_REQUEST_DENIED, STATUS_UNKNOWN_PRINCIPAL, SignedResponse,
+    sign_authn_response, sign_error_response,
+};
+use crate::models::name_id::{NAME_ID_FORMAT_UNSPECIFIED, resolve_format};
+use crate::models::pending_request::PendingAuthnRequest;
 use crate::models::request::{IdpInitiatedQuery, SamlRequest, SsoQuery};
+use crate::models::service_provider::{AttributeRequest, ServiceProvider};
+use crate::models::session::{
+    AUTHN_CONTEXT_UNSPECIFIED, IdpSession, SESSION_COOKIE_NAME, SessionParticipant,
+};
 use crate::models::state::AppState;
 use crate::models::user::User;
+use crate::signing::SigningBackend;
+
+const PROTOCOL_NAMESPACE: &str = "urn:oasis:names:tc:SAML:2.0:protocol";
+const METADATA_NAMESPACE: &str = "urn:oasis:names:tc:SAML:2.0:metadata";
 
 pub async fn handle_sso(
+    req: HttpRequest,
     query: web::Query<SsoQuery>,
     state: web::Data<AppState>,
     saml_request: Option<web::Form<SamlRequest>>,
 ) -> impl Responder {
     info!("Handling SP-initiated SSO request");
 
-    // Extract userId
-    let user_id = query.user_id.clone();
-    if user_id.is_empty() {
-        warn!("Missing userId in SP-initiated SSO request");
-        return HttpResponse::BadRequest().body("Missing userId parameter");
-    }
-
-    debug!("Processing SSO for user: {}", user_id);
-
-    // Check if user exists in our database
-    let user = match state.user_database.find_user(&user_id) {
-        Some(user) => user,
-        None => {
-            warn!("User not found in database: {}", user_id);
-            return HttpResponse::Unauthorized().body(format!("User '{}' not found", user_id));
-        }
-    };
-
-    debug!("User found in database: {}", user_id);
-
     // Decode SAML request
-    let authn_request = match saml_request.borrow() {
+    let xml = match saml_request.borrow() {
         Some(form) => {
             // Handle POST request
             debug!("Handling POST binding SAML request");
-            let decoded = match general_purpose::STANDARD.decode(&form.saml_request) {
-                Ok(data) => data,
+            match decode_post_message(&form.saml_request) {
+                Ok(xml) => xml,
                 Err(e) => {
-                    error!("Failed to decode SAML request: {}", e);
+                    error!("Failed to decode POST binding SAML request: {}", e);
                     return HttpResponse::BadRequest().body("Invalid SAML request encoding");
                 }
-            };
-
-            let xml = match String::from_utf8(decoded) {
-                Ok(xml_str) => xml_str,
-                Err(e) => {
-                    error!("Failed to convert decoded SAML request to UTF-8: {}", e);
-                    return HttpResponse::BadRequest().body("Invalid SAML request format");
-                }
-            };
-
-            debug!("Parsing SAML AuthnRequest");
-            let request: AuthnRequest = match xml.parse() {
-                Ok(req) => req,
-                Err(e) => {
-                    error!("Failed to parse SAML AuthnRequest: {}", e);
-                    return HttpResponse::BadRequest().body("Invalid SAML AuthnRequest");
-                }
-            };
-            request
+            }
         }
-        None => {
+        None => match &query.saml_request {
             // Handle GET request for redirect binding
-            if query.saml_request.is_some() {
-                debug!("Handling GET binding SAML request");
-                // Decode and inflate the request
-                // Implementation needed for deflate decompression
-                // ...
-                error!("Redirect binding not implemented yet");
-                todo!("Implement SAML request decoding for redirect binding")
-            } else {
+            Some(encoded) => {
+                debug!("Handling Redirect binding SAML request");
+                match decode_redirect_message(encoded) {
+                    Ok(xml) => xml,
+                    Err(e) => {
+                        error!("Failed to decode Redirect binding SAML request: {}", e);
+                        return HttpResponse::BadRequest().body("Invalid SAML request encoding");
+                    }
+                }
+            }
+            None => {
                 warn!("Missing SAMLRequest parameter");
                 return HttpResponse::BadRequest().body("Missing SAMLRequest parameter");
             }
+        },
+    };
+
+    debug!("Parsing SAML AuthnRequest");
+    let authn_request: AuthnRequest = match xml.parse() {
+        Ok(req) => req,
+        Err(e) => {
+            error!("Failed to parse SAML AuthnRequest: {}", e);
+            return HttpResponse::BadRequest().body("Invalid SAML AuthnRequest");
         }
     };
 
-    // Process the request and return a SAML response
-    // Generate a successful response
+    // Resolve the SP from the AuthnRequest Issuer
+    let Some(sp_entity_id) = authn_request.issuer.as_ref().and_then(|i| i.value.as_ref()) else {
+        warn!("AuthnRequest is missing an Issuer");
+        return HttpResponse::BadRequest().body("AuthnRequest is missing an Issuer");
+    };
+    let Some(sp) = state.service_providers.find(sp_entity_id) else {
+        warn!("AuthnRequest from unknown SP: {}", sp_entity_id);
+        return HttpResponse::BadRequest()
+            .body(format!("Unknown service provider '{}'", sp_entity_id));
+    };
+    debug!("Resolved AuthnRequest issuer to SP {}", sp.entity_id);
+
+    // Failures before the requested ACS is validated are reported to the SP's
+    // default ACS, which is always a registered endpoint
+    let Some(default_acs) = sp.default_acs() else {
+        error!("No default ACS endpoint registered for SP {}", sp.entity_id);
+        return HttpResponse::InternalServerError()
+            .body("No ACS endpoint registered for service provider");
+    };
+    let in_response_to = authn_request.id.clone();
+    let reject = |relay_state: &str, status: ErrorStatus| {
+        warn!(
+            "Rejecting AuthnRequest {} from SP {}: {}",
+            in_response_to, sp.entity_id, status.message
+        );
+        send_error_response(
+            &state,
+            &sp.entity_id,
+            &default_acs.location,
+            Some(in_response_to.clone()),
+            relay_state,
+            &status,
+        )
+    };
+
     let relay_state = saml_request
         .as_ref()
         .and_then(|req| req.relay_state.clone())
         .or_else(|| query.relay_state.clone())
         .unwrap_or_default();
+    if let Err(e) = validate_relay_state(&relay_state) {
+        return reject("", ErrorStatus::requester(e.to_string()));
+    }
 
-    // Extract information from the AuthnRequest
-    let audience = authn_request.issuer.map(|i| i.value).unwrap_or_default();
-    let acs_url = authn_request
-        .assertion_consumer_service_url
-        .unwrap_or_default();
-    let in_response_to = authn_request.id;
+    // Verify the request signature if the SP signs its requests
+    let raw_query = saml_request.is_none().then(|| req.query_string());
+    if let Err(e) = verify_sp_message(
+        &sp,
+        raw_query,
+        "SAMLRequest",
+        &xml,
+        authn_request.signature.as_ref(),
+        &authn_request.id,
+    ) {
+        return reject(
+            &relay_state,
+            ErrorStatus::requester(format!("Signature verification failed: {}", e))
+                .with_second_level_code(STATUS_REQUEST_DENIED),
+        );
+    }
+
+    // Resolve the ACS endpoint against those registered for the SP
+    let requested_acs_index = match authn_request.assertion_consumer_service_index {
+        Some(index) => match u16::try_from(index) {
+            Ok(index) => Some(index),
+            Err(_) => {
+                return reject(
+                    &relay_state,
+                    ErrorStatus::requester(format!(
+                        "AssertionConsumerServiceIndex {} is out of range",
+                        index
+                    )),
+                );
+            }
+        },
+        None => None,
+    };
+    let acs_url = match sp.resolve_acs(
+        authn_request.assertion_consumer_service_url.as_deref(),
+        requested_acs_index,
+        authn_request.protocol_binding.as_deref(),
+    ) {
+        Ok(acs) => acs.location.clone(),
+        Err(e) => return reject(&relay_state, ErrorStatus::requester(e.to_string())),
+    };
+
+    // Pick the NameID format from the request's NameIDPolicy
+    let name_id_policy = authn_request.name_id_policy.as_ref();
+    let requested_format = name_id_policy.and_then(|policy| policy.format.as_deref());
+    let Some(name_id_format) = resolve_format(requested_format, sp.name_id_format.as_deref())
+    else {
+        let message = format!(
+            "NameID format {} is not supported",
+            requested_format.unwrap_or_default()
+        );
+        warn!(
+            "Rejecting AuthnRequest {} from SP {}: {}",
+            in_response_to, sp.entity_id, message
+        );
+        return send_error_response(
+            &state,
+            &sp.entity_id,
+            &acs_url,
+            Some(in_response_to),
+            &relay_state,
+            &ErrorStatus::requester(message).with_second_level_code(STATUS_INVALID_NAME_ID_POLICY),
+        );
+    };
+    let name_id_format = name_id_format.to_string();
+    // An omitted AllowCreate places no restriction on creating identifiers
+    let allow_create = name_id_policy
+        .and_then(|policy| policy.allow_create)
+        .unwrap_or(true);
+
+    // Attributes the SP asks for narrow what its release policy allows
+    let consuming_service_index = match authn_request.attribute_consuming_service_index {
+        Some(index) => match u16::try_from(index)
+            .ok()
+            .filter(|index| sp.attribute_consuming_service(*index).is_some())
+        {
+            Some(index) => Some(index),
+            None => {
+                return send_error_response(
+                    &state,
+                    &sp.entity_id,
+                    &acs_url,
+                    Some(in_response_to),
+                    &relay_state,
+                    &ErrorStatus::requester(format!(
+                        "Unknown AttributeConsumingServiceIndex {}",
+                        index
+                    )),
+                );
+            }
+        },
+        None => None,
+    };
+    let requested_attributes = match requested_attribute_names(&xml) {
+        Ok(names) => names,
+        Err(e) => {
+            warn!(
+                "Ignoring RequestedAttributes of AuthnRequest {}: {}",
+                in_response_to, e
+            );
+            None
+        }
+    };
 
     debug!(
-        "AuthnRequest details - Audience: {:?}, ACS URL: {}, ID: {}",
-        audience, acs_url, in_response_to
+        "AuthnRequest details - SP: {}, ACS URL: {}, ID: {}",
+        sp.entity_id, acs_url, in_response_to
     );
 
-    // Create user attributes from database
-    let attributes = create_user_attributes_from_db(user);
-
-    debug!("Signing SAML response");
-    let authn_response_fields = SignAuthnResponseFields {
-        idp_x509_cert_der: &state.cert_der,
-        subject_name_id: &user_id,
-        audience: &audience.unwrap(),
-        acs_url: &acs_url,
-        issuer: &state.idp_entity_id,
-        in_response_to_id: Some(in_response_to),
-        attributes: &attributes,
+    let pending = PendingAuthnRequest {
+        sp_entity_id: sp.entity_id.clone(),
+        acs_url,
+        in_response_to: Some(in_response_to),
+        relay_state,
+        force_authn: authn_request.force_authn.unwrap_or(false),
+        is_passive: authn_request.is_passive.unwrap_or(false),
+        name_id_format,
+        allow_create,
+        attribute_request: AttributeRequest {
+            consuming_service_index,
+            requested_attributes,
+        },
+        created_at: Utc::now(),
     };
+    authenticate_or_prompt(&req, &state, query.user_id.as_deref(), pending)
+}
 
-    // Sign the response
-    let response = match sign_authn_response_with_config(&state.idp, authn_response_fields) {
-        Ok(resp) => {
-            debug!("Successfully signed SAML response with ID: {}", resp.id);
-            resp
-        }
-        Err(e) => {
-            error!("Failed to sign SAML response: {}", e);
-            return HttpResponse::InternalServerError()
-                .body(format!("Failed to create SAML response: {}", e));
+// Reads the names of the `md:RequestedAttribute`s in the Extensions of an
+// AuthnRequest, as defined by the SAML V2.0 protocol extension for requesting
+// attributes per request. Returns `None` when the request lists none.
+fn requested_attribute_names(xml: &str) -> Result<Option<Vec<String>>, Box<dyn std::error::Error>> {
+    let document = Parser::default().parse_string(xml)?;
+    let root = document
+        .get_root_element()
+        .ok_or("AuthnRequest has no root element")?;
+    let mut names = Vec::new();
+    for extensions in root
+        .get_child_elements()
+        .into_iter()
+        .filter(|child| is_element(child, PROTOCOL_NAMESPACE, "Extensions"))
+    {
+        collect_requested_attribute_names(&extensions, &mut names);
+    }
+    Ok((!names.is_empty()).then_some(names))
+}
+
+fn collect_requested_attribute_names(node: &Node, names: &mut Vec<String>) {
+    for child in node.get_child_elements() {
+        if is_element(&child, METADATA_NAMESPACE, "RequestedAttribute") {
+            names.extend(child.get_attribute("Name"));
+        } else {
+            collect_requested_attribute_names(&child, names);
         }
-    };
+    }
+}
 
-    info!("Sending SAML response to {}", acs_url);
-    // Create and return HTML form with SAML response
-    create_saml_post_form(&response, &acs_url, &relay_state)
+fn is_element(node: &Node, namespace: &str, name: &str) -> bool {
+    node.get_name() == name
+        && node
+            .get_namespace()
+            .is_some_and(|ns| ns.get_href() == namespace)
 }
 
 pub async fn handle_idp_initiated_sso(
+    req: HttpRequest,
     query: web::Query<IdpInitiatedQuery>,
     state: web::Data<AppState>,
 ) -> impl Responder {
     info!("Handling IdP-initiated SSO request");
 
-    // Extract userId
-    let user_id = query.user_id.clone();
-    if user_id.is_empty() {
-        warn!("Missing userId in IdP-initiated SSO request");
-        return HttpResponse::BadRequest().body("Missing userId parameter");
-    }
-
-    debug!("Processing IdP-initiated SSO for user: {}", user_id);
-
-    // Check if user exists in our database
-    let user = match state.user_database.find_user(&user_id) {
-        Some(user) => user,
-        None => {
-            warn!("User not found in database: {}", user_id);
-            return HttpResponse::Unauthorized().body(format!("User '{}' not found", user_id));
-        }
-    };
-
-    debug!("User found in database: {}", user_id);
-
     // Get relay state if provided
     let relay_state = query.relay_state.clone().unwrap_or_default();
+    if let Err(e) = validate_relay_state(&relay_state) {
+        warn!("Rejecting IdP-initiated SSO with invalid RelayState: {}", e);
+        return HttpResponse::BadRequest().body("Invalid RelayState parameter");
+    }
 
-    // Create user attributes from the database record
-    let attributes = create_user_attributes_from_db(user);
+    // Resolve the target SP, defaulting to the only registered SP
+    let sp = match &query.sp {
+        Some(entity_id) => state.service_providers.find(entity_id),
+        None => state.service_providers.single(),
+    };
+    let Some(sp) = sp else {
+        warn!(
+            "Unknown or missing SP in IdP-initiated SSO request: {:?}",
+            query.sp
+        );
+        return HttpResponse::BadRequest().body("Unknown or missing sp parameter");
+    };
+    let Some(acs) = sp.default_acs() else {
+        error!("No default ACS endpoint registered for SP {}", sp.entity_id);
+        return HttpResponse::InternalServerError()
+            .body("No ACS endpoint registered for service provider");
+    };
 
     debug!(
         "IdP-initiated SSO to SP entity: {}, ACS URL: {}",
-        state.sp_entity_id, state.sp_acs_url
+        sp.entity_id, acs.location
     );
 
-    debug!("Signing SAML response for IdP-initiated SSO");
-    let authn_response_fields = SignAuthnResponseFields {
-        idp_x509_cert_der: &state.cert_der,
-        subject_name_id: &user_id,
-        audience: &state.sp_entity_id,
-        acs_url: &state.sp_acs_url,
-        issuer: &state.idp_entity_id,
-        in_response_to_id: None,
-        attributes: &attributes,
+    let pending = PendingAuthnRequest {
+        sp_entity_id: sp.entity_id.clone(),
+        acs_url: acs.location.clone(),
+        in_response_to: None,
+        relay_state,
+        force_authn: false,
+        is_passive: false,
+        name_id_format: sp
+            .name_id_format
+            .clone()
+            .unwrap_or_else(|| NAME_ID_FORMAT_UNSPECIFIED.to_string()),
+        allow_create: true,
+        attribute_request: AttributeRequest::default(),
+        created_at: Utc::now(),
     };
-    // Sign the response
-    let response = match sign_authn_response_with_config(&state.idp, authn_response_fields) {
-        Ok(resp) => {
-            debug!("Successfully signed SAML response with ID: {}", resp.id);
-            resp
+    authenticate_or_prompt(&req, &state, query.user_id.as_deref(), pending)
+}
+
+// Completes the request silently when the user already has an IdP session or
+// test mode allows impersonation, otherwise asks the user to sign in
+fn authenticate_or_prompt(
+    req: &HttpRequest,
+    state: &AppState,
+    user_id: Option<&str>,
+    pending: PendingAuthnRequest,
+) -> HttpResponse {
+    if let Some(user_id) = user_id {
+        if !state.test_mode {
+            warn!(
+                "Ignoring user_id '{}' because test mode is disabled",
+                user_id
+            );
+        } else {
+            debug!("Test mode: impersonating user {}", user_id);
+
+            // Check if user exists in our database
+            return match state.user_database.find_user(user_id) {
+                Some(user) => {
+                    let session = state
+                        .sessions
+                        .create(&user.user_id, AUTHN_CONTEXT_UNSPECIFIED);
+                    let response = issue_authn_response(state, user, &session, &pending);
+                    with_session_cookie(response, state, &session)
+                }
+                None => {
+                    warn!("User not found in database: {}", user_id);
+                    send_error_response(
+                        state,
+                        &pending.sp_entity_id,
+                        &pending.acs_url,
+                        pending.in_response_to.clone(),
+                        &pending.relay_state,
+                        &ErrorStatus::responder(format!("User '{}' not found", user_id))
+                            .with_second_level_code(STATUS_UNKNOWN_PRINCIPAL),
+                    )
+                }
+            };
         }
-        Err(e) => {
-            error!("Failed to sign SAML response: {}", e);
-            return HttpResponse::InternalServerError()
-                .body(format!("Failed to create SAML response: {}", e));
+    }
+
+    // Reuse an existing IdP session unless the SP demands fresh authentication
+    if pending.force_authn {
+        debug!("ForceAuthn requested, ignoring any existing IdP session");
+    } else if let Some(session) = req
+        .cookie(SESSION_COOKIE_NAME)
+        .and_then(|cookie| state.sessions.touch(cookie.value()))
+    {
+        match state.user_database.find_user(&session.user_id) {
+            Some(user) => {
+                info!(
+                    "Reusing IdP session {} for user {}",
+                    session.session_index, user.user_id
+                );
+                return issue_authn_response(state, user, &session, &pending);
+            }
+            None => warn!("User {} from IdP session no longer exists", session.user_id),
         }
+    }
+
+    // A passive request must be answered without interacting with the user
+    if pending.is_passive {
+        debug!("IsPassive requested but the user must sign in");
+        return send_error_response(
+            state,
+            &pending.sp_entity_id,
+            &pending.acs_url,
+            pending.in_response_to.clone(),
+            &pending.relay_state,
+            &ErrorStatus::responder("The user is not signed in at the IdP")
+                .with_second_level_code(STATUS_NO_PASSIVE),
+        );
+    }
+
+    let request_id = state.pending_requests.insert(pending);
+    login_form(&request_id, None)
+}
+
+/// Attaches the IdP session cookie to a response
+pub fn with_session_cookie(
+    mut response: HttpResponse,
+    state: &AppState,
+    session: &IdpSession,
+) -> HttpResponse {
+    // SameSite=None is required because SPs deliver POST binding requests cross-site
+    let cookie = Cookie::build(SESSION_COOKIE_NAME, session.id.clone())
+        .path("/")
+        .secure(true)
+        .http_only(true)
+        .same_site(SameSite::None)
+        .max_age(CookieDuration::seconds(
+            state.sessions.cookie_max_age_secs(),
+        ))
+        .finish();
+    if let Err(e) = response.add_cookie(&cookie) {
+        error!("Failed to set IdP session cookie: {}", e);
+    }
+    response
+}
+
+/// Signs an assertion for an authenticated user and posts it to the SP
+pub fn issue_authn_response(
+    state: &AppState,
+    user: &User,
+    session: &IdpSession,
+    pending: &PendingAuthnRequest,
+) -> HttpResponse {
+    let Some(sp) = state.service_providers.find(&pending.sp_entity_id) else {
+        error!(
+            "SP {} is no longer registered, dropping pending request",
+            pending.sp_entity_id
+        );
+        return HttpResponse::BadRequest().body("Unknown service provider");
     };
 
-    info!(
-        "Sending IdP-initiated SAML response to {}",
-        state.sp_acs_url
+    // Create user attributes from the database record
+    let attributes = create_user_attributes_from_db(user, &sp, &pending.attribute_request);
+
+    let name_id_format = pending.name_id_format.as_str();
+    let name_id =
+        match state
+            .name_ids
+            .name_id(name_id_format, user, &sp.entity_id, pending.allow_create)
+        {
+            Ok(Some(name_id)) => name_id,
+            Ok(None) => {
+                return send_error_response(
+                    state,
+                    &pending.sp_entity_id,
+                    &pending.acs_url,
+                    pending.in_response_to.clone(),
+                    &pending.relay_state,
+                    &ErrorStatus::requester("No persistent NameID exists and AllowCreate is false")
+                        .with_second_level_code(STATUS_INVALID_NAME_ID_POLICY),
+                );
+            }
+            Err(e) => {
+                error!("Failed to issue NameID for user {}: {}", user.user_id, e);
+                return send_error_response(
+                    state,
+                    &pending.sp_entity_id,
+                    &pending.acs_url,
+                    pending.in_response_to.clone(),
+                    &pending.relay_state,
+                    &ErrorStatus::responder("Failed to issue NameID"),
+                );
+            }
+        };
+
+    let key = state.signing_keys.active();
+    let algorithms = key.signing_algorithms(&sp);
+
+    debug!("Signing SAML response for user {}", user.user_id);
+    let authn_response_fields = AuthnResponseFields {
+        idp_x509_cert_der: &key.cert_der,
+        subject_name_id: &name_id,
+        name_id_format,
+        audience: &sp.entity_id,
+        acs_url: &pending.acs_url,
+        issuer: &state.idp_entity_id,
+        in_response_to_id: pending.in_response_to.clone(),
+        attributes: &attributes,
+        session,
+        assertion_lifetime: Duration::seconds(sp.assertion_lifetime_secs.into()),
+        clock_skew: Duration::seconds(sp.clock_skew_secs.into()),
+        one_time_use: sp.one_time_use,
+        signing_mode: sp.signing_mode,
+        algorithms,
+        encryption: sp
+            .encryption_certificate_der
+            .as_deref()
+            .filter(|_| sp.encrypts_assertions())
+            .map(|certificate_der| AssertionEncryption {
+                certificate_der,
+                data_algorithm: sp.data_encryption_algorithm,
+                key_transport: sp.key_transport_algorithm,
+            }),
+    };
+
+    // Sign the response
+    let response =
+        match sign_authn_response_with_config(key.signer.as_ref(), &authn_response_fields) {
+            Ok(resp) => {
+                debug!("Successfully signed SAML response with ID: {}", resp.id);
+                resp
+            }
+            Err(e) => {
+                error!("Failed to sign SAML response: {}", e);
+                return send_error_response(
+                    state,
+                    &pending.sp_entity_id,
+                    &pending.acs_url,
+                    pending.in_response_to.clone(),
+                    &pending.relay_state,
+                    &ErrorStatus::responder("Failed to create SAML response"),
+                );
+            }
+        };
+
+    // Remember the SP so it can be included in single logout
+    state.sessions.add_participant(
+        &session.id,
+        SessionParticipant {
+            sp_entity_id: sp.entity_id.clone(),
+            name_id: name_id.clone(),
+            name_id_format: name_id_format.to_string(),
+        },
     );
+
+    info!("Sending SAML response to {}", pending.acs_url);
     // Create and return HTML form with SAML response
-    create_saml_post_form(&response, &state.sp_acs_url, &relay_state)
+    create_saml_post_form(&response, &pending.acs_url, &pending.relay_state)
 }
 
-// Create user attributes from database record
-fn create_user_attributes_from_db(user: &User) -> Vec<ResponseAttribute> {
-    let mut attributes = vec![
-        // Core attributes
-        ResponseAttribute {
-            required_attribute: RequiredAttribute {
-                name: "firstName".to_string(),
-                format: Some("urn:oasis:names:tc:SAML:2.0:attrname-format:uri".to_string()),
-            },
-            value: &user.first_name,
-        },
-        ResponseAttribute {
-            required_attribute: RequiredAttribute {
-                name: "lastName".to_string(),
-                format: Some("urn:oasis:names:tc:SAML:2.0:attrname-format:uri".to_string()),
-            },
-            value: &user.last_name,
-        },
-        ResponseAttribute {
-            required_attribute: RequiredAttribute {
-                name: "email".to_string(),
-                format: Some("urn:oasis:names:tc:SAML:2.0:attrname-format:uri".to_string()),
-            },
-            value: &user.email,
-        },
+// Create user attributes from database record, limited to those released to
+// the SP and named as configured for it
+fn create_user_attributes_from_db<'a>(
+    user: &'a User,
+    sp: &ServiceProvider,
+    request: &AttributeRequest,
+) -> Vec<ReleasedAttribute<'a>> {
+    // Core attributes
+    let mut values: Vec<(&str, &'a str)> = vec![
+        ("firstName", user.first_name.as_str()),
+        ("lastName", user.last_name.as_str()),
+        ("email", user.email.as_str()),
     ];
 
     // Add mobile phone if available
     if let Some(phone) = &user.mobile_phone {
-        attributes.push(ResponseAttribute {
-            required_attribute: RequiredAttribute {
-                name: "mobilePhone".to_string(),
-                format: Some("urn:oasis:names:tc:SAML:2.0:attrname-format:uri".to_string()),
-            },
-            value: phone,
-        });
+        values.push(("mobilePhone", phone.as_str()));
     }
 
     // Add any custom attributes from the user record
     if let Some(custom_attrs) = &user.attributes {
         for (name, value) in custom_attrs {
-            attributes.push(ResponseAttribute {
-                required_attribute: RequiredAttribute {
-                    name: name.clone(),
-                    format: Some("urn:oasis:names:tc:SAML:2.0:attrname-format:uri".to_string()),
-                },
-                value,
-            });
+            values.push((name.as_str(), value.as_str()));
         }
     }
 
-    attributes
+    values
+        .into_iter()
+        .filter_map(|(source, value)| {
+            let name = sp.attribute_name(source);
+            if !sp.releases_attribute(source, &name.name, request) {
+                debug!("Withholding attribute {} from SP {}", source, sp.entity_id);
+                return None;
+            }
+            Some(ReleasedAttribute { name, value })
+        })
+        .collect()
 }
-/// Fields to the sign_auth_response method
-struct SignAuthnResponseFields<'a> {
-    idp_x509_cert_der: &'a [u8],
-    subject_name_id: &'a str,
-    audience: &'a str,
-    acs_url: &'a str,
-    issuer: &'a str,
-    in_response_to_id: Option<String>,
-    attributes: &'a [ResponseAttribute<'a>],
+
+/// Signs a non-success Response and posts it to the SP
+pub fn send_error_response(
+    state: &AppState,
+    sp_entity_id: &str,
+    acs_url: &str,
+    in_response_to: Option<String>,
+    relay_state: &str,
+    status: &ErrorStatus,
+) -> HttpResponse {
+    let Some(sp) = state.service_providers.find(sp_entity_id) else {
+        error!("Service provider no longer registered: {}", sp_entity_id);
+        return HttpResponse::BadRequest()
+            .body(format!("Unknown service provider '{}'", sp_entity_id));
+    };
+    let key = state.signing_keys.active();
+    let signed = sign_error_response(
+        key.signer.as_ref(),
+        &key.cert_der,
+        &state.idp_entity_id,
+        acs_url,
+        in_response_to,
+        status,
+        key.signing_algorithms(&sp),
+    );
+    match signed {
+        Ok(response) => {
+            info!(
+                "Sending SAML error response to {}: {}",
+                acs_url, status.message
+            );
+            create_saml_post_form(&response, acs_url, relay_state)
+        }
+        Err(e) => {
+            error!("Failed to sign SAML error response: {}", e);
+            HttpResponse::InternalServerError()
+                .body(format!("Failed to create SAML response: {}", e))
+        }
+    }
 }
 
 // Custom function to handle response signing with extra options
 fn sign_authn_response_with_config(
-    idp: &IdentityProvider,
-    fields: SignAuthnResponseFields,
-) -> Result<Response, Box<dyn std::error::Error>> {
-    // Use the standard signing method which already returns a Response
-    let response = sign_authn_response(
-        idp,
-        fields.idp_x509_cert_der,
-        fields.subject_name_id,
-        fields.audience,
-        fields.acs_url,
-        fields.issuer,
-        fields.in_response_to_id,
-        fields.attributes,
-    )?;
+    signer: &dyn SigningBackend,
+    fields: &AuthnResponseFields,
+) -> Result<SignedResponse, Box<dyn std::error::Error>> {
+    // Use the standard signing method which already returns the signed XML
+    let response = sign_authn_response(signer, fields)?;
 
     debug!("Generated response ID: {}", response.id);
-    trace!("Response: {:?}", response);
+    trace!("Response: {}", response.xml);
     Ok(response)
 }
 
 // Helper function to create HTML form for POST binding
-fn create_saml_post_form(response: &Response, acs_url: &str, relay_state: &str) -> HttpResponse {
-    // Convert to XML and encode
-    let response_xml = response.to_string().unwrap();
-
-    // Log the final response XML for debugging
-    // debug!("Final signed response XML: {}", response_xml);
-    let encoded_response = general_purpose::STANDARD.encode(response_xml.as_bytes());
-
-    // Create auto-submit form for the browser
-    let form = format!(
-        r#"
-    <html>
-        <head>
-            <title>SAML Response</title>
-        </head>
-        <body>
-            <form method="post" action="{}" id="SAMLResponseForm">
-                <input type="hidden" name="SAMLResponse" value="{}" />
-                <input type="hidden" name="RelayState" value="{}" />
-                <input id="SAMLSubmitButton" type="submit" value="Submit" />
-            </form>
-            <script>
-                document.getElementById('SAMLSubmitButton').style.visibility="hidden";
-                document.getElementById('SAMLResponseForm').submit();
-            </script>
-        </body>
-    </html>
-    "#,
-        acs_url, encoded_response, relay_state
-    );
-
-    HttpResponse::Ok()
-        .content_type("text/html; charset=utf-8")
-        .body(form)
+fn create_saml_post_form(
+    response: &SignedResponse,
+    acs_url: &str,
+    relay_state: &str,
+) -> HttpResponse {
+    post_binding_form(acs_url, "SAMLResponse", &response.xml, relay_state)
 }
@@ -0,0 +1,113 @@
+use actix_web::{HttpResponse, Responder, web};
+use chrono::{DateTime, Utc};
+use log::{debug, error};
+use serde::Serialize;
+use std::fmt::Write as _;
+
+use crate::cert_expiry::{ExpiryLevel, days_to_expiry};
+use crate::key_rotation::KeyRole;
+use crate::models::state::AppState;
+
+#[derive(Serialize)]
+struct CertificateStatus {
+    role: KeyRole,
+    fingerprint: String,
+    expires_at: DateTime<Utc>,
+    days_to_expiry: i64,
+    level: ExpiryLevel,
+}
+
+#[derive(Serialize)]
+struct Status {
+    /// Expiry level of the active signing certificate
+    status: ExpiryLevel,
+    certificates: Vec<CertificateStatus>,
+}
+
+/// Reports the expiry of the published signing certificates as JSON. Answers
+/// with 503 once the active certificate has expired, so health checks fail.
+pub async fn status(state: web::Data<AppState>) -> impl Responder {
+    debug!("Serving IdP status");
+    let certificates = match state.signing_keys.certificate_validity() {
+        Ok(certificates) => certificates,
+        Err(e) => {
+            error!("Failed to read signing certificate validity: {}", e);
+            return HttpResponse::InternalServerError().body("Failed to read IdP status");
+        }
+    };
+
+    let now = Utc::now();
+    let certificates: Vec<_> = certificates
+        .into_iter()
+        .map(|certificate| CertificateStatus {
+            level: state.certificate_expiry.level(certificate.expires_at, now),
+            days_to_expiry: days_to_expiry(certificate.expires_at, now),
+            role: certificate.role,
+            fingerprint: certificate.fingerprint,
+            expires_at: certificate.expires_at,
+        })
+        .collect();
+    let Some(status) = certificates
+        .iter()
+        .find(|certificate| certificate.role == KeyRole::Active)
+        .map(|certificate| certificate.level)
+    else {
+        error!("No active signing certificate is published");
+        return HttpResponse::InternalServerError().body("Failed to read IdP status");
+    };
+
+    let mut response = if status == ExpiryLevel::Expired {
+        HttpResponse::ServiceUnavailable()
+    } else {
+        HttpResponse::Ok()
+    };
+    response.json(Status {
+        status,
+        certificates,
+    })
+}
+
+/// Exposes signing certificate expiry in the Prometheus text format
+pub async fn metrics(state: web::Data<AppState>) -> impl Responder {
+    debug!("Serving IdP metrics");
+    let certificates = match state.signing_keys.certificate_validity() {
+        Ok(certificates) => certificates,
+        Err(e) => {
+            error!("Failed to read signing certificate validity: {}", e);
+            return HttpResponse::InternalServerError().body("Failed to read IdP metrics");
+        }
+    };
+
+    let now = Utc::now();
+    let mut body = String::new();
+    body.push_str(
+        "# HELP idp_signing_certificate_expiry_timestamp_seconds Time at which the signing certificate expires\n\
+         # TYPE idp_signing_certificate_expiry_timestamp_seconds gauge\n",
+    );
+    for certificate in &certificates {
+        let _ = writeln!(
+            body,
+            "idp_signing_certificate_expiry_timestamp_seconds{{role=\"{}\",fingerprint=\"{}\"}} {}",
+            certificate.role.as_str(),
+            certificate.fingerprint,
+            certificate.expires_at.timestamp()
+        );
+    }
+    body.push_str(
+        "# HELP idp_signing_certificate_days_to_expiry Whole days until the signing certificate expires\n\
+         # TYPE idp_signing_certificate_days_to_expiry gauge\n",
+    );
+    for certificate in &certificates {
+        let _ = writeln!(
+            body,
+            "idp_signing_certificate_days_to_expiry{{role=\"{}\",fingerprint=\"{}\"}} {}",
+            certificate.role.as_str(),
+            certificate.fingerprint,
+            days_to_expiry(certificate.expires_at, now)
+        );
+    }
+
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(body)
+}
@@ -0,0 +1,793 @@
+use actix_web::web;
+use chrono::{DateTime, Duration, Utc};
+use log::{debug, error, info, warn};
+use openssl::pkey::Id;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+
+use crate::cert_util::{
+    CertificateChain, CertificateMode, CertificateProfile, IdpKeyType, KeyFiles,
+    certificate_expiry, certificate_fingerprint, generate_identity_provider,
+    load_certificate_chain, load_certificate_der, load_identity_provider,
+    load_or_create_identity_provider, persist_idp_identity, request_certificate,
+    write_certificate_chain,
+};
+use crate::models::service_provider::{
+    ServiceProvider, ServiceProviderRegistry, SigningAlgorithms,
+};
+use crate::models::state::AppState;
+use crate::pkcs11::Pkcs11Token;
+use crate::signing::{FileKey, SigningBackend, check_certificate};
+
+const SCHEDULE_FILE_NAME: &str = "idp_key_rotation.yaml";
+
+/// How often the rotation schedule is checked
+const SCHEDULE_CHECK_INTERVAL_SECS: u64 = 60;
+
+/// An IdP signing key and its certificate
+#[derive(Clone)]
+pub struct SigningKey {
+    pub signer: Arc<dyn SigningBackend>,
+    pub cert_der: Vec<u8>,
+    /// Intermediate CA certificates published with `cert_der`
+    pub intermediates_der: Vec<Vec<u8>>,
+    /// When `cert_der` stops being valid
+    pub expires_at: DateTime<Utc>,
+}
+
+impl SigningKey {
+    fn new(signer: Arc<dyn SigningBackend>, mut cert_chain: CertificateChain) -> io::Result<Self> {
+        let cert_der = cert_chain.remove(0);
+        Ok(Self {
+            signer,
+            expires_at: certificate_expiry(&cert_der)?,
+            cert_der,
+            intermediates_der: cert_chain,
+        })
+    }
+
+    fn cert_chain(&self) -> CertificateChain {
+        std::iter::once(self.cert_der.clone())
+            .chain(self.intermediates_der.iter().cloned())
+            .collect()
+    }
+
+    pub fn key_type(&self) -> Id {
+        self.signer.key_type()
+    }
+
+    /// Returns the signature and digest algorithms for messages sent to `sp`
+    /// with this key, defaulting the signature algorithm to the key type
+    pub fn signing_algorithms(&self, sp: &ServiceProvider) -> SigningAlgorithms {
+        sp.signing_algorithms(self.key_type())
+    }
+
+    pub fn from_files(files: &KeyFiles) -> io::Result<Self> {
+        let (idp, cert_chain) = load_identity_provider(files)?;
+        Self::new(Arc::new(FileKey::new(&idp)?), cert_chain)
+    }
+}
+
+/// Where the IdP private keys are kept. Certificates are always files.
+pub enum KeyStore {
+    /// Key files next to the certificates, generated by the IdP
+    Files,
+    /// A PKCS#11 token, where keys are created by an administrator. The active
+    /// key is labelled `key_label` and others get a suffix, such as
+    /// `idp-signing.next` for the staged key.
+    Pkcs11 {
+        token: Arc<Pkcs11Token>,
+        key_label: String,
+    },
+}
+
+impl KeyStore {
+    // Opens the key stored at `files`. `role` is the suffix of a non-active
+    // key's files and token label, as in `KeyFiles::sibling`.
+    fn open(&self, files: &KeyFiles, role: Option<&str>) -> io::Result<SigningKey> {
+        match self {
+            Self::Files => SigningKey::from_files(files),
+            Self::Pkcs11 { token, key_label } => {
+                let label = token_label(key_label, role);
+                if !files.cert_path.exists() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::NotFound,
+                        format!(
+                            "No certificate for the PKCS#11 key {}. Save it as {}",
+                            label,
+                            files.cert_path.display()
+                        ),
+                    ));
+                }
+                let key = token.key(&label)?;
+                let cert_chain = load_certificate_chain(&files.cert_path)?;
+                check_certificate(&key, &cert_chain[0])?;
+                SigningKey::new(Arc::new(key), cert_chain)
+            }
+        }
+    }
+
+    // Whether a key and its certificate are stored at `files`
+    fn contains(&self, files: &KeyFiles, role: Option<&str>) -> io::Result<bool> {
+        match self {
+            Self::Files => Ok(files.exist()),
+            Self::Pkcs11 { token, key_label } => {
+                Ok(files.cert_path.exists() && token.has_key(&token_label(key_label, role))?)
+            }
+        }
+    }
+}
+
+fn token_label(key_label: &str, role: Option<&str>) -> String {
+    match role {
+        Some(role) => format!("{}.{}", key_label, role),
+        None => key_label.to_string(),
+    }
+}
+
+// Times of the last rotation steps, kept on disk so the schedule survives restarts
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct RotationSchedule {
+    #[serde(skip)]
+    path: PathBuf,
+    activated_at: Option<DateTime<Utc>>,
+    staged_at: Option<DateTime<Utc>>,
+    previous_retires_at: Option<DateTime<Utc>>,
+}
+
+impl RotationSchedule {
+    fn load(path: PathBuf) -> io::Result<Self> {
+        let schedule: Self = match fs::read_to_string(&path) {
+            Ok(contents) => serde_yaml::from_str(&contents)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Self::default(),
+            Err(e) => return Err(e),
+        };
+        Ok(Self { path, ..schedule })
+    }
+
+    fn save(&self) -> io::Result<()> {
+        let contents = serde_yaml::to_string(self).map_err(io::Error::other)?;
+        fs::write(&self.path, contents)
+    }
+}
+
+struct KeySet {
+    active: SigningKey,
+    staged: Option<SigningKey>,
+    previous_cert_der: Option<Vec<u8>>,
+    schedule: RotationSchedule,
+}
+
+/// Current state of the signing keys, as shown to administrators
+#[derive(Debug, Serialize)]
+pub struct KeyRingStatus {
+    pub active_fingerprint: String,
+    pub active_since: Option<DateTime<Utc>>,
+    pub active_expires_at: DateTime<Utc>,
+    /// When a replacement key is staged automatically
+    pub next_rotation_at: Option<DateTime<Utc>>,
+    pub staged_fingerprint: Option<String>,
+    pub staged_activates_at: Option<DateTime<Utc>>,
+    pub previous_fingerprint: Option<String>,
+    pub previous_retires_at: Option<DateTime<Utc>>,
+}
+
+/// Which of the published signing certificates a certificate is
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum KeyRole {
+    Active,
+    Staged,
+    Previous,
+}
+
+impl KeyRole {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Active => "active",
+            Self::Staged => "staged",
+            Self::Previous => "previous",
+        }
+    }
+}
+
+/// Validity of a published signing certificate
+#[derive(Debug, Clone)]
+pub struct CertificateValidity {
+    pub role: KeyRole,
+    pub fingerprint: String,
+    pub expires_at: DateTime<Utc>,
+}
+
+/// The IdP signing keys and their rotation.
+///
+/// A new key is first staged: its certificate is published in metadata next to
+/// the active one, so SPs can pick it up before it is used. After the overlap
+/// period, or when an administrator asks, it becomes the active key. The
+/// certificate of the key it replaced stays published for another overlap
+/// period, and is then retired.
+pub struct KeyRing {
+    keys: RwLock<KeySet>,
+    /// Files of the active key. The staged key and previous certificate are
+    /// kept next to them
+    files: KeyFiles,
+    key_store: KeyStore,
+    /// Type of newly generated keys. Existing keys keep their type until rotated
+    key_type: IdpKeyType,
+    /// How newly generated keys get their certificate
+    certificate_profile: CertificateProfile,
+    overlap: Duration,
+    /// Age of the active key at which a replacement is staged automatically
+    rotation_interval: Option<Duration>,
+    /// Time before the active certificate expires at which a replacement is
+    /// staged automatically
+    rotate_before_expiry: Option<Duration>,
+}
+
+impl KeyRing {
+    /// Loads the active key, creating it on first start, along with any staged
+    /// key and previous certificate left by an unfinished rotation
+    pub fn load(
+        files: KeyFiles,
+        key_store: KeyStore,
+        key_type: IdpKeyType,
+        certificate_profile: CertificateProfile,
+        overlap: Duration,
+        rotation_interval: Option<Duration>,
+        rotate_before_expiry: Option<Duration>,
+    ) -> io::Result<Self> {
+        let active = match &key_store {
+            KeyStore::Files => {
+                let (idp, cert_chain) =
+                    load_or_create_identity_provider(&files, key_type, &certificate_profile)?;
+                SigningKey::new(Arc::new(FileKey::new(&idp)?), cert_chain)?
+            }
+            KeyStore::Pkcs11 { .. } => key_store.open(&files, None)?,
+        };
+
+        let staged_files = files.sibling("next");
+        let staged = if key_store.contains(&staged_files, Some("next"))? {
+            info!("Loading staged IdP signing key");
+            Some(key_store.open(&staged_files, Some("next"))?)
+        } else {
+            None
+        };
+
+        let previous_cert_path = files.sibling("previous").cert_path;
+        let previous_cert_der = if previous_cert_path.exists() {
+            info!("Loading previous IdP signing certificate");
+            Some(load_certificate_der(&previous_cert_path)?)
+        } else {
+            None
+        };
+
+        // Fill in times missing from the schedule, such as for keys created
+        // before rotation was set up, counting from now
+        let now = Utc::now();
+        let mut schedule =
+            RotationSchedule::load(files.cert_path.with_file_name(SCHEDULE_FILE_NAME))?;
+        schedule.activated_at.get_or_insert(now);
+        schedule.staged_at = staged.as_ref().map(|_| schedule.staged_at.unwrap_or(now));
+        schedule.previous_retires_at = previous_cert_der
+            .as_ref()
+            .map(|_| schedule.previous_retires_at.unwrap_or(now + overlap));
+        schedule.save()?;
+
+        Ok(Self {
+            keys: RwLock::new(KeySet {
+                active,
+                staged,
+                previous_cert_der,
+                schedule,
+            }),
+            files,
+            key_store,
+            key_type,
+            certificate_profile,
+            overlap,
+            rotation_interval,
+            rotate_before_expiry,
+        })
+    }
+
+    /// The key used to sign messages
+    pub fn active(&self) -> SigningKey {
+        self.keys.read().unwrap().active.clone()
+    }
+
+    /// Certificate chains published in metadata: the active one, followed by
+    /// the staged and previous certificates while they overlap with it
+    pub fn published_certificates(&self) -> Vec<CertificateChain> {
+        let keys = self.keys.read().unwrap();
+        std::iter::once(keys.active.cert_chain())
+            .chain(keys.staged.as_ref().map(SigningKey::cert_chain))
+            .chain(
+                keys.previous_cert_der
+                    .clone()
+                    .map(|cert_der| vec![cert_der]),
+            )
+            .collect()
+    }
+
+    /// Generates a new key and publishes its certificate, without signing
+    /// with it yet. In CSR mode only a certificate signing request is written;
+    /// the key is staged once its issued certificate has been saved.
+    pub fn stage(&self) -> io::Result<()> {
+        if self.keys.read().unwrap().staged.is_some() {
+            return Err(already_staged());
+        }
+
+        let staged_files = self.files.sibling("next");
+        if let KeyStore::Pkcs11 { key_label, .. } = &self.key_store {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!(
+                    "Keys in a PKCS#11 token are created outside the IdP. Create a key labelled \
+                     {}.next and save its certificate as {} to stage it",
+                    key_label,
+                    staged_files.cert_path.display()
+                ),
+            ));
+        }
+        if self.certificate_profile.mode == CertificateMode::Csr {
+            if staged_files.key_path.exists() {
+                return Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    "A certificate signing request for the next signing key is pending",
+                ));
+            }
+            let csr_path =
+                request_certificate(&staged_files, self.key_type, &self.certificate_profile)?;
+            info!(
+                "Next signing key is staged once the certificate for {} is saved as {}",
+                csr_path.display(),
+                staged_files.cert_path.display()
+            );
+            return Ok(());
+        }
+
+        // Key generation is slow, so signing carries on with the current keys
+        // until the new one is ready
+        let (idp, cert_chain) =
+            generate_identity_provider(self.key_type, &self.certificate_profile)?;
+        let fingerprint = certificate_fingerprint(&cert_chain[0])?;
+        let staged = SigningKey::new(Arc::new(FileKey::new(&idp)?), cert_chain.clone())?;
+
+        let mut keys = self.keys.write().unwrap();
+        if keys.staged.is_some() {
+            return Err(already_staged());
+        }
+        persist_idp_identity(&idp, &cert_chain, &staged_files)?;
+        let now = Utc::now();
+        keys.schedule.staged_at = Some(now);
+        keys.schedule.save()?;
+
+        info!(
+            "Staged new signing key {}, to be activated at {}",
+            fingerprint,
+            now + self.overlap
+        );
+        keys.staged = Some(staged);
+        Ok(())
+    }
+
+    /// Switches signing to the staged key. The replaced certificate stays
+    /// published until the overlap period has passed. The previous certificate
+    /// has to be retired first, so it is never overwritten while published, and
+    /// the staged key must suit the signature algorithm configured for each SP.
+    pub fn activate(&self, service_providers: &ServiceProviderRegistry) -> io::Result<()> {
+        let mut keys = self.keys.write().unwrap();
+        let Some(staged) = keys.staged.clone() else {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                "No signing key is staged",
+            ));
+        };
+        if keys.previous_cert_der.is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                "The previous signing certificate must be retired before activating the staged key",
+            ));
+        }
+        for sp in service_providers.all() {
+            sp.check_idp_key_type(staged.key_type())
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string()))?;
+        }
+        let fingerprint = certificate_fingerprint(&staged.cert_der)?;
+        let now = Utc::now();
+        if keys
+            .schedule
+            .staged_at
+            .is_some_and(|staged_at| staged_at + self.overlap > now)
+        {
+            warn!("Activating the staged signing key before the overlap period has passed");
+        }
+
+        let staged_files = self.files.sibling("next");
+        write_certificate_chain(
+            &self.files.sibling("previous").cert_path,
+            &[keys.active.cert_der.clone()],
+        )?;
+        match &self.key_store {
+            KeyStore::Files => fs::rename(&staged_files.key_path, &self.files.key_path)?,
+            KeyStore::Pkcs11 { token, key_label } => {
+                // A key still labelled as previous, such as one left by an
+                // interrupted retirement, is retired now
+                let previous_label = token_label(key_label, Some("previous"));
+                if token.has_key(&previous_label)? {
+                    token.relabel_key(&previous_label, &retired_token_label(key_label))?;
+                }
+                token.relabel_key(key_label, &previous_label)?;
+                token.relabel_key(&token_label(key_label, Some("next")), key_label)?;
+            }
+        }
+        fs::rename(&staged_files.cert_path, &self.files.cert_path)?;
+
+        let previous = std::mem::replace(&mut keys.active, staged);
+        keys.staged = None;
+        keys.previous_cert_der = Some(previous.cert_der);
+        keys.schedule.activated_at = Some(now);
+        keys.schedule.staged_at = None;
+        keys.schedule.previous_retires_at = Some(now + self.overlap);
+        keys.schedule.save()?;
+
+        info!("Activated signing key {}", fingerprint);
+        Ok(())
+    }
+
+    /// Stops publishing the certificate of the previous key
+    pub fn retire(&self) -> io::Result<()> {
+        let mut keys = self.keys.write().unwrap();
+        let Some(previous_cert_der) = &keys.previous_cert_der else {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                "No previous signing certificate is published",
+            ));
+        };
+        let fingerprint = certificate_fingerprint(previous_cert_der)?;
+        match fs::remove_file(self.files.sibling("previous").cert_path) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+        // Token keys are not destroyed automatically. A retired key keeps a
+        // dated label until an administrator deletes it.
+        if let KeyStore::Pkcs11 { token, key_label } = &self.key_store {
+            let previous_label = token_label(key_label, Some("previous"));
+            if token.has_key(&previous_label)? {
+                token.relabel_key(&previous_label, &retired_token_label(key_label))?;
+            }
+        }
+        keys.previous_cert_der = None;
+        keys.schedule.previous_retires_at = None;
+        keys.schedule.save()?;
+
+        info!("Retired signing certificate {}", fingerprint);
+        Ok(())
+    }
+
+    // Stages a key provided from outside the IdP: one whose certificate was
+    // issued for a pending certificate signing request, or a token key
+    fn stage_provided_key(&self) -> io::Result<()> {
+        let staged_files = self.files.sibling("next");
+        let mut keys = self.keys.write().unwrap();
+        if keys.staged.is_some() || !self.key_store.contains(&staged_files, Some("next"))? {
+            return Ok(());
+        }
+
+        let staged = self.key_store.open(&staged_files, Some("next"))?;
+        let fingerprint = certificate_fingerprint(&staged.cert_der)?;
+        let now = Utc::now();
+        keys.schedule.staged_at = Some(now);
+        keys.schedule.save()?;
+
+        info!(
+            "Staged provided signing key {}, to be activated at {}",
+            fingerprint,
+            now + self.overlap
+        );
+        keys.staged = Some(staged);
+        Ok(())
+    }
+
+    /// Performs the rotation steps that are due
+    pub fn run_schedule(&self, service_providers: &ServiceProviderRegistry) -> io::Result<()> {
+        if self.certificate_profile.mode == CertificateMode::Csr
+            || matches!(self.key_store, KeyStore::Pkcs11 { .. })
+        {
+            self.stage_provided_key()?;
+        }
+
+        let now = Utc::now();
+        let (retire_due, activate_due, stage_due) = {
+            let keys = self.keys.read().unwrap();
+            let schedule = &keys.schedule;
+            (
+                schedule
+                    .previous_retires_at
+                    .is_some_and(|retires_at| retires_at <= now),
+                schedule
+                    .staged_at
+                    .is_some_and(|staged_at| staged_at + self.overlap <= now),
+                keys.staged.is_none()
+                    && matches!(self.key_store, KeyStore::Files)
+                    && !self.files.sibling("next").key_path.exists()
+                    && self
+                        .next_rotation_at(&keys)
+                        .is_some_and(|rotation_at| rotation_at <= now),
+            )
+        };
+
+        if retire_due {
+            self.retire()?;
+        }
+        if activate_due {
+            self.activate(service_providers)?;
+        }
+        if stage_due {
+            debug!("Active signing key reached the rotation interval");
+            self.stage()?;
+        }
+        Ok(())
+    }
+
+    pub fn status(&self) -> io::Result<KeyRingStatus> {
+        let keys = self.keys.read().unwrap();
+        Ok(KeyRingStatus {
+            active_fingerprint: certificate_fingerprint(&keys.active.cert_der)?,
+            active_since: keys.schedule.activated_at,
+            active_expires_at: keys.active.expires_at,
+            next_rotation_at: keys
+                .staged
+                .is_none()
+                .then(|| self.next_rotation_at(&keys))
+                .flatten(),
+            staged_fingerprint: keys
+                .staged
+                .as_ref()
+                .map(|key| certificate_fingerprint(&key.cert_der))
+                .transpose()?,
+            staged_activates_at: keys
+                .schedule
+                .staged_at
+                .map(|staged_at| staged_at + self.overlap),
+            previous_fingerprint: keys
+                .previous_cert_der
+                .as_deref()
+                .map(certificate_fingerprint)
+                .transpose()?,
+            previous_retires_at: keys.schedule.previous_retires_at,
+        })
+    }
+
+    /// Validity of each published certificate, the active one first
+    pub fn certificate_validity(&self) -> io::Result<Vec<CertificateValidity>> {
+        let keys = self.keys.read().unwrap();
+        let mut certificates = vec![(KeyRole::Active, &keys.active.cert_der)];
+        certificates.extend(
+            keys.staged
+                .as_ref()
+                .map(|key| (KeyRole::Staged, &key.cert_der)),
+        );
+        certificates.extend(
+            keys.previous_cert_der
+                .as_ref()
+                .map(|cert_der| (KeyRole::Previous, cert_der)),
+        );
+        certificates
+            .into_iter()
+            .map(|(role, cert_der)| {
+                Ok(CertificateValidity {
+                    role,
+                    fingerprint: certificate_fingerprint(cert_der)?,
+                    expires_at: certificate_expiry(cert_der)?,
+                })
+            })
+            .collect()
+    }
+
+    // The earlier of the rotation interval and the expiry margin, if set
+    fn next_rotation_at(&self, keys: &KeySet) -> Option<DateTime<Utc>> {
+        let by_age = keys
+            .schedule
+            .activated_at
+            .zip(self.rotation_interval)
+            .map(|(activated_at, interval)| activated_at + interval);
+        let by_expiry = self
+            .rotate_before_expiry
+            .map(|margin| keys.active.expires_at - margin);
+        by_age.into_iter().chain(by_expiry).min()
+    }
+}
+
+fn already_staged() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::AlreadyExists,
+        "A signing key is already staged",
+    )
+}
+
+// Label given to a token key when it is retired, dated so retired keys do not
+// clash
+fn retired_token_label(key_label: &str) -> String {
+    format!(
+        "{}.retired-{}",
+        key_label,
+        Utc::now().format("%Y%m%d%H%M%S")
+    )
+}
+
+/// Periodically performs due key rotation steps without restarting the server
+pub fn spawn_key_rotation(state: web::Data<AppState>) {
+    tokio::spawn(async move {
+        let mut interval =
+            tokio::time::interval(std::time::Duration::from_secs(SCHEDULE_CHECK_INTERVAL_SECS));
+        loop {
+            interval.tick().await;
+            // Rotation reads and writes key files and may generate a key
+            let state = state.clone();
+            match tokio::task::spawn_blocking(move || {
+                state.signing_keys.run_schedule(&state.service_providers)
+            })
+            .await
+            {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => error!("Signing key rotation failed: {}", e),
+                Err(e) => error!("Signing key rotation task failed: {}", e),
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use uuid::Uuid;
+
+    fn load_key_ring(dir: &std::path::Path) -> KeyRing {
+        KeyRing::load(
+            KeyFiles {
+                key_path: dir.join("idp_private_key.der"),
+                cert_path: dir.join("idp_certificate.der"),
+                passphrase: None,
+            },
+            KeyStore::Files,
+            IdpKeyType::EcdsaP256,
+            CertificateProfile::default(),
+            Duration::hours(24),
+            None,
+            None,
+        )
+        .unwrap()
+    }
+
+    fn temporary_dir() -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("key-rotation-test-{}", Uuid::new_v4().simple()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn roles(key_ring: &KeyRing) -> Vec<KeyRole> {
+        key_ring
+            .certificate_validity()
+            .unwrap()
+            .into_iter()
+            .map(|certificate| certificate.role)
+            .collect()
+    }
+
+    #[test]
+    fn rotation_moves_keys_through_each_role() {
+        let dir = temporary_dir();
+        let key_ring = load_key_ring(&dir);
+        let original = key_ring.active().cert_der;
+        assert_eq!(roles(&key_ring), [KeyRole::Active]);
+
+        key_ring.stage().unwrap();
+        let staged = key_ring.status().unwrap().staged_fingerprint.unwrap();
+        assert_eq!(roles(&key_ring), [KeyRole::Active, KeyRole::Staged]);
+        assert_eq!(
+            key_ring.stage().unwrap_err().kind(),
+            io::ErrorKind::AlreadyExists
+        );
+
+        key_ring
+            .activate(&ServiceProviderRegistry::default())
+            .unwrap();
+        let status = key_ring.status().unwrap();
+        assert_eq!(status.active_fingerprint, staged);
+        assert_eq!(
+            status.previous_fingerprint,
+            Some(certificate_fingerprint(&original).unwrap())
+        );
+        assert_eq!(roles(&key_ring), [KeyRole::Active, KeyRole::Previous]);
+
+        key_ring.retire().unwrap();
+        assert_eq!(roles(&key_ring), [KeyRole::Active]);
+        assert_eq!(
+            key_ring.retire().unwrap_err().kind(),
+            io::ErrorKind::NotFound
+        );
+
+        // The rotated keys are picked up again after a restart
+        let reloaded = load_key_ring(&dir);
+        assert_eq!(reloaded.status().unwrap().active_fingerprint, staged);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn activate_requires_a_staged_key() {
+        let dir = temporary_dir();
+        let key_ring = load_key_ring(&dir);
+        assert_eq!(
+            key_ring
+                .activate(&ServiceProviderRegistry::default())
+                .unwrap_err()
+                .kind(),
+            io::ErrorKind::NotFound
+        );
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn activate_refuses_while_previous_certificate_is_published() {
+        let dir = temporary_dir();
+        let key_ring = load_key_ring(&dir);
+        key_ring.stage().unwrap();
+        key_ring
+            .activate(&ServiceProviderRegistry::default())
+            .unwrap();
+        key_ring.stage().unwrap();
+        let active = key_ring.status().unwrap().active_fingerprint;
+
+        assert_eq!(
+            key_ring
+                .activate(&ServiceProviderRegistry::default())
+                .unwrap_err()
+                .kind(),
+            io::ErrorKind::AlreadyExists
+        );
+        assert_eq!(key_ring.status().unwrap().active_fingerprint, active);
+        assert_eq!(
+            roles(&key_ring),
+            [KeyRole::Active, KeyRole::Staged, KeyRole::Previous]
+        );
+
+        key_ring.retire().unwrap();
+        key_ring
+            .activate(&ServiceProviderRegistry::default())
+            .unwrap();
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn activate_refuses_a_key_an_sp_cannot_use() {
+        let dir = temporary_dir();
+        let key_ring = load_key_ring(&dir);
+        key_ring.stage().unwrap();
+        let service_providers = ServiceProviderRegistry::default();
+        service_providers
+            .register(
+                serde_yaml::from_str(
+                    "entity_id: https://sp.example.com\n\
+                     acs_endpoints:\n\
+                     - location: https://sp.example.com/acs\n\
+                     signature_algorithm: rsa-sha256\n",
+                )
+                .unwrap(),
+            )
+            .unwrap();
+
+        assert_eq!(
+            key_ring.activate(&service_providers).unwrap_err().kind(),
+            io::ErrorKind::InvalidInput
+        );
+        assert!(key_ring.status().unwrap().staged_fingerprint.is_some());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
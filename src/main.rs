@@ -4,10 +4,15 @@ use env_logger::Env;
 use log::{debug, error, info};
 use std::env;
 
+mod cert_expiry;
 mod cert_util;
 mod config;
 mod handlers;
+mod key_rotation;
 mod models;
+mod pkcs11;
+mod signing;
+mod sp_metadata;
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
@@ -21,7 +26,7 @@ async fn main() -> std::io::Result<()> {
     info!("Starting SAML IdP server");
 
     // Create application state
-    let app_state = match config::create_app_state() {
+    let app_state = match config::create_app_state().await {
         Ok(state) => {
             debug!("Application state created successfully");
             state
@@ -51,6 +56,9 @@ async fn main() -> std::io::Result<()> {
             .route("/", web::get().to(handlers::landing::index))
             .route("/sso", web::get().to(handlers::sso::handle_sso))
             .route("/sso", web::post().to(handlers::sso::handle_sso))
+            .route("/login", web::post().to(handlers::login::handle_login))
+            .route("/slo", web::get().to(handlers::slo::handle_slo))
+            .route("/slo", web::post().to(handlers::slo::handle_slo))
             .route(
                 "/idp-init",
                 web::get().to(handlers::sso::handle_idp_initiated_sso),
@@ -64,6 +72,21 @@ async fn main() -> std::io::Result<()> {
                 "/certificate/der",
                 web::get().to(handlers::metadata::certificate_der),
             )
+            .route("/status", web::get().to(handlers::status::status))
+            .route("/metrics", web::get().to(handlers::status::metrics))
+            .route("/admin/keys", web::get().to(handlers::admin::key_status))
+            .route(
+                "/admin/keys/stage",
+                web::post().to(handlers::admin::stage_key),
+            )
+            .route(
+                "/admin/keys/activate",
+                web::post().to(handlers::admin::activate_key),
+            )
+            .route(
+                "/admin/keys/retire",
+                web::post().to(handlers::admin::retire_key),
+            )
     })
     .bind(&server_addr)?
     .workers(1)
@@ -0,0 +1,271 @@
+use serde::Deserialize;
+
+/// `NameFormat` of a released attribute
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum AttributeNameFormat {
+    #[default]
+    Uri,
+    Basic,
+    Unspecified,
+}
+
+impl AttributeNameFormat {
+    pub fn uri(self) -> &'static str {
+        match self {
+            Self::Uri => "urn:oasis:names:tc:SAML:2.0:attrname-format:uri",
+            Self::Basic => "urn:oasis:names:tc:SAML:2.0:attrname-format:basic",
+            Self::Unspecified => "urn:oasis:names:tc:SAML:2.0:attrname-format:unspecified",
+        }
+    }
+}
+
+/// Built-in set of names user attributes are released under
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum AttributeProfile {
+    /// The user attribute names, such as `firstName`, with the `uri` format
+    #[default]
+    Default,
+    /// The user attribute names with the `basic` format
+    Basic,
+    /// X.500/LDAP OIDs, such as `urn:oid:2.5.4.42` for `givenName`
+    X500,
+    /// The X.500 OIDs plus the eduPerson OIDs for custom attributes named
+    /// after eduPerson attributes, such as `eduPersonPrincipalName`
+    Eduperson,
+    /// Claim URIs used by Microsoft Entra ID (Azure AD)
+    Azure,
+}
+
+// (user attribute, Name, FriendlyName)
+type ProfileEntry = (&'static str, &'static str, &'static str);
+
+const X500_ATTRIBUTES: &[ProfileEntry] = &[
+    ("firstName", "urn:oid:2.5.4.42", "givenName"),
+    ("lastName", "urn:oid:2.5.4.4", "sn"),
+    ("email", "urn:oid:0.9.2342.19200300.100.1.3", "mail"),
+    (
+        "mobilePhone",
+        "urn:oid:0.9.2342.19200300.100.1.41",
+        "mobile",
+    ),
+];
+
+const EDUPERSON_ATTRIBUTES: &[ProfileEntry] = &[
+    (
+        "eduPersonAffiliation",
+        "urn:oid:1.3.6.1.4.1.5923.1.1.1.1",
+        "eduPersonAffiliation",
+    ),
+    (
+        "eduPersonPrincipalName",
+        "urn:oid:1.3.6.1.4.1.5923.1.1.1.6",
+        "eduPersonPrincipalName",
+    ),
+    (
+        "eduPersonEntitlement",
+        "urn:oid:1.3.6.1.4.1.5923.1.1.1.7",
+        "eduPersonEntitlement",
+    ),
+    (
+        "eduPersonScopedAffiliation",
+        "urn:oid:1.3.6.1.4.1.5923.1.1.1.9",
+        "eduPersonScopedAffiliation",
+    ),
+    (
+        "eduPersonUniqueId",
+        "urn:oid:1.3.6.1.4.1.5923.1.1.1.13",
+        "eduPersonUniqueId",
+    ),
+];
+
+const AZURE_ATTRIBUTES: &[ProfileEntry] = &[
+    (
+        "firstName",
+        "http://schemas.xmlsoap.org/ws/2005/05/identity/claims/givenname",
+        "givenname",
+    ),
+    (
+        "lastName",
+        "http://schemas.xmlsoap.org/ws/2005/05/identity/claims/surname",
+        "surname",
+    ),
+    (
+        "email",
+        "http://schemas.xmlsoap.org/ws/2005/05/identity/claims/emailaddress",
+        "emailaddress",
+    ),
+    (
+        "mobilePhone",
+        "http://schemas.xmlsoap.org/ws/2005/05/identity/claims/mobilephone",
+        "mobilephone",
+    ),
+];
+
+impl AttributeProfile {
+    fn name_format(self) -> AttributeNameFormat {
+        match self {
+            Self::Default | Self::X500 | Self::Eduperson => AttributeNameFormat::Uri,
+            Self::Basic => AttributeNameFormat::Basic,
+            Self::Azure => AttributeNameFormat::Unspecified,
+        }
+    }
+
+    fn entries(self) -> impl Iterator<Item = &'static ProfileEntry> {
+        let entries: &[&[ProfileEntry]] = match self {
+            Self::Default | Self::Basic => &[],
+            Self::X500 => &[X500_ATTRIBUTES],
+            Self::Eduperson => &[X500_ATTRIBUTES, EDUPERSON_ATTRIBUTES],
+            Self::Azure => &[AZURE_ATTRIBUTES],
+        };
+        entries.iter().flat_map(|entries| entries.iter())
+    }
+
+    /// The name the user attribute `source` is released under. Attributes
+    /// the profile does not cover keep their own name.
+    pub fn attribute_name(self, source: &str) -> AttributeName {
+        match self
+            .entries()
+            .find(|(entry_source, _, _)| *entry_source == source)
+        {
+            Some((_, name, friendly_name)) => AttributeName {
+                name: name.to_string(),
+                name_format: self.name_format(),
+                friendly_name: Some(friendly_name.to_string()),
+            },
+            None => AttributeName {
+                name: source.to_string(),
+                name_format: self.name_format(),
+                friendly_name: None,
+            },
+        }
+    }
+}
+
+/// Releases a user attribute under a name chosen for one SP
+#[derive(Debug, Deserialize, Clone)]
+pub struct AttributeMapping {
+    /// `firstName`, `lastName`, `email`, `mobilePhone` or a custom attribute
+    pub source: String,
+    pub name: String,
+    /// Defaults to the format of the SP's attribute profile
+    pub name_format: Option<AttributeNameFormat>,
+    pub friendly_name: Option<String>,
+}
+
+/// The `Name`, `NameFormat` and `FriendlyName` of a released attribute
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AttributeName {
+    pub name: String,
+    pub name_format: AttributeNameFormat,
+    pub friendly_name: Option<String>,
+}
+
+/// Resolves the name of the user attribute `source`, from an explicit mapping
+/// if there is one and from `profile` otherwise
+pub fn resolve_attribute_name(
+    profile: AttributeProfile,
+    mappings: &[AttributeMapping],
+    source: &str,
+) -> AttributeName {
+    match mappings.iter().find(|mapping| mapping.source == source) {
+        Some(mapping) => AttributeName {
+            name: mapping.name.clone(),
+            name_format: mapping.name_format.unwrap_or(profile.name_format()),
+            friendly_name: mapping.friendly_name.clone(),
+        },
+        None => profile.attribute_name(source),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_profile_keeps_attribute_names() {
+        let name = resolve_attribute_name(AttributeProfile::Default, &[], "firstName");
+        assert_eq!(
+            name,
+            AttributeName {
+                name: "firstName".to_string(),
+                name_format: AttributeNameFormat::Uri,
+                friendly_name: None,
+            }
+        );
+        assert_eq!(
+            resolve_attribute_name(AttributeProfile::Basic, &[], "firstName").name_format,
+            AttributeNameFormat::Basic
+        );
+    }
+
+    #[test]
+    fn x500_profile_uses_oids() {
+        let name = resolve_attribute_name(AttributeProfile::X500, &[], "email");
+        assert_eq!(name.name, "urn:oid:0.9.2342.19200300.100.1.3");
+        assert_eq!(name.name_format, AttributeNameFormat::Uri);
+        assert_eq!(name.friendly_name.as_deref(), Some("mail"));
+
+        // eduPerson attributes are only mapped by the eduperson profile
+        let custom = resolve_attribute_name(AttributeProfile::X500, &[], "eduPersonAffiliation");
+        assert_eq!(custom.name, "eduPersonAffiliation");
+        assert_eq!(custom.friendly_name, None);
+    }
+
+    #[test]
+    fn eduperson_profile_includes_x500_oids() {
+        let given_name = resolve_attribute_name(AttributeProfile::Eduperson, &[], "firstName");
+        assert_eq!(given_name.name, "urn:oid:2.5.4.42");
+        let principal =
+            resolve_attribute_name(AttributeProfile::Eduperson, &[], "eduPersonPrincipalName");
+        assert_eq!(principal.name, "urn:oid:1.3.6.1.4.1.5923.1.1.1.6");
+        assert_eq!(
+            principal.friendly_name.as_deref(),
+            Some("eduPersonPrincipalName")
+        );
+    }
+
+    #[test]
+    fn azure_profile_uses_claim_uris() {
+        let name = resolve_attribute_name(AttributeProfile::Azure, &[], "lastName");
+        assert_eq!(
+            name.name,
+            "http://schemas.xmlsoap.org/ws/2005/05/identity/claims/surname"
+        );
+        assert_eq!(name.name_format, AttributeNameFormat::Unspecified);
+        let unmapped = resolve_attribute_name(AttributeProfile::Azure, &[], "department");
+        assert_eq!(unmapped.name, "department");
+        assert_eq!(unmapped.name_format, AttributeNameFormat::Unspecified);
+    }
+
+    #[test]
+    fn mappings_take_precedence_over_the_profile() {
+        let mappings = [
+            AttributeMapping {
+                source: "email".to_string(),
+                name: "mail".to_string(),
+                name_format: Some(AttributeNameFormat::Basic),
+                friendly_name: None,
+            },
+            AttributeMapping {
+                source: "firstName".to_string(),
+                name: "given".to_string(),
+                name_format: None,
+                friendly_name: Some("Given name".to_string()),
+            },
+        ];
+        let email = resolve_attribute_name(AttributeProfile::X500, &mappings, "email");
+        assert_eq!(email.name, "mail");
+        assert_eq!(email.name_format, AttributeNameFormat::Basic);
+        assert_eq!(email.friendly_name, None);
+
+        let first_name = resolve_attribute_name(AttributeProfile::Azure, &mappings, "firstName");
+        assert_eq!(first_name.name, "given");
+        assert_eq!(first_name.name_format, AttributeNameFormat::Unspecified);
+        assert_eq!(first_name.friendly_name.as_deref(), Some("Given name"));
+
+        let last_name = resolve_attribute_name(AttributeProfile::X500, &mappings, "lastName");
+        assert_eq!(last_name.name, "urn:oid:2.5.4.4");
+    }
+}
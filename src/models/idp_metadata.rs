@@ -0,0 +1,90 @@
+use chrono::Duration;
+
+use crate::key_rotation::SigningKey;
+
+/// How the IdP publishes its metadata document
+pub struct IdpMetadataConfig {
+    /// Sign the metadata, with `signing_key` or else the active IdP key
+    pub sign: bool,
+    /// Key kept apart from the message signing keys for metadata only, so
+    /// partners can pin it across IdP key rotations
+    pub signing_key: Option<SigningKey>,
+    /// How long served metadata stays valid, published as `validUntil`
+    pub validity: Option<Duration>,
+    /// How long partners may cache the metadata, published as `cacheDuration`
+    pub cache_duration: Option<Duration>,
+    /// NameID formats advertised in the metadata, a subset of those supported
+    pub name_id_formats: Vec<String>,
+    pub organization: Option<MetadataOrganization>,
+    pub contacts: Vec<MetadataContact>,
+}
+
+/// Formats a duration as an `xs:duration`, such as `PT86400S`
+pub fn xml_duration(duration: Duration) -> String {
+    format!("PT{}S", duration.num_seconds())
+}
+
+/// The organization running the IdP, published as `md:Organization`
+pub struct MetadataOrganization {
+    pub name: String,
+    pub display_name: String,
+    pub url: String,
+}
+
+/// A contact published as `md:ContactPerson`
+#[derive(Debug, Clone)]
+pub struct MetadataContact {
+    pub contact_type: ContactType,
+    pub name: Option<String>,
+    pub email: String,
+}
+
+impl MetadataContact {
+    /// Parses a contact written as `Name <email>` or as a bare email address
+    pub fn parse(contact_type: ContactType, value: &str) -> Result<Self, String> {
+        let value = value.trim();
+        let (name, email) = match value.strip_suffix('>').and_then(|v| v.split_once('<')) {
+            Some((name, email)) => (Some(name.trim()).filter(|name| !name.is_empty()), email),
+            None => (None, value),
+        };
+        let email = email.trim();
+        if !email.contains('@') || email.contains(char::is_whitespace) {
+            return Err(format!("Invalid contact email address: {}", email));
+        }
+        Ok(Self {
+            contact_type,
+            name: name.map(str::to_string),
+            email: email.to_string(),
+        })
+    }
+}
+
+/// The `contactType` of a metadata contact
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ContactType {
+    Technical,
+    Support,
+    Administrative,
+    Billing,
+    Other,
+}
+
+impl ContactType {
+    pub const ALL: [Self; 5] = [
+        Self::Technical,
+        Self::Support,
+        Self::Administrative,
+        Self::Billing,
+        Self::Other,
+    ];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Technical => "technical",
+            Self::Support => "support",
+            Self::Administrative => "administrative",
+            Self::Billing => "billing",
+            Self::Other => "other",
+        }
+    }
+}
@@ -0,0 +1,73 @@
+use chrono::{DateTime, Duration, Utc};
+use log::debug;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use uuid::Uuid;
+
+use crate::models::session::SessionParticipant;
+
+/// How long SPs have to answer before a logout in progress is discarded
+const LOGOUT_TTL_MINUTES: i64 = 10;
+
+/// The SP that started a logout and is owed a LogoutResponse
+#[derive(Debug, Clone)]
+pub struct LogoutInitiator {
+    pub sp_entity_id: String,
+    pub request_id: String,
+    pub relay_state: String,
+}
+
+/// A front-channel logout being propagated to the session's SPs
+#[derive(Debug, Clone)]
+pub struct LogoutInProgress {
+    /// `None` when the user logged out at the IdP
+    pub initiator: Option<LogoutInitiator>,
+    /// SPs that still need to be sent a LogoutRequest
+    pub remaining: Vec<SessionParticipant>,
+    pub session_index: String,
+    /// ID of the LogoutRequest currently awaiting a response
+    pub pending_request_id: Option<String>,
+    /// Set when any SP could not be logged out
+    pub partial: bool,
+    pub created_at: DateTime<Utc>,
+}
+
+/// Server-side store of logouts in progress, keyed by an opaque ID carried in
+/// the RelayState of each LogoutRequest sent to an SP
+#[derive(Debug, Default)]
+pub struct LogoutStore {
+    logouts: Mutex<HashMap<String, LogoutInProgress>>,
+}
+
+impl LogoutStore {
+    pub fn insert(&self, logout: LogoutInProgress) -> String {
+        let id = Uuid::new_v4().simple().to_string();
+        debug!(
+            "Starting logout {} for session {} with {} SP(s) to notify",
+            id,
+            logout.session_index,
+            logout.remaining.len()
+        );
+        let mut logouts = self.logouts.lock().unwrap();
+        logouts.retain(|_, existing| !is_expired(existing));
+        logouts.insert(id.clone(), logout);
+        id
+    }
+
+    /// Removes and returns a logout, if it exists and has not expired
+    pub fn take(&self, id: &str) -> Option<LogoutInProgress> {
+        self.logouts
+            .lock()
+            .unwrap()
+            .remove(id)
+            .filter(|logout| !is_expired(logout))
+    }
+
+    pub fn put(&self, id: &str, logout: LogoutInProgress) {
+        self.logouts.lock().unwrap().insert(id.to_string(), logout);
+    }
+}
+
+fn is_expired(logout: &LogoutInProgress) -> bool {
+    Utc::now() - logout.created_at > Duration::minutes(LOGOUT_TTL_MINUTES)
+}
@@ -1,3 +1,10 @@
+pub mod attribute_mapping;
+pub mod idp_metadata;
+pub mod logout;
+pub mod name_id;
+pub mod pending_request;
 pub mod request;
+pub mod service_provider;
+pub mod session;
 pub mod state;
 pub mod user;
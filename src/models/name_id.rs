@@ -0,0 +1,254 @@
+use base64::Engine as _;
+use base64::engine::general_purpose;
+use log::{debug, info};
+use openssl::hash::MessageDigest;
+use openssl::pkey::PKey;
+use openssl::rand::rand_bytes;
+use openssl::sign::Signer;
+use std::collections::HashSet;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use uuid::Uuid;
+
+use crate::cert_util::{PRIVATE_KEY_FILE_MODE, write_atomically};
+use crate::models::user::User;
+
+pub const NAME_ID_FORMAT_UNSPECIFIED: &str =
+    "urn:oasis:names:tc:SAML:1.1:nameid-format:unspecified";
+pub const NAME_ID_FORMAT_EMAIL_ADDRESS: &str =
+    "urn:oasis:names:tc:SAML:1.1:nameid-format:emailAddress";
+pub const NAME_ID_FORMAT_PERSISTENT: &str = "urn:oasis:names:tc:SAML:2.0:nameid-format:persistent";
+pub const NAME_ID_FORMAT_TRANSIENT: &str = "urn:oasis:names:tc:SAML:2.0:nameid-format:transient";
+
+/// NameID formats this IdP can issue
+pub const SUPPORTED_NAME_ID_FORMATS: [&str; 4] = [
+    NAME_ID_FORMAT_UNSPECIFIED,
+    NAME_ID_FORMAT_EMAIL_ADDRESS,
+    NAME_ID_FORMAT_PERSISTENT,
+    NAME_ID_FORMAT_TRANSIENT,
+];
+
+const SALT_LENGTH: usize = 32;
+
+pub fn is_supported_format(format: &str) -> bool {
+    SUPPORTED_NAME_ID_FORMATS.contains(&format)
+}
+
+/// Picks the NameID format for an assertion from the `NameIDPolicy` Format of
+/// the request, falling back to the SP's default.
+///
+/// Returns `None` when the requested format cannot be issued.
+pub fn resolve_format<'a>(
+    requested: Option<&'a str>,
+    sp_default: Option<&'a str>,
+) -> Option<&'a str> {
+    match requested {
+        None | Some(NAME_ID_FORMAT_UNSPECIFIED) => {
+            Some(sp_default.unwrap_or(NAME_ID_FORMAT_UNSPECIFIED))
+        }
+        Some(format) if is_supported_format(format) => Some(format),
+        Some(_) => None,
+    }
+}
+
+/// Issues NameID values, deriving persistent identifiers that are stable for a
+/// user at one SP and unlinkable across SPs.
+///
+/// Persistent identifiers are an HMAC of the SP and user under a salt kept on
+/// disk. Identifiers that were handed out are recorded, so requests with
+/// `AllowCreate="false"` can be refused for users the SP has never seen.
+#[derive(Debug)]
+pub struct NameIdService {
+    salt: Vec<u8>,
+    issued_path: PathBuf,
+    issued: Mutex<HashSet<String>>,
+}
+
+impl NameIdService {
+    /// Loads the salt and issued identifiers, generating a new salt on first use
+    pub fn load<P: AsRef<Path>>(salt_path: P, issued_path: P) -> io::Result<Self> {
+        let salt_path = salt_path.as_ref();
+        let salt = if salt_path.exists() {
+            debug!("Loading NameID salt from {}", salt_path.display());
+            fs::read(salt_path)?
+        } else {
+            info!(
+                "Generating new NameID salt at {}. Persistent NameIDs change if it is lost",
+                salt_path.display()
+            );
+            let mut salt = vec![0; SALT_LENGTH];
+            rand_bytes(&mut salt)?;
+            write_atomically(salt_path, &salt, PRIVATE_KEY_FILE_MODE)?;
+            salt
+        };
+        if salt.len() < SALT_LENGTH {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("NameID salt in {} is too short", salt_path.display()),
+            ));
+        }
+
+        let issued = match fs::read_to_string(issued_path.as_ref()) {
+            Ok(contents) => contents
+                .lines()
+                .filter(|line| !line.is_empty())
+                .map(str::to_string)
+                .collect(),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => HashSet::new(),
+            Err(e) => return Err(e),
+        };
+        debug!("Loaded {} issued persistent NameID(s)", issued.len());
+
+        Ok(Self {
+            salt,
+            issued_path: issued_path.as_ref().to_path_buf(),
+            issued: Mutex::new(issued),
+        })
+    }
+
+    /// Returns the NameID value to issue for `user` at an SP.
+    ///
+    /// Returns `Ok(None)` when the SP asked for a persistent identifier that
+    /// does not exist yet but did not allow one to be created.
+    pub fn name_id(
+        &self,
+        format: &str,
+        user: &User,
+        sp_entity_id: &str,
+        allow_create: bool,
+    ) -> io::Result<Option<String>> {
+        match format {
+            NAME_ID_FORMAT_EMAIL_ADDRESS => Ok(Some(user.email.clone())),
+            NAME_ID_FORMAT_TRANSIENT => Ok(Some(format!("_{}", Uuid::new_v4().simple()))),
+            NAME_ID_FORMAT_PERSISTENT => self.persistent_name_id(user, sp_entity_id, allow_create),
+            _ => Ok(Some(user.user_id.clone())),
+        }
+    }
+
+    fn persistent_name_id(
+        &self,
+        user: &User,
+        sp_entity_id: &str,
+        allow_create: bool,
+    ) -> io::Result<Option<String>> {
+        let key = PKey::hmac(&self.salt)?;
+        let mut signer = Signer::new(MessageDigest::sha256(), &key)?;
+        signer.update(sp_entity_id.as_bytes())?;
+        signer.update(&[0])?;
+        signer.update(user.user_id.as_bytes())?;
+        let name_id = general_purpose::STANDARD.encode(signer.sign_to_vec()?);
+
+        let mut issued = self.issued.lock().unwrap();
+        if issued.contains(&name_id) {
+            return Ok(Some(name_id));
+        }
+        if !allow_create {
+            debug!(
+                "No persistent NameID exists for user {} at SP {} and AllowCreate is false",
+                user.user_id, sp_entity_id
+            );
+            return Ok(None);
+        }
+
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.issued_path)?;
+        writeln!(file, "{}", name_id)?;
+        debug!(
+            "Created persistent NameID for user {} at SP {}",
+            user.user_id, sp_entity_id
+        );
+        issued.insert(name_id.clone());
+        Ok(Some(name_id))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(unix)]
+    use std::os::unix::fs::PermissionsExt;
+
+    fn user(user_id: &str) -> User {
+        User {
+            user_id: user_id.to_string(),
+            first_name: "Test".to_string(),
+            last_name: "User".to_string(),
+            email: format!("{}@example.com", user_id),
+            password_hash: None,
+            mobile_phone: None,
+            attributes: None,
+        }
+    }
+
+    fn temporary_dir() -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("name-id-test-{}", Uuid::new_v4().simple()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn persistent(
+        service: &NameIdService,
+        user_id: &str,
+        sp: &str,
+        create: bool,
+    ) -> Option<String> {
+        service
+            .name_id(NAME_ID_FORMAT_PERSISTENT, &user(user_id), sp, create)
+            .unwrap()
+    }
+
+    #[test]
+    fn persistent_name_id_is_stable_across_restarts() {
+        let dir = temporary_dir();
+        let (salt, issued) = (dir.join("salt"), dir.join("issued"));
+        let service = NameIdService::load(&salt, &issued).unwrap();
+        let first = persistent(&service, "alice", "https://sp.example.com", true).unwrap();
+        assert_eq!(
+            persistent(&service, "alice", "https://sp.example.com", false),
+            Some(first.clone())
+        );
+
+        let reloaded = NameIdService::load(&salt, &issued).unwrap();
+        assert_eq!(
+            persistent(&reloaded, "alice", "https://sp.example.com", false),
+            Some(first)
+        );
+        #[cfg(unix)]
+        assert_eq!(
+            fs::metadata(&salt).unwrap().permissions().mode() & 0o777,
+            PRIVATE_KEY_FILE_MODE
+        );
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn persistent_name_id_differs_per_sp_and_user() {
+        let dir = temporary_dir();
+        let service = NameIdService::load(dir.join("salt"), dir.join("issued")).unwrap();
+        let alice = persistent(&service, "alice", "https://a.example.com", true);
+        assert_ne!(
+            alice,
+            persistent(&service, "alice", "https://b.example.com", true)
+        );
+        assert_ne!(
+            alice,
+            persistent(&service, "bob", "https://a.example.com", true)
+        );
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn persistent_name_id_requires_allow_create() {
+        let dir = temporary_dir();
+        let service = NameIdService::load(dir.join("salt"), dir.join("issued")).unwrap();
+        assert_eq!(
+            persistent(&service, "alice", "https://sp.example.com", false),
+            None
+        );
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,73 @@
+use chrono::{DateTime, Duration, Utc};
+use log::debug;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use uuid::Uuid;
+
+use crate::models::service_provider::AttributeRequest;
+
+/// How long a user has to sign in before a pending request is discarded
+const PENDING_REQUEST_TTL_MINUTES: i64 = 10;
+
+/// A validated SSO request waiting for the user to authenticate
+#[derive(Debug, Clone)]
+pub struct PendingAuthnRequest {
+    pub sp_entity_id: String,
+    pub acs_url: String,
+    /// ID of the AuthnRequest, or `None` for IdP-initiated SSO
+    pub in_response_to: Option<String>,
+    pub relay_state: String,
+    /// The SP asked for the user to authenticate again even with an IdP session
+    pub force_authn: bool,
+    /// The SP asked for the request to be answered without user interaction
+    pub is_passive: bool,
+    /// NameID format to issue, resolved from the request's `NameIDPolicy`
+    pub name_id_format: String,
+    /// The SP allows a new persistent NameID to be created for the user
+    pub allow_create: bool,
+    /// Attributes the request asks for, narrowing the SP's release policy
+    pub attribute_request: AttributeRequest,
+    pub created_at: DateTime<Utc>,
+}
+
+/// Server-side store of requests awaiting login, keyed by an opaque ID that is
+/// round-tripped through the login form
+#[derive(Debug, Default)]
+pub struct PendingRequestStore {
+    requests: Mutex<HashMap<String, PendingAuthnRequest>>,
+}
+
+impl PendingRequestStore {
+    /// Stores a pending request, returning the ID to embed in the login form
+    pub fn insert(&self, request: PendingAuthnRequest) -> String {
+        let id = Uuid::new_v4().to_string();
+        let mut requests = self.requests.lock().unwrap();
+        requests.retain(|_, pending| !is_expired(pending));
+        requests.insert(id.clone(), request);
+        debug!("Stored pending request {} ({} pending)", id, requests.len());
+        id
+    }
+
+    /// Returns a pending request without consuming it
+    pub fn get(&self, id: &str) -> Option<PendingAuthnRequest> {
+        self.requests
+            .lock()
+            .unwrap()
+            .get(id)
+            .filter(|pending| !is_expired(pending))
+            .cloned()
+    }
+
+    /// Removes and returns a pending request, if it exists and has not expired
+    pub fn take(&self, id: &str) -> Option<PendingAuthnRequest> {
+        self.requests
+            .lock()
+            .unwrap()
+            .remove(id)
+            .filter(|pending| !is_expired(pending))
+    }
+}
+
+fn is_expired(pending: &PendingAuthnRequest) -> bool {
+    Utc::now() - pending.created_at > Duration::minutes(PENDING_REQUEST_TTL_MINUTES)
+}
@@ -2,19 +2,58 @@ use serde::Deserialize;
 
 #[derive(Deserialize)]
 pub struct SsoQuery {
-    pub user_id: String,
+    /// Only honored in test mode
+    pub user_id: Option<String>,
+    #[serde(rename = "SAMLRequest")]
     pub saml_request: Option<String>,
+    #[serde(rename = "RelayState")]
     pub relay_state: Option<String>,
 }
 
 #[derive(Deserialize)]
 pub struct IdpInitiatedQuery {
-    pub user_id: String,
+    /// Only honored in test mode
+    pub user_id: Option<String>,
+    /// Entity ID of the target SP
+    pub sp: Option<String>,
     pub relay_state: Option<String>,
 }
 
 #[derive(Deserialize)]
 pub struct SamlRequest {
+    #[serde(rename = "SAMLRequest")]
     pub saml_request: String,
+    #[serde(rename = "RelayState")]
+    pub relay_state: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct LoginForm {
+    pub request_id: String,
+    pub username: String,
+    pub password: String,
+    /// Set when the user chose to cancel signing in
+    pub cancel: Option<String>,
+}
+
+/// Query parameters of a SingleLogoutService request over HTTP-Redirect
+#[derive(Deserialize)]
+pub struct SloQuery {
+    #[serde(rename = "SAMLRequest")]
+    pub saml_request: Option<String>,
+    #[serde(rename = "SAMLResponse")]
+    pub saml_response: Option<String>,
+    #[serde(rename = "RelayState")]
+    pub relay_state: Option<String>,
+}
+
+/// Form fields of a SingleLogoutService request over HTTP-POST
+#[derive(Deserialize)]
+pub struct SloForm {
+    #[serde(rename = "SAMLRequest")]
+    pub saml_request: Option<String>,
+    #[serde(rename = "SAMLResponse")]
+    pub saml_response: Option<String>,
+    #[serde(rename = "RelayState")]
     pub relay_state: Option<String>,
 }
@@ -0,0 +1,969 @@
+use log::debug;
+use openssl::hash::MessageDigest;
+use openssl::pkey::Id;
+use openssl::x509::X509;
+use samael::metadata::{HTTP_POST_BINDING, HTTP_REDIRECT_BINDING};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+
+use crate::cert_util::load_certificate_der;
+use crate::models::attribute_mapping::{
+    AttributeMapping, AttributeName, AttributeProfile, resolve_attribute_name,
+};
+use crate::models::name_id::is_supported_format;
+
+/// Default validity of issued assertions
+pub const DEFAULT_ASSERTION_LIFETIME_SECS: u32 = 300;
+/// Default allowance for the SP's clock running behind the IdP's
+pub const DEFAULT_CLOCK_SKEW_SECS: u32 = 60;
+
+/// An Assertion Consumer Service endpoint registered for an SP
+#[derive(Debug, Deserialize, Clone)]
+pub struct AcsEndpoint {
+    pub location: String,
+    #[serde(default = "default_acs_binding")]
+    pub binding: String,
+    pub index: Option<u16>,
+    #[serde(default)]
+    pub is_default: bool,
+}
+
+fn default_acs_binding() -> String {
+    HTTP_POST_BINDING.to_string()
+}
+
+/// A SingleLogoutService endpoint registered for an SP
+#[derive(Debug, Deserialize, Clone)]
+pub struct SloEndpoint {
+    pub location: String,
+    #[serde(default = "default_slo_binding")]
+    pub binding: String,
+    /// Where LogoutResponses are sent, if different from `location`
+    pub response_location: Option<String>,
+}
+
+fn default_slo_binding() -> String {
+    HTTP_REDIRECT_BINDING.to_string()
+}
+
+/// A requested attribute advertised by an SP's AttributeConsumingService
+#[derive(Debug, Deserialize, Clone)]
+pub struct RequestedAttribute {
+    pub name: String,
+}
+
+/// An AttributeConsumingService advertised by an SP
+#[derive(Debug, Deserialize, Clone)]
+pub struct AttributeConsumingService {
+    pub index: u16,
+    #[serde(default)]
+    pub is_default: bool,
+    pub requested_attributes: Vec<RequestedAttribute>,
+}
+
+impl AttributeConsumingService {
+    pub fn requests(&self, name: &str) -> bool {
+        self.requested_attributes.iter().any(|a| a.name == name)
+    }
+}
+
+/// Attributes an AuthnRequest asks for
+#[derive(Debug, Clone, Default)]
+pub struct AttributeRequest {
+    /// `AttributeConsumingServiceIndex` of the request, checked against the
+    /// SP's services when the request is received
+    pub consuming_service_index: Option<u16>,
+    /// Names listed in `RequestedAttribute` extensions of the request
+    pub requested_attributes: Option<Vec<String>>,
+}
+
+/// Block cipher used to encrypt assertions
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum DataEncryptionAlgorithm {
+    Aes128Gcm,
+    #[default]
+    Aes256Gcm,
+    Aes128Cbc,
+    Aes256Cbc,
+}
+
+impl DataEncryptionAlgorithm {
+    pub fn uri(self) -> &'static str {
+        match self {
+            Self::Aes128Gcm => "http://www.w3.org/2009/xmlenc11#aes128-gcm",
+            Self::Aes256Gcm => "http://www.w3.org/2009/xmlenc11#aes256-gcm",
+            Self::Aes128Cbc => "http://www.w3.org/2001/04/xmlenc#aes128-cbc",
+            Self::Aes256Cbc => "http://www.w3.org/2001/04/xmlenc#aes256-cbc",
+        }
+    }
+
+    pub fn from_uri(uri: &str) -> Option<Self> {
+        [
+            Self::Aes128Gcm,
+            Self::Aes256Gcm,
+            Self::Aes128Cbc,
+            Self::Aes256Cbc,
+        ]
+        .into_iter()
+        .find(|algorithm| algorithm.uri() == uri)
+    }
+}
+
+/// Algorithm used to encrypt the assertion key for the SP
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum KeyTransportAlgorithm {
+    /// RSA-OAEP with SHA-1 and MGF1 with SHA-1, supported by most SPs
+    #[default]
+    RsaOaepMgf1p,
+    /// RSA-OAEP with SHA-256 and MGF1 with SHA-256
+    RsaOaep,
+}
+
+impl KeyTransportAlgorithm {
+    pub fn uri(self) -> &'static str {
+        match self {
+            Self::RsaOaepMgf1p => "http://www.w3.org/2001/04/xmlenc#rsa-oaep-mgf1p",
+            Self::RsaOaep => "http://www.w3.org/2009/xmlenc11#rsa-oaep",
+        }
+    }
+
+    pub fn from_uri(uri: &str) -> Option<Self> {
+        [Self::RsaOaepMgf1p, Self::RsaOaep]
+            .into_iter()
+            .find(|algorithm| algorithm.uri() == uri)
+    }
+}
+
+/// Algorithm used to sign messages sent to an SP
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum SignatureAlgorithm {
+    /// Only accepted for SPs with `allow_sha1`
+    RsaSha1,
+    RsaSha256,
+    RsaSha512,
+    EcdsaSha256,
+    EcdsaSha384,
+}
+
+impl SignatureAlgorithm {
+    /// The default algorithm for an IdP key of the given type
+    pub fn default_for_key(key_type: Id) -> Self {
+        if key_type == Id::EC {
+            Self::EcdsaSha256
+        } else {
+            Self::RsaSha256
+        }
+    }
+
+    pub fn uri(self) -> &'static str {
+        match self {
+            Self::RsaSha1 => "http://www.w3.org/2000/09/xmldsig#rsa-sha1",
+            Self::RsaSha256 => "http://www.w3.org/2001/04/xmldsig-more#rsa-sha256",
+            Self::RsaSha512 => "http://www.w3.org/2001/04/xmldsig-more#rsa-sha512",
+            Self::EcdsaSha256 => "http://www.w3.org/2001/04/xmldsig-more#ecdsa-sha256",
+            Self::EcdsaSha384 => "http://www.w3.org/2001/04/xmldsig-more#ecdsa-sha384",
+        }
+    }
+
+    pub fn from_uri(uri: &str) -> Option<Self> {
+        [
+            Self::RsaSha1,
+            Self::RsaSha256,
+            Self::RsaSha512,
+            Self::EcdsaSha256,
+            Self::EcdsaSha384,
+        ]
+        .into_iter()
+        .find(|algorithm| algorithm.uri() == uri)
+    }
+
+    pub fn message_digest(self) -> MessageDigest {
+        match self {
+            Self::RsaSha1 => MessageDigest::sha1(),
+            Self::RsaSha256 | Self::EcdsaSha256 => MessageDigest::sha256(),
+            Self::EcdsaSha384 => MessageDigest::sha384(),
+            Self::RsaSha512 => MessageDigest::sha512(),
+        }
+    }
+
+    /// The key type able to produce this signature
+    pub fn key_type(self) -> Id {
+        match self {
+            Self::EcdsaSha256 | Self::EcdsaSha384 => Id::EC,
+            _ => Id::RSA,
+        }
+    }
+}
+
+/// Digest algorithm used for the references of XML signatures
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum DigestAlgorithm {
+    /// Only accepted for SPs with `allow_sha1`
+    Sha1,
+    #[default]
+    Sha256,
+    Sha384,
+    Sha512,
+}
+
+impl DigestAlgorithm {
+    pub fn uri(self) -> &'static str {
+        match self {
+            Self::Sha1 => "http://www.w3.org/2000/09/xmldsig#sha1",
+            Self::Sha256 => "http://www.w3.org/2001/04/xmlenc#sha256",
+            Self::Sha384 => "http://www.w3.org/2001/04/xmldsig-more#sha384",
+            Self::Sha512 => "http://www.w3.org/2001/04/xmlenc#sha512",
+        }
+    }
+
+    pub fn from_uri(uri: &str) -> Option<Self> {
+        [Self::Sha1, Self::Sha256, Self::Sha384, Self::Sha512]
+            .into_iter()
+            .find(|algorithm| algorithm.uri() == uri)
+    }
+
+    pub fn message_digest(self) -> MessageDigest {
+        match self {
+            Self::Sha1 => MessageDigest::sha1(),
+            Self::Sha256 => MessageDigest::sha256(),
+            Self::Sha384 => MessageDigest::sha384(),
+            Self::Sha512 => MessageDigest::sha512(),
+        }
+    }
+}
+
+/// Algorithms used to sign messages for an SP
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SigningAlgorithms {
+    pub signature: SignatureAlgorithm,
+    pub digest: DigestAlgorithm,
+}
+
+/// Which parts of an authentication Response are signed
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum SigningMode {
+    #[default]
+    Response,
+    Assertion,
+    Both,
+}
+
+impl SigningMode {
+    pub fn signs_response(self) -> bool {
+        matches!(self, Self::Response | Self::Both)
+    }
+
+    pub fn signs_assertion(self) -> bool {
+        matches!(self, Self::Assertion | Self::Both)
+    }
+}
+
+/// Configuration for a Service Provider this IdP issues assertions to
+#[derive(Debug, Deserialize, Clone)]
+pub struct ServiceProvider {
+    pub entity_id: String,
+    pub acs_endpoints: Vec<AcsEndpoint>,
+    /// Endpoints receiving front-channel logout messages
+    #[serde(default)]
+    pub single_logout_services: Vec<SloEndpoint>,
+    /// Path to the certificate used to verify the SP's signed requests
+    pub signing_certificate_path: Option<String>,
+    /// Path to the certificate used to encrypt assertions for the SP
+    pub encryption_certificate_path: Option<String>,
+    /// Signature algorithm for messages sent to the SP. Defaults to SHA-256
+    /// with the IdP's key type
+    pub signature_algorithm: Option<SignatureAlgorithm>,
+    #[serde(default)]
+    pub digest_algorithm: DigestAlgorithm,
+    /// Accept and produce SHA-1 signatures and digests, for legacy SPs only
+    #[serde(default)]
+    pub allow_sha1: bool,
+    /// Sign the Response, the Assertion or both
+    #[serde(default)]
+    pub signing_mode: SigningMode,
+    /// Encrypt assertions for this SP. Defaults to encrypting whenever an
+    /// encryption certificate is configured
+    pub encrypt_assertions: Option<bool>,
+    #[serde(default)]
+    pub data_encryption_algorithm: DataEncryptionAlgorithm,
+    #[serde(default)]
+    pub key_transport_algorithm: KeyTransportAlgorithm,
+    /// How long issued assertions are valid for
+    #[serde(default = "default_assertion_lifetime_secs")]
+    pub assertion_lifetime_secs: u32,
+    /// Allowance for clock differences with the SP, applied to `NotBefore`
+    #[serde(default = "default_clock_skew_secs")]
+    pub clock_skew_secs: u32,
+    /// Add a `OneTimeUse` condition to issued assertions
+    #[serde(default)]
+    pub one_time_use: bool,
+    /// Reject AuthnRequests from this SP that are not signed
+    #[serde(default)]
+    pub authn_requests_signed: bool,
+    /// NameID format used when the request does not ask for one
+    pub name_id_format: Option<String>,
+    /// Attribute names released to this SP. All attributes are released when unset
+    pub released_attributes: Option<Vec<String>>,
+    /// Attribute names never released to this SP, whatever it requests
+    #[serde(default)]
+    pub denied_attributes: Vec<String>,
+    /// Built-in names attributes are released under
+    #[serde(default)]
+    pub attribute_profile: AttributeProfile,
+    /// Names for individual attributes, taking precedence over the profile
+    #[serde(default)]
+    pub attribute_mappings: Vec<AttributeMapping>,
+    /// Attribute sets the SP requests, usually imported from its metadata
+    #[serde(default)]
+    pub attribute_consuming_services: Vec<AttributeConsumingService>,
+    /// Certificates the SP signs with. A message signed with any of them is
+    /// accepted, so the SP can roll over its key.
+    #[serde(skip)]
+    pub signing_certificates_der: Vec<Vec<u8>>,
+    #[serde(skip)]
+    pub encryption_certificate_der: Option<Vec<u8>>,
+}
+
+fn default_assertion_lifetime_secs() -> u32 {
+    DEFAULT_ASSERTION_LIFETIME_SECS
+}
+
+fn default_clock_skew_secs() -> u32 {
+    DEFAULT_CLOCK_SKEW_SECS
+}
+
+impl ServiceProvider {
+    /// Returns the ACS endpoint to use when a request does not specify one.
+    ///
+    /// Only HTTP-POST endpoints are considered, since that is the only binding
+    /// responses are delivered over.
+    pub fn default_acs(&self) -> Option<&AcsEndpoint> {
+        let mut post_endpoints = self
+            .acs_endpoints
+            .iter()
+            .filter(|acs| acs.binding == HTTP_POST_BINDING);
+        post_endpoints
+            .clone()
+            .find(|acs| acs.is_default)
+            .or_else(|| {
+                post_endpoints
+                    .clone()
+                    .filter(|acs| acs.index.is_some())
+                    .min_by_key(|acs| acs.index)
+            })
+            .or_else(|| post_endpoints.next())
+    }
+
+    /// Resolves the ACS endpoint requested by an AuthnRequest against the
+    /// registered endpoints.
+    ///
+    /// The request may name an endpoint by URL or by index, but not both. When
+    /// it names neither, the default endpoint is used.
+    pub fn resolve_acs(
+        &self,
+        url: Option<&str>,
+        index: Option<u16>,
+        binding: Option<&str>,
+    ) -> Result<&AcsEndpoint, Box<dyn std::error::Error>> {
+        if let Some(binding) = binding
+            && binding != HTTP_POST_BINDING
+        {
+            return Err(format!("Unsupported response ProtocolBinding {}", binding).into());
+        }
+
+        let acs = match (url, index) {
+            (Some(_), Some(_)) => {
+                return Err(
+                    "AssertionConsumerServiceURL and AssertionConsumerServiceIndex are mutually exclusive"
+                        .into(),
+                );
+            }
+            (Some(url), None) => self
+                .acs_endpoints
+                .iter()
+                .find(|acs| acs.location == url)
+                .ok_or_else(|| format!("AssertionConsumerServiceURL {} is not registered", url))?,
+            (None, Some(index)) => self
+                .acs_endpoints
+                .iter()
+                .find(|acs| acs.index == Some(index))
+                .ok_or_else(|| {
+                    format!("AssertionConsumerServiceIndex {} is not registered", index)
+                })?,
+            (None, None) => self
+                .default_acs()
+                .ok_or("No default ACS endpoint is registered")?,
+        };
+
+        if acs.binding != HTTP_POST_BINDING {
+            return Err(format!(
+                "ACS endpoint {} uses unsupported binding {}",
+                acs.location, acs.binding
+            )
+            .into());
+        }
+        Ok(acs)
+    }
+
+    /// Returns the SingleLogoutService endpoint to use, preferring the
+    /// HTTP-Redirect binding over HTTP-POST
+    pub fn slo_endpoint(&self) -> Option<&SloEndpoint> {
+        [HTTP_REDIRECT_BINDING, HTTP_POST_BINDING]
+            .iter()
+            .find_map(|binding| {
+                self.single_logout_services
+                    .iter()
+                    .find(|slo| slo.binding == *binding)
+            })
+    }
+
+    /// Returns the algorithms used to sign messages for this SP with an IdP key
+    /// of the given type
+    pub fn signing_algorithms(&self, idp_key_type: Id) -> SigningAlgorithms {
+        SigningAlgorithms {
+            signature: self
+                .signature_algorithm
+                .unwrap_or_else(|| SignatureAlgorithm::default_for_key(idp_key_type)),
+            digest: self.digest_algorithm,
+        }
+    }
+
+    /// Checks that the SP's configured signature algorithm, if any, can be used
+    /// with an IdP key of the given type
+    pub fn check_idp_key_type(&self, idp_key_type: Id) -> Result<(), Box<dyn std::error::Error>> {
+        match self.signature_algorithm {
+            Some(algorithm) if algorithm.key_type() != idp_key_type => Err(format!(
+                "SP {} is configured for {} but the IdP key type is {:?}",
+                self.entity_id,
+                algorithm.uri(),
+                idp_key_type
+            )
+            .into()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Returns whether assertions for this SP are sent as `EncryptedAssertion`s
+    pub fn encrypts_assertions(&self) -> bool {
+        self.encrypt_assertions
+            .unwrap_or(self.encryption_certificate_der.is_some())
+    }
+
+    /// Returns the AttributeConsumingService used when a request does not name one
+    pub fn default_attribute_consuming_service(&self) -> Option<&AttributeConsumingService> {
+        self.attribute_consuming_services
+            .iter()
+            .find(|service| service.is_default)
+            .or_else(|| {
+                self.attribute_consuming_services
+                    .iter()
+                    .min_by_key(|service| service.index)
+            })
+    }
+
+    /// Returns the AttributeConsumingService with the given index
+    pub fn attribute_consuming_service(&self, index: u16) -> Option<&AttributeConsumingService> {
+        self.attribute_consuming_services
+            .iter()
+            .find(|service| service.index == index)
+    }
+
+    /// Returns the name the user attribute `source` is released to this SP under
+    pub fn attribute_name(&self, source: &str) -> AttributeName {
+        resolve_attribute_name(self.attribute_profile, &self.attribute_mappings, source)
+    }
+
+    /// Returns whether the user attribute `source`, released under `name`, may
+    /// be released to this SP for a request. Attributes can be listed under
+    /// either name.
+    ///
+    /// `denied_attributes` are never released. Otherwise an explicit
+    /// `released_attributes` list takes precedence, followed by the attributes
+    /// requested by the default AttributeConsumingService, and all attributes
+    /// are released when neither is configured. What the request asks for, by
+    /// `AttributeConsumingServiceIndex` or `RequestedAttribute`s, narrows
+    /// this further but never widens it.
+    pub fn releases_attribute(&self, source: &str, name: &str, request: &AttributeRequest) -> bool {
+        let matches = |listed: &str| listed == source || listed == name;
+        if self.denied_attributes.iter().any(|a| matches(a)) {
+            return false;
+        }
+        let allowed = match &self.released_attributes {
+            Some(allowed) => allowed.iter().any(|a| matches(a)),
+            None => self
+                .default_attribute_consuming_service()
+                .is_none_or(|service| service.requests(source) || service.requests(name)),
+        };
+        allowed
+            && request
+                .consuming_service_index
+                .and_then(|index| self.attribute_consuming_service(index))
+                .is_none_or(|service| service.requests(source) || service.requests(name))
+            && request
+                .requested_attributes
+                .as_ref()
+                .is_none_or(|requested| requested.iter().any(|a| matches(a)))
+    }
+
+    // Loads the certificates referenced by path into memory
+    fn load_certificates(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        if let Some(path) = &self.signing_certificate_path {
+            self.signing_certificates_der = vec![load_certificate_der(path).map_err(|e| {
+                format!(
+                    "Failed to load signing certificate for SP {} from {}: {}",
+                    self.entity_id, path, e
+                )
+            })?];
+        }
+        if let Some(path) = &self.encryption_certificate_path {
+            self.encryption_certificate_der = Some(load_certificate_der(path).map_err(|e| {
+                format!(
+                    "Failed to load encryption certificate for SP {} from {}: {}",
+                    self.entity_id, path, e
+                )
+            })?);
+        }
+        Ok(())
+    }
+
+    // Checks that the configuration is usable
+    fn validate(&self) -> Result<(), Box<dyn std::error::Error>> {
+        if self.default_acs().is_none() {
+            return Err(format!("SP {} has no HTTP-POST ACS endpoint", self.entity_id).into());
+        }
+        if self.authn_requests_signed && self.signing_certificates_der.is_empty() {
+            return Err(format!(
+                "SP {} requires signed AuthnRequests but has no signing certificate",
+                self.entity_id
+            )
+            .into());
+        }
+        let uses_sha1 = self.signature_algorithm == Some(SignatureAlgorithm::RsaSha1)
+            || self.digest_algorithm == DigestAlgorithm::Sha1;
+        if uses_sha1 && !self.allow_sha1 {
+            return Err(format!(
+                "SP {} is configured for SHA-1 signatures without allow_sha1",
+                self.entity_id
+            )
+            .into());
+        }
+        if self.assertion_lifetime_secs == 0 {
+            return Err(format!(
+                "SP {} has an assertion lifetime of zero seconds",
+                self.entity_id
+            )
+            .into());
+        }
+        if let Some(format) = &self.name_id_format
+            && !is_supported_format(format)
+        {
+            return Err(format!(
+                "SP {} uses unsupported NameID format {}",
+                self.entity_id, format
+            )
+            .into());
+        }
+        for (i, mapping) in self.attribute_mappings.iter().enumerate() {
+            let earlier = &self.attribute_mappings[..i];
+            if earlier.iter().any(|other| other.source == mapping.source) {
+                return Err(format!(
+                    "SP {} maps attribute {} more than once",
+                    self.entity_id, mapping.source
+                )
+                .into());
+            }
+            if earlier.iter().any(|other| other.name == mapping.name) {
+                return Err(format!(
+                    "SP {} maps several attributes to {}",
+                    self.entity_id, mapping.name
+                )
+                .into());
+            }
+        }
+        if self.encrypt_assertions == Some(true) && self.encryption_certificate_der.is_none() {
+            return Err(format!(
+                "SP {} requires encrypted assertions but has no encryption certificate",
+                self.entity_id
+            )
+            .into());
+        }
+        if let Some(cert_der) = &self.encryption_certificate_der {
+            let public_key = X509::from_der(cert_der)
+                .and_then(|cert| cert.public_key())
+                .map_err(|e| {
+                    format!(
+                        "SP {} has an invalid encryption certificate: {}",
+                        self.entity_id, e
+                    )
+                })?;
+            // Keys are only transported with RSA-OAEP
+            if public_key.id() != Id::RSA {
+                return Err(format!(
+                    "SP {} has an encryption certificate without an RSA key",
+                    self.entity_id
+                )
+                .into());
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Where to import an SP's configuration from its SAML metadata
+#[derive(Debug, Deserialize, Clone)]
+pub struct MetadataSource {
+    /// Path to an `EntityDescriptor` file
+    pub path: Option<String>,
+    /// URL serving the `EntityDescriptor`, refreshed periodically
+    pub url: Option<String>,
+    /// How often URL sources are re-fetched
+    #[serde(default = "default_refresh_interval_secs")]
+    pub refresh_interval_secs: u64,
+    /// Overrides the `AuthnRequestsSigned` flag from the metadata
+    pub authn_requests_signed: Option<bool>,
+    /// Overrides the first supported NameID format listed in the metadata
+    pub name_id_format: Option<String>,
+    pub signature_algorithm: Option<SignatureAlgorithm>,
+    pub digest_algorithm: Option<DigestAlgorithm>,
+    pub allow_sha1: Option<bool>,
+    /// Overrides the signing mode derived from `WantAssertionsSigned`
+    pub signing_mode: Option<SigningMode>,
+    /// Overrides whether assertions are encrypted when the metadata publishes
+    /// an encryption key
+    pub encrypt_assertions: Option<bool>,
+    pub assertion_lifetime_secs: Option<u32>,
+    pub clock_skew_secs: Option<u32>,
+    pub one_time_use: Option<bool>,
+    pub released_attributes: Option<Vec<String>>,
+    #[serde(default)]
+    pub denied_attributes: Vec<String>,
+    pub attribute_profile: Option<AttributeProfile>,
+    #[serde(default)]
+    pub attribute_mappings: Vec<AttributeMapping>,
+}
+
+fn default_refresh_interval_secs() -> u64 {
+    3600
+}
+
+impl MetadataSource {
+    /// Human readable description of the source for logging
+    pub fn describe(&self) -> &str {
+        self.url
+            .as_deref()
+            .or(self.path.as_deref())
+            .unwrap_or("<unset>")
+    }
+}
+
+/// Service provider configuration file contents
+#[derive(Debug, Deserialize)]
+pub struct ServiceProviderConfig {
+    #[serde(default)]
+    pub service_providers: Vec<ServiceProvider>,
+    #[serde(default)]
+    pub metadata_sources: Vec<MetadataSource>,
+}
+
+impl ServiceProviderConfig {
+    pub fn load_from_file<P: AsRef<Path>>(path: P) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut file = File::open(path)?;
+        let mut contents = String::new();
+        file.read_to_string(&mut contents)?;
+
+        let config: ServiceProviderConfig = serde_yaml::from_str(&contents)?;
+        Ok(config)
+    }
+}
+
+/// Registry of the Service Providers this IdP trusts, keyed by entity ID.
+///
+/// Entries can be replaced at runtime when SP metadata is refreshed.
+#[derive(Debug, Default)]
+pub struct ServiceProviderRegistry {
+    providers: RwLock<HashMap<String, Arc<ServiceProvider>>>,
+}
+
+impl ServiceProviderRegistry {
+    /// Adds an SP to the registry, loading its certificates
+    pub fn register(&self, mut sp: ServiceProvider) -> Result<(), Box<dyn std::error::Error>> {
+        sp.load_certificates()?;
+        sp.validate()?;
+
+        let mut providers = self.providers.write().unwrap();
+        if providers.contains_key(&sp.entity_id) {
+            return Err(format!("SP {} is registered more than once", sp.entity_id).into());
+        }
+
+        debug!(
+            "Registered SP {} with {} ACS endpoint(s), encrypted assertions: {}",
+            sp.entity_id,
+            sp.acs_endpoints.len(),
+            sp.encrypts_assertions()
+        );
+        providers.insert(sp.entity_id.clone(), Arc::new(sp));
+        Ok(())
+    }
+
+    /// Replaces the registered configuration for an SP, e.g. after a metadata
+    /// refresh. The SP must already be registered and be usable with the
+    /// active IdP key type.
+    pub fn replace(
+        &self,
+        sp: ServiceProvider,
+        idp_key_type: Id,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        sp.validate()?;
+        sp.check_idp_key_type(idp_key_type)?;
+        let mut providers = self.providers.write().unwrap();
+        let Some(registered) = providers.get_mut(&sp.entity_id) else {
+            return Err(format!("SP {} is not registered", sp.entity_id).into());
+        };
+        debug!("Updated configuration for SP {}", sp.entity_id);
+        *registered = Arc::new(sp);
+        Ok(())
+    }
+
+    pub fn find(&self, entity_id: &str) -> Option<Arc<ServiceProvider>> {
+        self.providers.read().unwrap().get(entity_id).cloned()
+    }
+
+    /// Returns the only registered SP, if exactly one is registered
+    pub fn single(&self) -> Option<Arc<ServiceProvider>> {
+        let providers = self.providers.read().unwrap();
+        if providers.len() == 1 {
+            providers.values().next().cloned()
+        } else {
+            None
+        }
+    }
+
+    /// Returns a snapshot of all registered SPs
+    pub fn all(&self) -> Vec<Arc<ServiceProvider>> {
+        self.providers.read().unwrap().values().cloned().collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.providers.read().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.providers.read().unwrap().is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cert_util::{CertificateProfile, IdpKeyType, generate_identity_provider};
+    use samael::metadata::HTTP_REDIRECT_BINDING;
+
+    fn service_provider(yaml: &str) -> ServiceProvider {
+        serde_yaml::from_str(&format!("entity_id: https://sp.example.com\n{}", yaml)).unwrap()
+    }
+
+    fn acs_sp() -> ServiceProvider {
+        service_provider(
+            "acs_endpoints:
+  - location: https://sp.example.com/acs/0
+    index: 0
+  - location: https://sp.example.com/acs/1
+    index: 1
+    is_default: true
+  - location: https://sp.example.com/acs/artifact
+    index: 2
+    binding: urn:oasis:names:tc:SAML:2.0:bindings:HTTP-Artifact
+",
+        )
+    }
+
+    #[test]
+    fn resolve_acs_defaults_to_the_default_endpoint() {
+        let sp = acs_sp();
+        let acs = sp.resolve_acs(None, None, None).unwrap();
+        assert_eq!(acs.location, "https://sp.example.com/acs/1");
+    }
+
+    #[test]
+    fn resolve_acs_finds_registered_url_or_index() {
+        let sp = acs_sp();
+        let by_url = sp
+            .resolve_acs(Some("https://sp.example.com/acs/0"), None, None)
+            .unwrap();
+        assert_eq!(by_url.index, Some(0));
+        let by_index = sp
+            .resolve_acs(None, Some(1), Some(HTTP_POST_BINDING))
+            .unwrap();
+        assert_eq!(by_index.location, "https://sp.example.com/acs/1");
+    }
+
+    #[test]
+    fn resolve_acs_rejects_unregistered_or_ambiguous_requests() {
+        let sp = acs_sp();
+        assert!(
+            sp.resolve_acs(Some("https://attacker.example.com/acs"), None, None)
+                .is_err()
+        );
+        assert!(sp.resolve_acs(None, Some(7), None).is_err());
+        assert!(
+            sp.resolve_acs(Some("https://sp.example.com/acs/0"), Some(0), None)
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn resolve_acs_rejects_unsupported_bindings() {
+        let sp = acs_sp();
+        assert!(sp.resolve_acs(None, Some(2), None).is_err());
+        assert!(
+            sp.resolve_acs(None, Some(0), Some(HTTP_REDIRECT_BINDING))
+                .is_err()
+        );
+    }
+
+    fn post_sp(yaml: &str) -> ServiceProvider {
+        service_provider(&format!(
+            "acs_endpoints:\n  - location: https://sp.example.com/acs\n{}",
+            yaml
+        ))
+    }
+
+    #[test]
+    fn signing_algorithms_default_to_the_idp_key_type() {
+        let sp = post_sp("");
+        assert_eq!(
+            sp.signing_algorithms(Id::RSA),
+            SigningAlgorithms {
+                signature: SignatureAlgorithm::RsaSha256,
+                digest: DigestAlgorithm::Sha256,
+            }
+        );
+        assert_eq!(
+            sp.signing_algorithms(Id::EC).signature,
+            SignatureAlgorithm::EcdsaSha256
+        );
+
+        let sp = post_sp("signature_algorithm: rsa-sha512\ndigest_algorithm: sha384\n");
+        assert_eq!(
+            sp.signing_algorithms(Id::RSA),
+            SigningAlgorithms {
+                signature: SignatureAlgorithm::RsaSha512,
+                digest: DigestAlgorithm::Sha384,
+            }
+        );
+    }
+
+    #[test]
+    fn validate_refuses_sha1_unless_allowed() {
+        assert!(
+            post_sp("signature_algorithm: rsa-sha1\n")
+                .validate()
+                .is_err()
+        );
+        assert!(post_sp("digest_algorithm: sha1\n").validate().is_err());
+        post_sp("signature_algorithm: rsa-sha1\ndigest_algorithm: sha1\nallow_sha1: true\n")
+            .validate()
+            .unwrap();
+    }
+
+    #[test]
+    fn validate_requires_an_rsa_encryption_certificate() {
+        let mut sp = post_sp("");
+        for (key_type, valid) in [(IdpKeyType::Rsa2048, true), (IdpKeyType::EcdsaP256, false)] {
+            let (_, cert_chain) =
+                generate_identity_provider(key_type, &CertificateProfile::default()).unwrap();
+            sp.encryption_certificate_der = Some(cert_chain[0].clone());
+            assert_eq!(sp.validate().is_ok(), valid, "{:?}", key_type);
+        }
+        sp.encryption_certificate_der = Some(b"not a certificate".to_vec());
+        assert!(sp.validate().is_err());
+    }
+
+    fn released(sp: &ServiceProvider, request: &AttributeRequest) -> Vec<&'static str> {
+        ["firstName", "email", "mobilePhone", "permissions"]
+            .into_iter()
+            .filter(|name| sp.releases_attribute(name, name, request))
+            .collect()
+    }
+
+    #[test]
+    fn releases_all_attributes_without_a_policy() {
+        let sp = service_provider("acs_endpoints: []\n");
+        assert_eq!(
+            released(&sp, &AttributeRequest::default()),
+            ["firstName", "email", "mobilePhone", "permissions"]
+        );
+    }
+
+    #[test]
+    fn deny_list_overrides_release_list_and_requests() {
+        let sp = service_provider(
+            "acs_endpoints: []
+released_attributes: [email, permissions]
+denied_attributes: [permissions]
+",
+        );
+        let request = AttributeRequest {
+            consuming_service_index: None,
+            requested_attributes: Some(vec!["permissions".to_string(), "email".to_string()]),
+        };
+        assert_eq!(released(&sp, &AttributeRequest::default()), ["email"]);
+        assert_eq!(released(&sp, &request), ["email"]);
+    }
+
+    #[test]
+    fn deny_list_matches_the_released_name() {
+        let sp = service_provider("acs_endpoints: []\ndenied_attributes: [urn:oid:2.5.4.42]\n");
+        let request = AttributeRequest::default();
+        assert!(!sp.releases_attribute("firstName", "urn:oid:2.5.4.42", &request));
+        assert!(sp.releases_attribute("lastName", "urn:oid:2.5.4.4", &request));
+    }
+
+    #[test]
+    fn requests_narrow_but_never_widen_the_release_policy() {
+        let sp = service_provider(
+            "acs_endpoints: []
+attribute_consuming_services:
+  - index: 0
+    is_default: true
+    requested_attributes: [{name: email}]
+  - index: 1
+    requested_attributes: [{name: email}, {name: mobilePhone}]
+",
+        );
+        assert_eq!(released(&sp, &AttributeRequest::default()), ["email"]);
+
+        let by_index = AttributeRequest {
+            consuming_service_index: Some(1),
+            requested_attributes: None,
+        };
+        // The service named by the request cannot add to the default one
+        assert_eq!(released(&sp, &by_index), ["email"]);
+
+        let by_name = AttributeRequest {
+            consuming_service_index: None,
+            requested_attributes: Some(vec!["mobilePhone".to_string(), "email".to_string()]),
+        };
+        assert_eq!(released(&sp, &by_name), ["email"]);
+
+        // An explicit release list is narrowed the same way
+        let sp = service_provider(
+            "acs_endpoints: []
+released_attributes: [firstName, email]
+attribute_consuming_services:
+  - index: 1
+    requested_attributes: [{name: email}, {name: mobilePhone}]
+",
+        );
+        assert_eq!(released(&sp, &by_index), ["email"]);
+    }
+}
@@ -0,0 +1,150 @@
+use chrono::{DateTime, Duration, Utc};
+use log::debug;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use uuid::Uuid;
+
+/// Name of the cookie carrying the IdP session ID
+pub const SESSION_COOKIE_NAME: &str = "idp_session";
+
+/// AuthnContext class for users who signed in with a password over HTTPS
+pub const AUTHN_CONTEXT_PASSWORD_PROTECTED_TRANSPORT: &str =
+    "urn:oasis:names:tc:SAML:2.0:ac:classes:PasswordProtectedTransport";
+/// AuthnContext class for users impersonated in test mode
+pub const AUTHN_CONTEXT_UNSPECIFIED: &str = "urn:oasis:names:tc:SAML:2.0:ac:classes:unspecified";
+
+/// An SP the user was issued an assertion for during a session
+#[derive(Debug, Clone)]
+pub struct SessionParticipant {
+    pub sp_entity_id: String,
+    /// NameID sent in the assertion, repeated in the LogoutRequest
+    pub name_id: String,
+    pub name_id_format: String,
+}
+
+/// An authenticated user's session at the IdP
+#[derive(Debug, Clone)]
+pub struct IdpSession {
+    /// Secret ID stored in the session cookie
+    pub id: String,
+    /// Opaque index shared with SPs as the assertion's `SessionIndex`
+    pub session_index: String,
+    pub user_id: String,
+    pub authn_instant: DateTime<Utc>,
+    /// How the user authenticated, as an AuthnContextClassRef
+    pub authn_context_class: String,
+    pub last_activity: DateTime<Utc>,
+    /// Absolute expiry, published to SPs as `SessionNotOnOrAfter`
+    pub not_on_or_after: DateTime<Utc>,
+    /// SPs that received an assertion in this session, in order
+    pub participants: Vec<SessionParticipant>,
+}
+
+/// Server-side session store with idle and absolute timeouts
+#[derive(Debug)]
+pub struct SessionStore {
+    sessions: Mutex<HashMap<String, IdpSession>>,
+    idle_timeout: Duration,
+    absolute_timeout: Duration,
+}
+
+impl SessionStore {
+    pub fn new(idle_timeout: Duration, absolute_timeout: Duration) -> Self {
+        SessionStore {
+            sessions: Mutex::new(HashMap::new()),
+            idle_timeout,
+            absolute_timeout,
+        }
+    }
+
+    /// Starts a new session for a user who has just authenticated
+    pub fn create(&self, user_id: &str, authn_context_class: &str) -> IdpSession {
+        let now = Utc::now();
+        let session = IdpSession {
+            id: Uuid::new_v4().simple().to_string() + &Uuid::new_v4().simple().to_string(),
+            session_index: format!("_{}", Uuid::new_v4().simple()),
+            user_id: user_id.to_string(),
+            authn_instant: now,
+            authn_context_class: authn_context_class.to_string(),
+            last_activity: now,
+            not_on_or_after: now + self.absolute_timeout,
+            participants: Vec::new(),
+        };
+
+        let mut sessions = self.sessions.lock().unwrap();
+        sessions.retain(|_, existing| !self.is_expired(existing, now));
+        sessions.insert(session.id.clone(), session.clone());
+        debug!(
+            "Created IdP session {} for user {}",
+            session.session_index, user_id
+        );
+        session
+    }
+
+    /// Returns the active session with the given ID, refreshing its idle timer
+    pub fn touch(&self, id: &str) -> Option<IdpSession> {
+        let now = Utc::now();
+        let mut sessions = self.sessions.lock().unwrap();
+        let session = sessions.get_mut(id)?;
+        if self.is_expired(session, now) {
+            debug!("IdP session {} has expired", session.session_index);
+            sessions.remove(id);
+            return None;
+        }
+
+        session.last_activity = now;
+        Some(session.clone())
+    }
+
+    /// Records that an SP received an assertion in the session
+    pub fn add_participant(&self, id: &str, participant: SessionParticipant) {
+        let mut sessions = self.sessions.lock().unwrap();
+        if let Some(session) = sessions.get_mut(id) {
+            session
+                .participants
+                .retain(|existing| existing.sp_entity_id != participant.sp_entity_id);
+            debug!(
+                "SP {} joined IdP session {}",
+                participant.sp_entity_id, session.session_index
+            );
+            session.participants.push(participant);
+        }
+    }
+
+    /// Ends a session, returning it if it existed
+    pub fn remove(&self, id: &str) -> Option<IdpSession> {
+        self.sessions.lock().unwrap().remove(id)
+    }
+
+    /// Returns the active session with the given ID without refreshing it
+    pub fn get(&self, id: &str) -> Option<IdpSession> {
+        let now = Utc::now();
+        self.sessions
+            .lock()
+            .unwrap()
+            .get(id)
+            .filter(|session| !self.is_expired(session, now))
+            .cloned()
+    }
+
+    /// Returns the active session with the given `SessionIndex`
+    pub fn find_by_session_index(&self, session_index: &str) -> Option<IdpSession> {
+        let now = Utc::now();
+        self.sessions
+            .lock()
+            .unwrap()
+            .values()
+            .find(|session| session.session_index == session_index)
+            .filter(|session| !self.is_expired(session, now))
+            .cloned()
+    }
+
+    /// Seconds until the cookie for a new session should expire
+    pub fn cookie_max_age_secs(&self) -> i64 {
+        self.absolute_timeout.num_seconds()
+    }
+
+    fn is_expired(&self, session: &IdpSession, now: DateTime<Utc>) -> bool {
+        now >= session.not_on_or_after || now - session.last_activity > self.idle_timeout
+    }
+}
@@ -1,12 +1,27 @@
+use crate::cert_expiry::ExpiryThresholds;
+use crate::key_rotation::KeyRing;
+use crate::models::idp_metadata::IdpMetadataConfig;
+use crate::models::logout::LogoutStore;
+use crate::models::name_id::NameIdService;
+use crate::models::pending_request::PendingRequestStore;
+use crate::models::service_provider::ServiceProviderRegistry;
+use crate::models::session::SessionStore;
 use crate::models::user::UserDatabase;
-use samael::idp;
-use std::sync::Arc;
 
 pub struct AppState {
-    pub idp: Arc<idp::IdentityProvider>,
-    pub cert_der: Vec<u8>,
+    pub signing_keys: KeyRing,
+    /// When signing certificates are reported as nearing expiry
+    pub certificate_expiry: ExpiryThresholds,
     pub idp_entity_id: String,
-    pub sp_entity_id: String,
-    pub sp_acs_url: String,
+    pub metadata: IdpMetadataConfig,
+    pub service_providers: ServiceProviderRegistry,
     pub user_database: UserDatabase,
+    pub pending_requests: PendingRequestStore,
+    pub sessions: SessionStore,
+    pub logouts: LogoutStore,
+    pub name_ids: NameIdService,
+    /// Allows SSO endpoints to authenticate users by `user_id` query parameter
+    pub test_mode: bool,
+    /// Bearer token required by the admin endpoints, which are disabled when unset
+    pub admin_token: Option<String>,
 }
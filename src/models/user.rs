@@ -1,8 +1,22 @@
+use argon2::password_hash::{PasswordHasher, SaltString};
+use argon2::{Argon2, PasswordHash, PasswordVerifier};
+use log::warn;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{BTreeSet, HashMap};
 use std::fs::File;
 use std::io::Read;
 use std::path::Path;
+use std::sync::LazyLock;
+
+// Checked in place of a stored hash for unknown users and users without a
+// password, so a failed login takes as long whether or not the user exists
+static DUMMY_PASSWORD_HASH: LazyLock<String> = LazyLock::new(|| {
+    let salt = SaltString::encode_b64(b"unknown user salt").expect("valid salt");
+    Argon2::default()
+        .hash_password(b"unknown user", &salt)
+        .expect("hashing with the default parameters succeeds")
+        .to_string()
+});
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct User {
@@ -10,10 +24,30 @@ pub struct User {
     pub first_name: String,
     pub last_name: String,
     pub email: String,
+    /// Argon2 PHC string. Users without a hash can only sign in via test mode
+    pub password_hash: Option<String>,
     pub mobile_phone: Option<String>,
     pub attributes: Option<HashMap<String, String>>,
 }
 
+impl User {
+    /// Checks a password against the user's stored hash
+    pub fn verify_password(&self, password: &str) -> bool {
+        let Some(hash) = &self.password_hash else {
+            return false;
+        };
+        match PasswordHash::new(hash) {
+            Ok(parsed) => Argon2::default()
+                .verify_password(password.as_bytes(), &parsed)
+                .is_ok(),
+            Err(e) => {
+                warn!("Invalid password hash for user {}: {}", self.user_id, e);
+                false
+            }
+        }
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct UserDatabase {
     pub users: Vec<User>,
@@ -32,4 +66,81 @@ impl UserDatabase {
     pub fn find_user(&self, user_id: &str) -> Option<&User> {
         self.users.iter().find(|u| u.user_id == user_id)
     }
+
+    /// Returns the user with the given ID if `password` matches their stored
+    /// hash. The password is hashed for unknown users too, so the time taken
+    /// does not reveal which user IDs exist.
+    pub fn authenticate(&self, user_id: &str, password: &str) -> Option<&User> {
+        match self.find_user(user_id) {
+            Some(user) if user.password_hash.is_some() => {
+                user.verify_password(password).then_some(user)
+            }
+            _ => {
+                let dummy = PasswordHash::new(&DUMMY_PASSWORD_HASH).expect("valid dummy hash");
+                let _ = Argon2::default().verify_password(password.as_bytes(), &dummy);
+                None
+            }
+        }
+    }
+
+    /// Names of the attributes released for users in the database: the core
+    /// attributes, `mobilePhone` when any user has one, and every custom
+    /// attribute name in sorted order
+    pub fn attribute_names(&self) -> Vec<String> {
+        let mut names: Vec<String> = ["firstName", "lastName", "email"]
+            .into_iter()
+            .map(str::to_string)
+            .collect();
+        if self.users.iter().any(|user| user.mobile_phone.is_some()) {
+            names.push("mobilePhone".to_string());
+        }
+        let custom_names: BTreeSet<&String> = self
+            .users
+            .iter()
+            .filter_map(|user| user.attributes.as_ref())
+            .flat_map(|attributes| attributes.keys())
+            .collect();
+        for name in custom_names {
+            if !names.contains(name) {
+                names.push(name.clone());
+            }
+        }
+        names
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user(user_id: &str, password: Option<&str>) -> User {
+        let salt = SaltString::encode_b64(user_id.as_bytes()).unwrap();
+        User {
+            user_id: user_id.to_string(),
+            first_name: "Alice".to_string(),
+            last_name: "Liddell".to_string(),
+            email: format!("{}@example.com", user_id),
+            password_hash: password.map(|password| {
+                Argon2::default()
+                    .hash_password(password.as_bytes(), &salt)
+                    .unwrap()
+                    .to_string()
+            }),
+            mobile_phone: None,
+            attributes: None,
+        }
+    }
+
+    #[test]
+    fn authenticate_checks_the_stored_hash() {
+        let database = UserDatabase {
+            users: vec![user("alice", Some("correct horse")), user("bob", None)],
+        };
+        let alice = database.authenticate("alice", "correct horse").unwrap();
+        assert_eq!(alice.user_id, "alice");
+        assert!(database.authenticate("alice", "wrong").is_none());
+        // Users without a password only sign in through test mode
+        assert!(database.authenticate("bob", "").is_none());
+        assert!(database.authenticate("carol", "correct horse").is_none());
+    }
 }
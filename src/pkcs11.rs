@@ -0,0 +1,377 @@
+use cryptoki::context::{CInitializeArgs, Pkcs11};
+use cryptoki::mechanism::Mechanism;
+use cryptoki::object::{Attribute, AttributeType, KeyType, ObjectClass, ObjectHandle};
+use cryptoki::session::{Session, UserType};
+use cryptoki::types::AuthPin;
+use log::{debug, info};
+use openssl::hash::{MessageDigest, hash};
+use openssl::nid::Nid;
+use openssl::pkey::Id;
+use std::io;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use crate::signing::SigningBackend;
+
+/// Where to find the PKCS#11 token holding the IdP signing keys
+#[derive(Debug, Clone)]
+pub struct Pkcs11Config {
+    /// PKCS#11 module of the token, such as `/usr/lib/softhsm/libsofthsm2.so`
+    pub module_path: PathBuf,
+    pub token_label: String,
+    pub pin: String,
+}
+
+/// A logged-in PKCS#11 token. Keys never leave it: messages are sent to the
+/// token to be signed.
+pub struct Pkcs11Token {
+    // PKCS#11 login state is shared by all sessions of the application, so a
+    // single logged-in session is used for every key
+    session: Mutex<Session>,
+}
+
+impl Pkcs11Token {
+    pub fn open(config: &Pkcs11Config) -> io::Result<Self> {
+        let context = Pkcs11::new(&config.module_path).map_err(|e| {
+            io::Error::other(format!(
+                "Failed to load PKCS#11 module {}: {}",
+                config.module_path.display(),
+                e
+            ))
+        })?;
+        context
+            .initialize(CInitializeArgs::OsThreads)
+            .map_err(pkcs11_error)?;
+
+        let mut slot = None;
+        for candidate in context.get_slots_with_token().map_err(pkcs11_error)? {
+            let token_info = context.get_token_info(candidate).map_err(pkcs11_error)?;
+            if token_info.label() == config.token_label {
+                slot = Some(candidate);
+                break;
+            }
+        }
+        let slot = slot.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("No PKCS#11 token labelled {}", config.token_label),
+            )
+        })?;
+
+        let session = context.open_rw_session(slot).map_err(pkcs11_error)?;
+        session
+            .login(
+                UserType::User,
+                Some(&AuthPin::new(config.pin.clone().into())),
+            )
+            .map_err(pkcs11_error)?;
+        info!("Logged in to PKCS#11 token {}", config.token_label);
+        Ok(Self {
+            session: Mutex::new(session),
+        })
+    }
+
+    /// The private key labelled `label`
+    pub fn key(self: &Arc<Self>, label: &str) -> io::Result<Pkcs11Key> {
+        let session = self.session.lock().unwrap();
+        let handle = find_private_key(&session, label)?.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("No private key labelled {} in the PKCS#11 token", label),
+            )
+        })?;
+        let key_type = match session
+            .get_attributes(handle, &[AttributeType::KeyType])
+            .map_err(pkcs11_error)?
+            .first()
+        {
+            Some(Attribute::KeyType(key_type)) if *key_type == KeyType::RSA => Id::RSA,
+            Some(Attribute::KeyType(key_type)) if *key_type == KeyType::EC => Id::EC,
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("PKCS#11 key {} is neither an RSA nor an EC key", label),
+                ));
+            }
+        };
+        debug!("Found {:?} key {} in the PKCS#11 token", key_type, label);
+        Ok(Pkcs11Key {
+            token: Arc::clone(self),
+            handle,
+            key_type,
+        })
+    }
+
+    pub fn has_key(&self, label: &str) -> io::Result<bool> {
+        Ok(find_private_key(&self.session.lock().unwrap(), label)?.is_some())
+    }
+
+    /// Renames the private and public keys labelled `from`
+    pub fn relabel_key(&self, from: &str, to: &str) -> io::Result<()> {
+        let session = self.session.lock().unwrap();
+        let handles = session
+            .find_objects(&[Attribute::Label(from.as_bytes().to_vec())])
+            .map_err(pkcs11_error)?;
+        for handle in handles {
+            session
+                .update_attributes(handle, &[Attribute::Label(to.as_bytes().to_vec())])
+                .map_err(pkcs11_error)?;
+        }
+        info!("Relabelled PKCS#11 key {} as {}", from, to);
+        Ok(())
+    }
+}
+
+/// A private key held in a PKCS#11 token
+pub struct Pkcs11Key {
+    token: Arc<Pkcs11Token>,
+    handle: ObjectHandle,
+    key_type: Id,
+}
+
+impl SigningBackend for Pkcs11Key {
+    fn key_type(&self) -> Id {
+        self.key_type
+    }
+
+    fn sign(&self, digest: MessageDigest, data: &[u8]) -> io::Result<Vec<u8>> {
+        let session = self.token.session.lock().unwrap();
+        if self.key_type == Id::EC {
+            // Tokens such as SoftHSM only offer plain ECDSA, which signs a
+            // digest computed here and returns raw r || s
+            let digest = hash(digest, data)?;
+            return session
+                .sign(&Mechanism::Ecdsa, self.handle, &digest)
+                .map_err(pkcs11_error);
+        }
+
+        let mechanism = match digest.type_() {
+            Nid::SHA1 => Mechanism::Sha1RsaPkcs,
+            Nid::SHA256 => Mechanism::Sha256RsaPkcs,
+            Nid::SHA384 => Mechanism::Sha384RsaPkcs,
+            Nid::SHA512 => Mechanism::Sha512RsaPkcs,
+            other => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    format!("Unsupported digest for PKCS#11 signing: {:?}", other),
+                ));
+            }
+        };
+        session
+            .sign(&mechanism, self.handle, data)
+            .map_err(pkcs11_error)
+    }
+}
+
+fn find_private_key(session: &Session, label: &str) -> io::Result<Option<ObjectHandle>> {
+    let handles = session
+        .find_objects(&[
+            Attribute::Class(ObjectClass::PRIVATE_KEY),
+            Attribute::Label(label.as_bytes().to_vec()),
+        ])
+        .map_err(pkcs11_error)?;
+    match handles.as_slice() {
+        [] => Ok(None),
+        [handle] => Ok(Some(*handle)),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "Several private keys are labelled {} in the PKCS#11 token",
+                label
+            ),
+        )),
+    }
+}
+
+fn pkcs11_error(e: cryptoki::error::Error) -> io::Error {
+    io::Error::other(format!("PKCS#11 error: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::signing::{check_certificate, raw_ecdsa_to_der};
+    use openssl::asn1::Asn1Time;
+    use openssl::bn::{BigNum, BigNumContext};
+    use openssl::ec::{EcGroup, EcKey, EcPoint};
+    use openssl::pkey::{PKey, Public};
+    use openssl::rsa::Rsa;
+    use openssl::sign::Verifier;
+    use openssl::x509::{X509Builder, X509NameBuilder};
+    use std::fs;
+    use std::path::Path;
+    use uuid::Uuid;
+
+    const TOKEN_LABEL: &str = "idp-test";
+    const USER_PIN: &str = "1234";
+
+    // Points SoftHSM at an empty token directory and initializes a token in
+    // it, with an RSA and a P-256 key pair. Returns the public keys.
+    fn init_softhsm_token(module_path: &str, dir: &Path) -> (PKey<Public>, PKey<Public>) {
+        let token_dir = dir.join("tokens");
+        fs::create_dir_all(&token_dir).unwrap();
+        let config_path = dir.join("softhsm2.conf");
+        fs::write(
+            &config_path,
+            format!("directories.tokendir = {}\n", token_dir.display()),
+        )
+        .unwrap();
+        // SoftHSM reads its configuration when the module is initialized. No
+        // other test reads this variable.
+        unsafe { std::env::set_var("SOFTHSM2_CONF", &config_path) };
+
+        let context = Pkcs11::new(module_path).unwrap();
+        context.initialize(CInitializeArgs::OsThreads).unwrap();
+        let slot = context.get_slots_with_token().unwrap()[0];
+        let so_pin = AuthPin::new("5678".into());
+        context.init_token(slot, &so_pin, TOKEN_LABEL).unwrap();
+        let session = context.open_rw_session(slot).unwrap();
+        session.login(UserType::So, Some(&so_pin)).unwrap();
+        session.init_pin(&AuthPin::new(USER_PIN.into())).unwrap();
+        session.logout().unwrap();
+        session
+            .login(UserType::User, Some(&AuthPin::new(USER_PIN.into())))
+            .unwrap();
+
+        let private_template = |label: &str| {
+            vec![
+                Attribute::Token(true),
+                Attribute::Private(true),
+                Attribute::Sign(true),
+                Attribute::Label(label.as_bytes().to_vec()),
+            ]
+        };
+        let (rsa_public, _) = session
+            .generate_key_pair(
+                &Mechanism::RsaPkcsKeyPairGen,
+                &[
+                    Attribute::Token(true),
+                    Attribute::Verify(true),
+                    Attribute::ModulusBits(2048.into()),
+                    Attribute::PublicExponent(vec![1, 0, 1]),
+                    Attribute::Label(b"rsa".to_vec()),
+                ],
+                &private_template("rsa"),
+            )
+            .unwrap();
+        // DER encoding of the prime256v1 OID
+        let p256_params = vec![0x06, 0x08, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x03, 0x01, 0x07];
+        let (ec_public, _) = session
+            .generate_key_pair(
+                &Mechanism::EccKeyPairGen,
+                &[
+                    Attribute::Token(true),
+                    Attribute::Verify(true),
+                    Attribute::EcParams(p256_params),
+                    Attribute::Label(b"ec".to_vec()),
+                ],
+                &private_template("ec"),
+            )
+            .unwrap();
+
+        let rsa_attributes = session
+            .get_attributes(
+                rsa_public,
+                &[AttributeType::Modulus, AttributeType::PublicExponent],
+            )
+            .unwrap();
+        let [Attribute::Modulus(n), Attribute::PublicExponent(e)] = rsa_attributes.as_slice()
+        else {
+            panic!("RSA public key has no modulus and exponent");
+        };
+        let rsa_key = Rsa::from_public_components(
+            BigNum::from_slice(n).unwrap(),
+            BigNum::from_slice(e).unwrap(),
+        )
+        .unwrap();
+
+        let ec_attributes = session
+            .get_attributes(ec_public, &[AttributeType::EcPoint])
+            .unwrap();
+        let [Attribute::EcPoint(point)] = ec_attributes.as_slice() else {
+            panic!("EC public key has no point");
+        };
+        // The point is wrapped in a DER OCTET STRING, whose two byte header
+        // is skipped
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        let point =
+            EcPoint::from_bytes(&group, &point[2..], &mut BigNumContext::new().unwrap()).unwrap();
+        let ec_key = EcKey::from_public_key(&group, &point).unwrap();
+
+        (
+            PKey::from_rsa(rsa_key).unwrap(),
+            PKey::from_ec_key(ec_key).unwrap(),
+        )
+    }
+
+    // A certificate for `public_key`, signed by a throwaway key since only
+    // the public key matters here
+    fn certificate_for(public_key: &PKey<Public>) -> Vec<u8> {
+        let issuer_key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        let mut name = X509NameBuilder::new().unwrap();
+        name.append_entry_by_text("CN", "idp.example.com").unwrap();
+        let name = name.build();
+        let mut builder = X509Builder::new().unwrap();
+        builder.set_version(2).unwrap();
+        builder.set_subject_name(&name).unwrap();
+        builder.set_issuer_name(&name).unwrap();
+        builder.set_pubkey(public_key).unwrap();
+        builder
+            .set_not_before(&Asn1Time::days_from_now(0).unwrap())
+            .unwrap();
+        builder
+            .set_not_after(&Asn1Time::days_from_now(1).unwrap())
+            .unwrap();
+        builder.sign(&issuer_key, MessageDigest::sha256()).unwrap();
+        builder.build().to_der().unwrap()
+    }
+
+    // Runs only when SOFTHSM2_MODULE names the SoftHSM PKCS#11 module, such as
+    // /usr/lib/softhsm/libsofthsm2.so
+    #[test]
+    fn softhsm_keys_sign_like_file_keys() {
+        let Ok(module_path) = std::env::var("SOFTHSM2_MODULE") else {
+            eprintln!("SOFTHSM2_MODULE is not set, skipping the PKCS#11 test");
+            return;
+        };
+        let dir = std::env::temp_dir().join(format!("idp-pkcs11-{}", Uuid::new_v4().simple()));
+        let (rsa_public, ec_public) = init_softhsm_token(&module_path, &dir);
+
+        let token = Arc::new(
+            Pkcs11Token::open(&Pkcs11Config {
+                module_path: PathBuf::from(&module_path),
+                token_label: TOKEN_LABEL.to_string(),
+                pin: USER_PIN.to_string(),
+            })
+            .unwrap(),
+        );
+        assert!(token.has_key("rsa").unwrap());
+        assert!(!token.has_key("missing").unwrap());
+
+        let message = b"PKCS#11 signing test";
+        for (label, public_key, digest) in [
+            ("rsa", &rsa_public, MessageDigest::sha512()),
+            ("ec", &ec_public, MessageDigest::sha256()),
+        ] {
+            let key = token.key(label).unwrap();
+            assert_eq!(key.key_type(), public_key.id());
+            let signature = key.sign(digest, message).unwrap();
+            let signature = if key.key_type() == Id::EC {
+                raw_ecdsa_to_der(&signature).unwrap()
+            } else {
+                signature
+            };
+            let mut verifier = Verifier::new(digest, public_key).unwrap();
+            verifier.update(message).unwrap();
+            assert!(verifier.verify(&signature).unwrap());
+
+            check_certificate(&key, &certificate_for(public_key)).unwrap();
+        }
+
+        token.relabel_key("ec", "ec-previous").unwrap();
+        assert!(!token.has_key("ec").unwrap());
+        token.key("ec-previous").unwrap();
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,325 @@
+use base64::Engine as _;
+use base64::engine::general_purpose;
+use libxml::parser::Parser;
+use libxml::tree::Node;
+use libxml::tree::c14n::{CanonicalizationMode, CanonicalizationOptions};
+use openssl::bn::BigNum;
+use openssl::ecdsa::EcdsaSig;
+use openssl::hash::{MessageDigest, hash};
+use openssl::pkey::{Id, PKey, Private};
+use openssl::sign::{Signer, Verifier};
+use openssl::x509::X509;
+use samael::crypto;
+use samael::idp::IdentityProvider;
+use std::io;
+
+use crate::models::service_provider::{DigestAlgorithm, SignatureAlgorithm};
+
+const XMLDSIG_NAMESPACE: &str = "http://www.w3.org/2000/09/xmldsig#";
+const EXCLUSIVE_C14N: &str = "http://www.w3.org/2001/10/xml-exc-c14n#";
+
+/// Holds an IdP private key and signs with it, without handing the key out
+pub trait SigningBackend: Send + Sync {
+    fn key_type(&self) -> Id;
+
+    /// Signs `data` hashed with `digest`. RSA signatures are PKCS#1 v1.5 and
+    /// ECDSA signatures raw r || s, as XML DSig and the Redirect binding expect.
+    fn sign(&self, digest: MessageDigest, data: &[u8]) -> io::Result<Vec<u8>>;
+
+    /// Fills in the signature template left empty in `xml`
+    fn sign_xml(&self, xml: &str) -> Result<String, Box<dyn std::error::Error>> {
+        sign_enveloped(xml, self)
+    }
+}
+
+/// A private key loaded from a file
+pub struct FileKey {
+    key: PKey<Private>,
+    key_der: Vec<u8>,
+}
+
+impl FileKey {
+    pub fn new(idp: &IdentityProvider) -> io::Result<Self> {
+        let key_der = idp
+            .export_private_key_der()
+            .map_err(|e| io::Error::other(format!("Failed to get private key DER: {}", e)))?;
+        Ok(Self {
+            key: PKey::private_key_from_der(&key_der)?,
+            key_der,
+        })
+    }
+}
+
+impl SigningBackend for FileKey {
+    fn key_type(&self) -> Id {
+        self.key.id()
+    }
+
+    fn sign(&self, digest: MessageDigest, data: &[u8]) -> io::Result<Vec<u8>> {
+        let mut signer = Signer::new(digest, &self.key)?;
+        signer.update(data)?;
+        let signature = signer.sign_to_vec()?;
+        if self.key.id() != Id::EC {
+            return Ok(signature);
+        }
+
+        // OpenSSL produces DER, XML DSig expects raw r || s
+        let ecdsa = EcdsaSig::from_der(&signature)?;
+        let field_size = self.key.ec_key()?.group().order_bits().div_ceil(8) as i32;
+        let mut raw = ecdsa.r().to_vec_padded(field_size)?;
+        raw.extend(ecdsa.s().to_vec_padded(field_size)?);
+        Ok(raw)
+    }
+
+    // File keys are signed with xmlsec, as before backends were introduced
+    fn sign_xml(&self, xml: &str) -> Result<String, Box<dyn std::error::Error>> {
+        Ok(crypto::sign_xml(xml, &self.key_der)?)
+    }
+}
+
+/// Checks that `backend` holds the private key of the certificate, by signing
+/// a test message and verifying it with the certificate's public key
+pub fn check_certificate(backend: &dyn SigningBackend, cert_der: &[u8]) -> io::Result<()> {
+    let public_key = X509::from_der(cert_der)?.public_key()?;
+    if public_key.id() != backend.key_type() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "The IdP certificate does not match the private key type",
+        ));
+    }
+
+    let message = b"IdP signing key check";
+    let signature = backend.sign(MessageDigest::sha256(), message)?;
+    let signature = if public_key.id() == Id::EC {
+        raw_ecdsa_to_der(&signature)?
+    } else {
+        signature
+    };
+    let mut verifier = Verifier::new(MessageDigest::sha256(), &public_key)?;
+    verifier.update(message)?;
+    if !verifier.verify(&signature)? {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "The IdP certificate does not match the private key",
+        ));
+    }
+    Ok(())
+}
+
+/// Converts a raw r || s ECDSA signature to the DER encoding OpenSSL uses
+pub fn raw_ecdsa_to_der(signature: &[u8]) -> io::Result<Vec<u8>> {
+    if signature.is_empty() || signature.len() % 2 != 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Malformed raw ECDSA signature of {} bytes", signature.len()),
+        ));
+    }
+    let (r, s) = signature.split_at(signature.len() / 2);
+    let signature =
+        EcdsaSig::from_private_components(BigNum::from_slice(r)?, BigNum::from_slice(s)?)?;
+    Ok(signature.to_der()?)
+}
+
+/// Computes the enveloped signature whose template is left empty in `xml`,
+/// using `backend` for the signature value.
+///
+/// Only the templates built by `signature_template` are supported: a single
+/// reference to the parent element, with the enveloped-signature and
+/// exclusive canonicalization transforms.
+pub fn sign_enveloped<B: SigningBackend + ?Sized>(
+    xml: &str,
+    backend: &B,
+) -> Result<String, Box<dyn std::error::Error>> {
+    let document = Parser::default().parse_string(xml)?;
+    let root = document
+        .get_root_element()
+        .ok_or("XML document has no root element")?;
+    let mut signature =
+        find_signature_template(&root).ok_or("XML document has no signature template")?;
+    let mut signed_element = signature
+        .get_parent()
+        .ok_or("Signature template has no parent element")?;
+
+    let signed_info = dsig_child(&signature, "SignedInfo")?;
+    let canonicalization = dsig_child(&signed_info, "CanonicalizationMethod")?;
+    if canonicalization.get_attribute("Algorithm").as_deref() != Some(EXCLUSIVE_C14N) {
+        return Err("Only exclusive canonicalization is supported".into());
+    }
+    let reference = dsig_child(&signed_info, "Reference")?;
+    let id = signed_element
+        .get_attribute("ID")
+        .ok_or("Signed element has no ID")?;
+    if reference.get_attribute("URI") != Some(format!("#{}", id)) {
+        return Err("Signature reference does not point to its parent element".into());
+    }
+
+    let digest_uri = dsig_child(&reference, "DigestMethod")?
+        .get_attribute("Algorithm")
+        .unwrap_or_default();
+    let digest = DigestAlgorithm::from_uri(&digest_uri)
+        .ok_or_else(|| format!("Unsupported digest algorithm {}", digest_uri))?;
+    let signature_uri = dsig_child(&signed_info, "SignatureMethod")?
+        .get_attribute("Algorithm")
+        .unwrap_or_default();
+    let algorithm = SignatureAlgorithm::from_uri(&signature_uri)
+        .ok_or_else(|| format!("Unsupported signature algorithm {}", signature_uri))?;
+    if algorithm.key_type() != backend.key_type() {
+        return Err(format!(
+            "Signature algorithm {} does not match the IdP key type {:?}",
+            signature_uri,
+            backend.key_type()
+        )
+        .into());
+    }
+
+    // The enveloped-signature transform digests the element without the
+    // signature, which is put back in the same place afterwards
+    let previous_sibling = signature.get_prev_sibling();
+    let next_sibling = signature.get_next_sibling();
+    signature.unlink_node();
+    let canonical_element = signed_element
+        .canonicalize(exclusive_c14n())
+        .map_err(|_| "Failed to canonicalize the signed element")?;
+    match (previous_sibling, next_sibling) {
+        (Some(mut previous), _) => previous.add_next_sibling(&mut signature)?,
+        (None, Some(mut next)) => next.add_prev_sibling(&mut signature)?,
+        (None, None) => signed_element.add_child(&mut signature)?,
+    }
+
+    let digest_value = hash(digest.message_digest(), canonical_element.as_bytes())?;
+    dsig_child(&reference, "DigestValue")?
+        .set_content(&general_purpose::STANDARD.encode(digest_value))?;
+
+    let canonical_signed_info = dsig_child(&signature, "SignedInfo")?
+        .canonicalize(exclusive_c14n())
+        .map_err(|_| "Failed to canonicalize SignedInfo")?;
+    let signature_value =
+        backend.sign(algorithm.message_digest(), canonical_signed_info.as_bytes())?;
+    dsig_child(&signature, "SignatureValue")?
+        .set_content(&general_purpose::STANDARD.encode(signature_value))?;
+
+    Ok(document.to_string())
+}
+
+fn exclusive_c14n() -> CanonicalizationOptions {
+    CanonicalizationOptions {
+        mode: CanonicalizationMode::ExclusiveCanonical1_0,
+        with_comments: false,
+        inclusive_ns_prefixes: vec![],
+    }
+}
+
+// Finds the first signature whose SignatureValue has not been filled in
+fn find_signature_template(node: &Node) -> Option<Node> {
+    for child in node.get_child_elements() {
+        if is_dsig_element(&child, "Signature")
+            && dsig_child(&child, "SignatureValue")
+                .is_ok_and(|value| value.get_content().trim().is_empty())
+        {
+            return Some(child);
+        }
+        if let Some(signature) = find_signature_template(&child) {
+            return Some(signature);
+        }
+    }
+    None
+}
+
+fn dsig_child(node: &Node, name: &str) -> Result<Node, String> {
+    node.get_child_elements()
+        .into_iter()
+        .find(|child| is_dsig_element(child, name))
+        .ok_or_else(|| format!("Signature template has no {} element", name))
+}
+
+fn is_dsig_element(node: &Node, name: &str) -> bool {
+    node.get_name() == name
+        && node
+            .get_namespace()
+            .is_some_and(|namespace| namespace.get_href() == XMLDSIG_NAMESPACE)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cert_util::{CertificateProfile, IdpKeyType, generate_identity_provider};
+    use crate::handlers::response_builder::{signature_template, strip_xml_declaration};
+    use crate::models::service_provider::SigningAlgorithms;
+    use samael::traits::ToXml;
+
+    fn file_key(key_type: IdpKeyType) -> (FileKey, Vec<u8>) {
+        let (idp, cert_chain) =
+            generate_identity_provider(key_type, &CertificateProfile::default()).unwrap();
+        (FileKey::new(&idp).unwrap(), cert_chain[0].clone())
+    }
+
+    // A LogoutRequest with an empty signature template between its Issuer and
+    // NameID, as built for the SPs
+    fn signature_template_xml(cert_der: &[u8], signature: SignatureAlgorithm) -> String {
+        let algorithms = SigningAlgorithms {
+            signature,
+            digest: DigestAlgorithm::Sha256,
+        };
+        let template = signature_template("_logout", cert_der, algorithms)
+            .to_string()
+            .unwrap();
+        format!(
+            r#"<samlp:LogoutRequest xmlns:samlp="urn:oasis:names:tc:SAML:2.0:protocol" xmlns:saml="urn:oasis:names:tc:SAML:2.0:assertion" ID="_logout" Version="2.0" IssueInstant="2024-01-01T00:00:00Z"><saml:Issuer>https://idp.example.com</saml:Issuer>{}<saml:NameID>alice</saml:NameID></samlp:LogoutRequest>"#,
+            strip_xml_declaration(&template)
+        )
+    }
+
+    #[test]
+    fn sign_enveloped_signatures_verify_with_xmlsec() {
+        for (key_type, algorithm) in [
+            (IdpKeyType::Rsa2048, SignatureAlgorithm::RsaSha256),
+            (IdpKeyType::Rsa2048, SignatureAlgorithm::RsaSha512),
+            (IdpKeyType::EcdsaP256, SignatureAlgorithm::EcdsaSha256),
+            (IdpKeyType::EcdsaP384, SignatureAlgorithm::EcdsaSha384),
+        ] {
+            let (key, cert_der) = file_key(key_type);
+            let xml = signature_template_xml(&cert_der, algorithm);
+            let signed = sign_enveloped(&xml, &key).unwrap();
+            crypto::verify_signed_xml(&signed, &cert_der, Some("ID")).unwrap();
+
+            // The signature stays where the template was
+            let issuer_end = signed.find("</saml:Issuer>").unwrap();
+            let signature_start = signed.find("<ds:Signature").unwrap();
+            assert!(issuer_end < signature_start);
+            assert!(signature_start < signed.find("<saml:NameID>").unwrap());
+
+            let (_, other_cert_der) = file_key(key_type);
+            assert!(crypto::verify_signed_xml(&signed, &other_cert_der, Some("ID")).is_err());
+            let tampered = signed.replace(">alice<", ">mallory<");
+            assert!(crypto::verify_signed_xml(&tampered, &cert_der, Some("ID")).is_err());
+        }
+    }
+
+    #[test]
+    fn sign_enveloped_refuses_an_algorithm_for_another_key_type() {
+        let (key, cert_der) = file_key(IdpKeyType::EcdsaP256);
+        let xml = signature_template_xml(&cert_der, SignatureAlgorithm::RsaSha256);
+        let error = sign_enveloped(&xml, &key).unwrap_err().to_string();
+        assert!(error.contains("key type"), "{}", error);
+    }
+
+    #[test]
+    fn check_certificate_matches_the_key() {
+        for key_type in [IdpKeyType::Rsa2048, IdpKeyType::EcdsaP256] {
+            let (key, cert_der) = file_key(key_type);
+            check_certificate(&key, &cert_der).unwrap();
+            let (_, other_cert_der) = file_key(key_type);
+            assert!(check_certificate(&key, &other_cert_der).is_err());
+        }
+    }
+
+    #[test]
+    fn raw_ecdsa_to_der_rejects_malformed_signatures() {
+        assert!(raw_ecdsa_to_der(&[]).is_err());
+        assert!(raw_ecdsa_to_der(&[1; 63]).is_err());
+        let der = raw_ecdsa_to_der(&[1; 64]).unwrap();
+        let signature = EcdsaSig::from_der(&der).unwrap();
+        assert_eq!(signature.r().to_vec(), vec![1; 32]);
+        assert_eq!(signature.s().to_vec(), vec![1; 32]);
+    }
+}
@@ -0,0 +1,289 @@
+use actix_web::web;
+use base64::Engine as _;
+use base64::engine::general_purpose;
+use log::{debug, info, warn};
+use samael::metadata::{EntityDescriptor, SpSsoDescriptor};
+use std::time::Duration;
+
+use crate::models::name_id::is_supported_format;
+use crate::models::service_provider::{
+    AcsEndpoint, AttributeConsumingService, DEFAULT_ASSERTION_LIFETIME_SECS,
+    DEFAULT_CLOCK_SKEW_SECS, DataEncryptionAlgorithm, KeyTransportAlgorithm, MetadataSource,
+    RequestedAttribute, ServiceProvider, SigningMode, SloEndpoint,
+};
+use crate::models::state::AppState;
+
+type MetadataError = Box<dyn std::error::Error + Send + Sync>;
+
+/// How long fetching metadata from a URL may take
+const METADATA_FETCH_TIMEOUT_SECS: u64 = 30;
+
+/// Loads an SP's configuration from the metadata file or URL in `source`.
+///
+/// Metadata signatures are not checked, so URL sources must use HTTPS and point
+/// at a server trusted to describe the SP.
+pub async fn load_metadata_source(
+    source: &MetadataSource,
+) -> Result<ServiceProvider, MetadataError> {
+    let xml = match (&source.path, &source.url) {
+        (Some(path), None) => {
+            debug!("Reading SP metadata from file {}", path);
+            tokio::fs::read_to_string(path).await?
+        }
+        (None, Some(url)) => {
+            if url::Url::parse(url)?.scheme() != "https" {
+                return Err(format!("Metadata URL {} does not use HTTPS", url).into());
+            }
+            debug!("Fetching SP metadata from {}", url);
+            reqwest::Client::builder()
+                .timeout(Duration::from_secs(METADATA_FETCH_TIMEOUT_SECS))
+                .build()?
+                .get(url)
+                .send()
+                .await?
+                .error_for_status()?
+                .text()
+                .await?
+        }
+        _ => return Err("Metadata sources must set exactly one of path or url".into()),
+    };
+
+    service_provider_from_metadata(&xml, source)
+}
+
+/// Builds an SP configuration from an `EntityDescriptor` containing an
+/// `SPSSODescriptor`, applying any overrides from `source`
+pub fn service_provider_from_metadata(
+    xml: &str,
+    source: &MetadataSource,
+) -> Result<ServiceProvider, MetadataError> {
+    let entity: EntityDescriptor = xml
+        .parse()
+        .map_err(|e| format!("Invalid SP metadata: {}", e))?;
+    let entity_id = entity
+        .entity_id
+        .ok_or("SP metadata is missing an entityID")?;
+    let descriptor = entity
+        .sp_sso_descriptors
+        .and_then(|descriptors| descriptors.into_iter().next())
+        .ok_or_else(|| format!("SP metadata for {} has no SPSSODescriptor", entity_id))?;
+
+    let acs_endpoints = descriptor
+        .assertion_consumer_services
+        .iter()
+        .map(|endpoint| AcsEndpoint {
+            location: endpoint.location.clone(),
+            binding: endpoint.binding.clone(),
+            index: u16::try_from(endpoint.index).ok(),
+            is_default: endpoint.is_default.unwrap_or(false),
+        })
+        .collect();
+
+    let single_logout_services = descriptor
+        .single_logout_services
+        .iter()
+        .map(|endpoint| SloEndpoint {
+            location: endpoint.location.clone(),
+            binding: endpoint.binding.clone(),
+            response_location: endpoint.response_location.clone(),
+        })
+        .collect();
+
+    let attribute_consuming_services = descriptor
+        .attribute_consuming_services
+        .iter()
+        .filter_map(|service| {
+            Some(AttributeConsumingService {
+                index: u16::try_from(service.index).ok()?,
+                is_default: service.is_default.unwrap_or(false),
+                requested_attributes: service
+                    .requested_attributes
+                    .iter()
+                    .map(|attr| RequestedAttribute {
+                        name: attr.name.clone(),
+                    })
+                    .collect(),
+            })
+        })
+        .collect();
+
+    // Use the first supported algorithms the SP lists on its encryption key
+    let encryption_methods: Vec<&str> = descriptor
+        .key_descriptors
+        .iter()
+        .filter(|kd| kd.key_use.as_deref().is_none_or(|u| u == "encryption"))
+        .flat_map(|kd| kd.encryption_methods.iter().flatten())
+        .map(|method| method.algorithm.as_str())
+        .collect();
+    let data_encryption_algorithm = encryption_methods
+        .iter()
+        .find_map(|uri| DataEncryptionAlgorithm::from_uri(uri))
+        .unwrap_or_default();
+    let key_transport_algorithm = encryption_methods
+        .iter()
+        .find_map(|uri| KeyTransportAlgorithm::from_uri(uri))
+        .unwrap_or_default();
+
+    let sp = ServiceProvider {
+        acs_endpoints,
+        single_logout_services,
+        signing_certificate_path: None,
+        encryption_certificate_path: None,
+        signature_algorithm: source.signature_algorithm,
+        digest_algorithm: source.digest_algorithm.unwrap_or_default(),
+        allow_sha1: source.allow_sha1.unwrap_or(false),
+        // SPs that want signed assertions still get a signed Response
+        signing_mode: source.signing_mode.unwrap_or(
+            if descriptor.want_assertions_signed.unwrap_or(false) {
+                SigningMode::Both
+            } else {
+                SigningMode::Response
+            },
+        ),
+        encrypt_assertions: source.encrypt_assertions,
+        data_encryption_algorithm,
+        key_transport_algorithm,
+        assertion_lifetime_secs: source
+            .assertion_lifetime_secs
+            .unwrap_or(DEFAULT_ASSERTION_LIFETIME_SECS),
+        clock_skew_secs: source.clock_skew_secs.unwrap_or(DEFAULT_CLOCK_SKEW_SECS),
+        one_time_use: source.one_time_use.unwrap_or(false),
+        authn_requests_signed: source
+            .authn_requests_signed
+            .or(descriptor.authn_requests_signed)
+            .unwrap_or(false),
+        name_id_format: source.name_id_format.clone().or_else(|| {
+            descriptor
+                .name_id_formats
+                .iter()
+                .find(|format| is_supported_format(format))
+                .cloned()
+        }),
+        released_attributes: source.released_attributes.clone(),
+        denied_attributes: source.denied_attributes.clone(),
+        attribute_profile: source.attribute_profile.unwrap_or_default(),
+        attribute_mappings: source.attribute_mappings.clone(),
+        attribute_consuming_services,
+        signing_certificates_der: find_certificates(&descriptor, "signing")?,
+        encryption_certificate_der: find_certificates(&descriptor, "encryption")?
+            .into_iter()
+            .next(),
+        entity_id,
+    };
+
+    debug!(
+        "Parsed SP metadata for {}: {} ACS endpoint(s), {} AttributeConsumingService(s)",
+        sp.entity_id,
+        sp.acs_endpoints.len(),
+        sp.attribute_consuming_services.len()
+    );
+    Ok(sp)
+}
+
+/// Periodically re-fetches URL metadata sources and updates the registry.
+/// `sources` pairs each source with the entity ID it was first loaded as.
+///
+/// A failed refresh keeps the previously loaded configuration in place, as
+/// does metadata describing a different entity ID.
+pub fn spawn_metadata_refresh(state: web::Data<AppState>, sources: Vec<(String, MetadataSource)>) {
+    for (entity_id, source) in sources.into_iter().filter(|(_, s)| s.url.is_some()) {
+        let state = state.clone();
+        tokio::spawn(async move {
+            let mut interval =
+                tokio::time::interval(Duration::from_secs(source.refresh_interval_secs.max(1)));
+            // The first tick completes immediately and the source was loaded at startup
+            interval.tick().await;
+
+            loop {
+                interval.tick().await;
+                debug!("Refreshing SP metadata from {}", source.describe());
+                match load_metadata_source(&source).await {
+                    Ok(sp) if sp.entity_id != entity_id => warn!(
+                        "Ignoring refreshed metadata from {}: entityID changed from {} to {}",
+                        source.describe(),
+                        entity_id,
+                        sp.entity_id
+                    ),
+                    Ok(sp) => {
+                        let idp_key_type = state.signing_keys.active().key_type();
+                        match state.service_providers.replace(sp, idp_key_type) {
+                            Ok(()) => info!("Refreshed SP metadata for {}", entity_id),
+                            Err(e) => warn!("Ignoring refreshed metadata for {}: {}", entity_id, e),
+                        }
+                    }
+                    Err(e) => warn!(
+                        "Failed to refresh SP metadata from {}: {}",
+                        source.describe(),
+                        e
+                    ),
+                }
+            }
+        });
+    }
+}
+
+// Returns the certificates usable for `key_use`, in document order.
+// KeyDescriptors without a `use` attribute apply to both signing and encryption.
+fn find_certificates(
+    descriptor: &SpSsoDescriptor,
+    key_use: &str,
+) -> Result<Vec<Vec<u8>>, MetadataError> {
+    descriptor
+        .key_descriptors
+        .iter()
+        .filter(|kd| kd.key_use.as_deref().is_none_or(|u| u == key_use))
+        .filter_map(|kd| kd.key_info.x509_data.as_ref())
+        .filter_map(|x509| x509.certificates.first())
+        .map(|cert_b64| {
+            let cleaned: String = cert_b64.chars().filter(|c| !c.is_whitespace()).collect();
+            general_purpose::STANDARD.decode(cleaned).map_err(|e| {
+                MetadataError::from(format!(
+                    "Invalid {} certificate in SP metadata: {}",
+                    key_use, e
+                ))
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key_descriptor(key_use: Option<&str>, cert_der: &[u8]) -> String {
+        let encoded = general_purpose::STANDARD.encode(cert_der);
+        let (head, tail) = encoded.split_at(4);
+        format!(
+            r#"<md:KeyDescriptor{}><ds:KeyInfo><ds:X509Data><ds:X509Certificate>{}
+  {}</ds:X509Certificate></ds:X509Data></ds:KeyInfo></md:KeyDescriptor>"#,
+            key_use
+                .map(|key_use| format!(r#" use="{}""#, key_use))
+                .unwrap_or_default(),
+            head,
+            tail
+        )
+    }
+
+    #[test]
+    fn every_signing_certificate_is_kept() {
+        let xml = format!(
+            r#"<md:EntityDescriptor xmlns:md="urn:oasis:names:tc:SAML:2.0:metadata" xmlns:ds="http://www.w3.org/2000/09/xmldsig#" entityID="https://sp.example.com"><md:SPSSODescriptor protocolSupportEnumeration="urn:oasis:names:tc:SAML:2.0:protocol">{}{}{}<md:AssertionConsumerService Binding="urn:oasis:names:tc:SAML:2.0:bindings:HTTP-POST" Location="https://sp.example.com/acs" index="0"/></md:SPSSODescriptor></md:EntityDescriptor>"#,
+            key_descriptor(Some("signing"), b"current signing certificate"),
+            key_descriptor(Some("encryption"), b"encryption certificate"),
+            key_descriptor(None, b"next certificate"),
+        );
+        let source: MetadataSource = serde_yaml::from_str("path: sp.xml\n").unwrap();
+        let sp = service_provider_from_metadata(&xml, &source).unwrap();
+        assert_eq!(
+            sp.signing_certificates_der,
+            [
+                b"current signing certificate".to_vec(),
+                b"next certificate".to_vec()
+            ]
+        );
+        assert_eq!(
+            sp.encryption_certificate_der,
+            Some(b"encryption certificate".to_vec())
+        );
+    }
+}